@@ -1,3 +1,5 @@
+use std::process::Command;
+
 use anyhow::Result;
 use vergen::{BuildBuilder, CargoBuilder, Emitter, RustcBuilder};
 
@@ -11,5 +13,26 @@ fn main() -> Result<()> {
         .add_instructions(&cargo)?
         .add_instructions(&rustc)?
         .emit()?;
+    emit_git_commit();
     Ok(())
 }
+
+/// `GIT_COMMIT` is the checked out commit, `unknown` when building outside of a git checkout
+/// such as from a Docker context without `.git`.
+fn emit_git_commit() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .unwrap_or("unknown".to_string());
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
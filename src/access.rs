@@ -0,0 +1,103 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::Request;
+use axum::http::header::USER_AGENT;
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+use tracing::info;
+
+use crate::envs::ACCESS_LOG_DIR;
+use crate::ip::maybe_ip_from_headers;
+
+/// Target of the access log events, written to `ACCESS_LOG_DIR` and nowhere else.
+pub const ACCESS_TARGET: &str = "access";
+
+/// A request being answered, logged once its response body is sent or dropped.
+struct Entry {
+    client_ip: String,
+    method: String,
+    uri: String,
+    user_agent: String,
+    status: u16,
+    bytes: u64,
+    started: Instant,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        info!(
+            target: ACCESS_TARGET,
+            client_ip = %self.client_ip,
+            method = %self.method,
+            uri = %self.uri,
+            status = self.status,
+            bytes = self.bytes,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            user_agent = %self.user_agent,
+        );
+    }
+}
+
+/// A response body counting the bytes sent into its entry.
+struct Counted {
+    inner: Body,
+    entry: Entry,
+}
+
+impl HttpBody for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.entry.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Write one line per request to the access log, with the bytes of the body as sent, so after
+/// compression, and the time until the last of them was.
+pub async fn access_log(request: Request, next: Next) -> Response {
+    if ACCESS_LOG_DIR.is_none() {
+        return next.run(request).await;
+    }
+    let started = Instant::now();
+    let client_ip = maybe_ip_from_headers(request.headers());
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let (method, uri) = (request.method().to_string(), request.uri().to_string());
+    let response = next.run(request).await;
+    let entry = Entry {
+        client_ip,
+        method,
+        uri,
+        user_agent,
+        status: response.status().as_u16(),
+        bytes: 0,
+        started,
+    };
+    response.map(|inner| Body::new(Counted { inner, entry }))
+}
@@ -0,0 +1,145 @@
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use ipnet::IpNet;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::envs::{IP_ALLOWLIST, IP_DENYLIST};
+use crate::ip::client_ip;
+use crate::structs::R;
+
+/// A block added at runtime, until it expires.
+struct Block {
+    net: IpNet,
+    until: Instant,
+    reason: Option<String>,
+}
+
+static BLOCKS: LazyLock<Mutex<Vec<Block>>> = LazyLock::new(Default::default);
+
+/// A CIDR range, or a single IP.
+pub fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map(|x| x.trunc())
+        .map_err(|_| format!("Invalid IP or CIDR range {}", s))
+}
+
+/// Whether `ip` is outside `IP_ALLOWLIST`, in `IP_DENYLIST` or blocked at runtime.
+pub fn is_denied(ip: IpAddr) -> bool {
+    if !IP_ALLOWLIST.is_empty() && !IP_ALLOWLIST.iter().any(|x| x.contains(&ip)) {
+        return true;
+    }
+    if IP_DENYLIST.iter().any(|x| x.contains(&ip)) {
+        return true;
+    }
+    let mut blocks = BLOCKS.lock().unwrap();
+    blocks.retain(|x| x.until > Instant::now());
+    blocks.iter().any(|x| x.net.contains(&ip))
+}
+
+/// Refuse denied clients with 403 before they count against any rate limit.
+pub async fn filter_ip(request: Request, next: Next) -> Response {
+    if let Some(ip) = client_ip(&request).filter(|x| is_denied(*x)) {
+        debug!("{} => {} denied", ip, request.uri());
+        return R::error_with_status(StatusCode::FORBIDDEN, -1, "Forbidden".into()).into_response();
+    }
+    next.run(request).await
+}
+
+/// Block `net` for `duration`, replacing an earlier block of the same range.
+pub fn block(net: IpNet, duration: Duration, reason: Option<String>) {
+    let mut blocks = BLOCKS.lock().unwrap();
+    blocks.retain(|x| x.net != net);
+    blocks.push(Block {
+        net,
+        until: Instant::now() + duration,
+        reason,
+    });
+}
+
+/// Lift a runtime block, returns whether there was one.
+pub fn unblock(net: IpNet) -> bool {
+    let mut blocks = BLOCKS.lock().unwrap();
+    let len = blocks.len();
+    blocks.retain(|x| x.net != net);
+    blocks.len() < len
+}
+
+/// The runtime blocks still in force, with the seconds until they expire.
+pub fn blocks() -> Vec<Value> {
+    let mut blocks = BLOCKS.lock().unwrap();
+    let now = Instant::now();
+    blocks.retain(|x| x.until > now);
+    blocks
+        .iter()
+        .map(|x| {
+            json!({
+                "net": x.net.to_string(),
+                "expires_in": (x.until - now).as_secs(),
+                "reason": x.reason,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges_and_single_ips() {
+        assert_eq!(parse_net("10.1.2.3/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(
+            parse_net("203.0.113.7").unwrap().to_string(),
+            "203.0.113.7/32"
+        );
+        assert_eq!(
+            parse_net("2001:db8::1").unwrap().to_string(),
+            "2001:db8::1/128"
+        );
+        assert_eq!(
+            parse_net("2001:db8::1/32").unwrap().to_string(),
+            "2001:db8::/32"
+        );
+        assert!(parse_net("10.0.0.0/33").is_err());
+        assert!(parse_net("example.com").is_err());
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let net = parse_net("192.0.2.128/25").unwrap();
+        assert!(net.contains(&ip("192.0.2.200")));
+        assert!(!net.contains(&ip("192.0.2.100")));
+        assert!(!net.contains(&ip("::ffff:192.0.2.200")));
+    }
+
+    #[test]
+    fn denies_blocked_ranges_until_unblocked() {
+        let net = parse_net("198.51.100.0/30").unwrap();
+        assert!(!is_denied(ip("198.51.100.2")));
+        block(net, Duration::from_secs(60), None);
+        assert!(is_denied(ip("198.51.100.2")));
+        assert!(!is_denied(ip("198.51.100.4")));
+        assert!(unblock(net));
+        assert!(!is_denied(ip("198.51.100.2")));
+        assert!(!unblock(net));
+    }
+
+    #[test]
+    fn lifts_expired_blocks() {
+        let net = parse_net("198.51.100.64/30").unwrap();
+        block(net, Duration::ZERO, None);
+        assert!(!is_denied(ip("198.51.100.65")));
+    }
+}
@@ -0,0 +1,55 @@
+use std::str::FromStr;
+
+use axum::extract::{Extension, Path};
+use axum::http::{HeaderMap, StatusCode};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::Address;
+use serde_json::json;
+
+use crate::envs::BITCOIN_NETWORK;
+use crate::handle_request;
+use crate::structs::{ResultCache, R};
+use crate::upstream::Upstreams;
+
+/// The Electrum scripthash of an address: the SHA256 of its output script, byte-reversed and
+/// hex-encoded. The address must belong to `BITCOIN_NETWORK`.
+pub fn to_scripthash(address: &str) -> anyhow::Result<String> {
+    let address = Address::from_str(address)?.require_network(*BITCOIN_NETWORK)?;
+    let mut hash = sha256::Hash::hash(address.script_pubkey().as_bytes()).to_byte_array();
+    hash.reverse();
+    Ok(hex::encode(hash))
+}
+
+/// `GET /address/:address/:method`, the `blockchain.scripthash.*` query or the Atomicals
+/// listing of an address, converted to its scripthash by the proxy.
+pub async fn handle_address_method(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path((address, method)): Path<(String, String)>,
+) -> R {
+    let (method, extra) = match method.as_str() {
+        "balance" => ("blockchain.scripthash.get_balance", None),
+        "history" => ("blockchain.scripthash.get_history", None),
+        "utxo" => ("blockchain.scripthash.listunspent", None),
+        "mempool" => ("blockchain.scripthash.get_mempool", None),
+        "atomicals" => ("blockchain.atomicals.listscripthash", Some(json!(true))),
+        _ => {
+            let message = format!("Unknown address method: {}", method);
+            return R::error_with_status(StatusCode::NOT_FOUND, -1, message);
+        }
+    };
+    let scripthash = match to_scripthash(&address) {
+        Ok(scripthash) => scripthash,
+        Err(e) => {
+            let message = format!("Invalid address: {}", e);
+            return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+        }
+    };
+    let params: Vec<_> = [Some(json!(scripthash)), extra]
+        .into_iter()
+        .flatten()
+        .collect();
+    let instances = upstreams.snapshot();
+    handle_request(cache, &instances, headers, method.into(), params).await
+}
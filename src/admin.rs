@@ -0,0 +1,258 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use axum::extract::{Extension, Query, Request};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::acl::{block, blocks, parse_net, unblock};
+use crate::audit::search;
+use crate::cache::{hottest, purge, purge_all};
+use crate::envs::{ADMIN_TOKEN, DISABLE_BROADCAST, READ_ONLY, READ_ONLY_MESSAGE};
+use crate::fanout::BROADCAST_METHOD;
+use crate::structs::{Params, ResultCache, R};
+use crate::telemetry::{log_filter, set_log_filter};
+use crate::upstream::{recent_events, Upstreams};
+
+/// Guard for `/admin` routes, which require `Authorization: Bearer $ADMIN_TOKEN` and are
+/// disabled altogether while `ADMIN_TOKEN` is unset.
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    if ADMIN_TOKEN.is_none() {
+        return R::error_with_status(StatusCode::FORBIDDEN, -1, "Admin API disabled".into())
+            .into_response();
+    }
+    if !is_admin(request.headers()) {
+        return R::error_with_status(StatusCode::UNAUTHORIZED, -1, "Unauthorized".into())
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Whether the request carries `Authorization: Bearer $ADMIN_TOKEN`, never while it is unset.
+pub fn is_admin(headers: &HeaderMap) -> bool {
+    let Some(token) = ADMIN_TOKEN.as_deref() else {
+        return false;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|x| x == token)
+}
+
+pub async fn handle_reload_upstreams(Extension(upstreams): Extension<Upstreams>) -> R {
+    match upstreams.reload() {
+        Ok(count) => {
+            info!("Admin reloaded upstreams: {} endpoints", count);
+            R::ok(json!({ "endpoints": count }))
+        }
+        Err(e) => {
+            error!("Failed to reload upstreams: {:?}", e);
+            R::error_with_status(StatusCode::BAD_REQUEST, -1, e.to_string())
+        }
+    }
+}
+
+/// The last `UPSTREAM_EVENT_LOG_SIZE` connection events of the upstreams, oldest first.
+pub async fn handle_upstream_events() -> R {
+    R::ok(json!(recent_events()))
+}
+
+/// Methods refused in read-only mode.
+const WRITE_METHODS: [&str; 1] = [BROADCAST_METHOD];
+
+/// The message write methods are refused with while in read-only mode, `None` otherwise.
+static READ_ONLY_MODE: LazyLock<Mutex<Option<String>>> =
+    LazyLock::new(|| Mutex::new(READ_ONLY.then(|| READ_ONLY_MESSAGE.clone())));
+
+/// Kill switch for relaying transactions, independent of read-only mode.
+static BROADCAST_DISABLED: LazyLock<AtomicBool> =
+    LazyLock::new(|| AtomicBool::new(*DISABLE_BROADCAST));
+
+/// The message to refuse `method` with, if it is a write method and either broadcasting is
+/// disabled or read-only mode is on.
+pub fn refuse_write(method: &str) -> Option<String> {
+    if !WRITE_METHODS.contains(&method) {
+        return None;
+    }
+    if method == BROADCAST_METHOD && BROADCAST_DISABLED.load(Ordering::SeqCst) {
+        return Some("Broadcast disabled".into());
+    }
+    READ_ONLY_MODE.lock().unwrap().clone()
+}
+
+#[derive(Deserialize)]
+pub struct ReadOnly {
+    enabled: bool,
+    message: Option<String>,
+}
+
+/// Switch read-only mode on or off, the message defaults to `READ_ONLY_MESSAGE`.
+pub async fn handle_read_only(Json(read_only): Json<ReadOnly>) -> R {
+    let message = read_only
+        .enabled
+        .then(|| read_only.message.unwrap_or(READ_ONLY_MESSAGE.clone()));
+    if read_only.enabled {
+        warn!("Admin enabled read-only mode");
+    } else {
+        info!("Admin disabled read-only mode");
+    }
+    *READ_ONLY_MODE.lock().unwrap() = message.clone();
+    R::ok(json!({ "enabled": read_only.enabled, "message": message }))
+}
+
+#[derive(Deserialize)]
+pub struct Broadcast {
+    enabled: bool,
+}
+
+/// Switch relaying of `blockchain.transaction.broadcast` on or off.
+pub async fn handle_broadcast(Json(broadcast): Json<Broadcast>) -> R {
+    if broadcast.enabled {
+        info!("Admin enabled broadcasting");
+    } else {
+        warn!("Admin disabled broadcasting");
+    }
+    BROADCAST_DISABLED.store(!broadcast.enabled, Ordering::SeqCst);
+    R::ok(json!({ "enabled": broadcast.enabled }))
+}
+
+/// The directives log lines are currently filtered with.
+pub async fn handle_log_level() -> R {
+    R::ok(json!({ "filter": log_filter() }))
+}
+
+#[derive(Deserialize)]
+pub struct LogLevel {
+    filter: String,
+}
+
+/// Change which log lines are written until the next restart, e.g. `info,elex_proxy=debug`.
+pub async fn handle_set_log_level(Json(log_level): Json<LogLevel>) -> R {
+    match set_log_filter(&log_level.filter) {
+        Ok(()) => {
+            warn!("Admin set the log filter to {}", log_level.filter);
+            R::ok(json!({ "filter": log_filter() }))
+        }
+        Err(e) => R::error_with_status(
+            StatusCode::BAD_REQUEST,
+            -1,
+            format!("Invalid log filter: {}", e),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Broadcasts {
+    txid: Option<String>,
+    client_ip: Option<String>,
+    limit: Option<usize>,
+}
+
+/// The latest broadcast attempts of `BROADCAST_AUDIT_LOG`, of a transaction or a client when
+/// given, 100 unless `limit` is given.
+pub async fn handle_broadcasts(Query(query): Query<Broadcasts>) -> R {
+    let found = tokio::task::spawn_blocking(move || {
+        search(
+            query.txid.as_deref(),
+            query.client_ip.as_deref(),
+            query.limit.unwrap_or(100),
+        )
+    })
+    .await
+    .unwrap();
+    match found {
+        Ok(attempts) => R::ok(json!(attempts)),
+        Err(e) => R::error_with_status(StatusCode::BAD_REQUEST, -1, e.to_string()),
+    }
+}
+
+/// The IP blocks added at runtime that have not expired yet.
+pub async fn handle_blocks() -> R {
+    R::ok(json!(blocks()))
+}
+
+#[derive(Deserialize)]
+pub struct Block {
+    net: String,
+    /// Seconds the block lasts, an hour by default.
+    duration: Option<u64>,
+    reason: Option<String>,
+}
+
+/// Refuse an IP or CIDR range with 403 for a while, on top of `IP_DENYLIST`.
+pub async fn handle_block(Json(block_request): Json<Block>) -> R {
+    let net = match parse_net(&block_request.net) {
+        Ok(net) => net,
+        Err(message) => return R::error_with_status(StatusCode::BAD_REQUEST, -1, message),
+    };
+    let duration = block_request.duration.unwrap_or(3600);
+    warn!(
+        "Admin blocked {} for {}s: {}",
+        net,
+        duration,
+        block_request.reason.as_deref().unwrap_or("no reason given")
+    );
+    block(net, Duration::from_secs(duration), block_request.reason);
+    R::ok(json!({ "net": net.to_string(), "expires_in": duration }))
+}
+
+#[derive(Deserialize)]
+pub struct Unblock {
+    net: String,
+}
+
+/// Lift a block added at runtime.
+pub async fn handle_unblock(Json(unblock_request): Json<Unblock>) -> R {
+    let net = match parse_net(&unblock_request.net) {
+        Ok(net) => net,
+        Err(message) => return R::error_with_status(StatusCode::BAD_REQUEST, -1, message),
+    };
+    let unblocked = unblock(net);
+    if unblocked {
+        info!("Admin unblocked {}", net);
+    }
+    R::ok(json!({ "net": net.to_string(), "unblocked": unblocked }))
+}
+
+#[derive(Deserialize)]
+pub struct Purge {
+    method: String,
+    params: Option<Params>,
+}
+
+/// Drop the cached result of a call, or every cached result of a method when `params` is
+/// left out.
+pub async fn handle_cache_purge(
+    Extension(cache): Extension<ResultCache>,
+    Json(purge_request): Json<Purge>,
+) -> R {
+    let method = purge_request.method;
+    let keys = purge(&cache, &method, purge_request.params.as_ref()).await;
+    info!("Admin purged {} cache keys of {}", keys, method);
+    R::ok(json!({ "method": method, "keys": keys }))
+}
+
+/// Drop every cached result.
+pub async fn handle_cache_clear(Extension(cache): Extension<ResultCache>) -> R {
+    purge_all(&cache).await;
+    warn!("Admin cleared the cache");
+    R::ok(json!({ "cleared": true }))
+}
+
+#[derive(Deserialize)]
+pub struct Hottest {
+    limit: Option<usize>,
+}
+
+/// The calls served from the cache most often, 20 unless `limit` is given.
+pub async fn handle_cache_hottest(Query(hottest_query): Query<Hottest>) -> R {
+    R::ok(json!(hottest(hottest_query.limit.unwrap_or(20))))
+}
@@ -0,0 +1,254 @@
+use axum::extract::{Extension, Path, Query};
+use axum::http::{HeaderMap, StatusCode};
+use serde_json::{json, Value};
+
+use crate::handle_request;
+use crate::structs::{ResultCache, R};
+use crate::upstream::{Instance, Upstreams};
+
+/// Resolve a realm or subrealm such as `myname` or `myname.sub` to its atomical id, level by
+/// level with `get_by_realm` and `get_by_subrealm`. Returns the status and candidates of the
+/// last level together with the atomical ids of its parents.
+pub async fn handle_realm(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> R {
+    let name = name.to_lowercase();
+    let parts = name.split('.').collect::<Vec<_>>();
+    if parts.iter().any(|x| !is_realm_name(x)) {
+        let message = format!("Invalid realm name: {}", name);
+        return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+    }
+    let instances = upstreams.snapshot();
+    let mut levels = vec![];
+    let mut found = Value::Null;
+    for (i, part) in parts.iter().enumerate() {
+        let (method, params) = match levels.last() {
+            None => ("blockchain.atomicals.get_by_realm", vec![json!(part)]),
+            Some((_, parent_id)) => (
+                "blockchain.atomicals.get_by_subrealm",
+                vec![json!(parent_id), json!(part)],
+            ),
+        };
+        found = match lookup(&cache, &instances, &headers, method, params).await {
+            Ok(found) => found,
+            Err(r) => return r,
+        };
+        let full_name = parts[..=i].join(".");
+        let Some(atomical_id) = found.get("atomical_id").and_then(|x| x.as_str()) else {
+            let message = format!("Realm not found: {}", full_name);
+            return R::error_with_status(StatusCode::NOT_FOUND, -1, message);
+        };
+        levels.push((full_name, atomical_id.to_string()));
+    }
+    let (_, atomical_id) = levels.pop().unwrap_or_default();
+    let parents = levels
+        .into_iter()
+        .map(|(name, atomical_id)| json!({ "name": name, "atomical_id": atomical_id }))
+        .collect::<Vec<_>>();
+    R::ok(json!({
+        "name": name,
+        "atomical_id": atomical_id,
+        "parents": parents,
+        "status": found.get("status"),
+        "candidate_atomical_id": found.get("candidate_atomical_id"),
+        "candidates": found.get("candidates"),
+    }))
+}
+
+fn is_realm_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-')
+}
+
+/// Run an Atomicals query through the cache and return its `result` object.
+async fn lookup(
+    cache: &ResultCache,
+    instances: &[Instance],
+    headers: &HeaderMap,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value, R> {
+    let r = handle_request(
+        cache.clone(),
+        instances,
+        headers.clone(),
+        method.into(),
+        params,
+    )
+    .await;
+    if !r.success {
+        return Err(r);
+    }
+    Ok(r.response
+        .and_then(|mut x| x.get_mut("result").map(Value::take))
+        .unwrap_or_default())
+}
+
+/// Resolve an ARC-20 ticker with `get_by_ticker` and `get_ft_info`: its atomical id, the
+/// deploy parameters (the `$` fields of the token, without the `$`) and the mint progress.
+pub async fn handle_ticker(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(ticker): Path<String>,
+) -> R {
+    let ticker = ticker.to_lowercase();
+    if ticker.is_empty() || !ticker.chars().all(|x| x.is_ascii_alphanumeric()) {
+        let message = format!("Invalid ticker: {}", ticker);
+        return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+    }
+    let instances = upstreams.snapshot();
+    let method = "blockchain.atomicals.get_by_ticker";
+    let found = match lookup(&cache, &instances, &headers, method, vec![json!(ticker)]).await {
+        Ok(found) => found,
+        Err(r) => return r,
+    };
+    let Some(atomical_id) = found.get("atomical_id").and_then(|x| x.as_str()) else {
+        let message = format!("Ticker not found: {}", ticker);
+        return R::error_with_status(StatusCode::NOT_FOUND, -1, message);
+    };
+    let method = "blockchain.atomicals.get_ft_info";
+    let info = match lookup(
+        &cache,
+        &instances,
+        &headers,
+        method,
+        vec![json!(atomical_id)],
+    )
+    .await
+    {
+        Ok(info) => info,
+        Err(r) => return r,
+    };
+    let deploy = info
+        .as_object()
+        .map(|x| {
+            x.iter()
+                .filter_map(|(k, v)| k.strip_prefix('$').map(|k| (k.to_string(), v.clone())))
+                .collect::<serde_json::Map<_, _>>()
+        })
+        .unwrap_or_default();
+    let mint_count = info
+        .pointer("/dft_info/mint_count")
+        .and_then(|x| x.as_u64());
+    let max_mints = deploy.get("max_mints").and_then(|x| x.as_u64());
+    R::ok(json!({
+        "ticker": ticker,
+        "atomical_id": atomical_id,
+        "atomical_number": info.get("atomical_number"),
+        "status": found.get("status"),
+        "candidate_atomical_id": found.get("candidate_atomical_id"),
+        "candidates": found.get("candidates"),
+        "deploy": deploy,
+        "mint": {
+            "mode": info.get("mint_mode"),
+            "count": mint_count,
+            "max": max_mints,
+            "minted_out": mint_count.zip(max_mints).map(|(n, max)| n >= max),
+            "bitworkc_current": info.pointer("/dft_info/mint_bitworkc_current"),
+            "bitworkr_current": info.pointer("/dft_info/mint_bitworkr_current"),
+        },
+    }))
+}
+
+/// Items returned per page of `/container/:name/items` when no `limit` is given.
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 100;
+
+/// Page through the dmitems of a container with `get_container_items`. `offset` and `limit`
+/// come from the query, `next_offset` is set while more items may follow.
+pub async fn handle_container_items(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(query): Query<Value>,
+) -> R {
+    let name = name.to_lowercase();
+    if !is_realm_name(&name) {
+        let message = format!("Invalid container name: {}", name);
+        return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+    }
+    let number = |key| {
+        query
+            .get(key)
+            .and_then(|x| x.as_str())
+            .and_then(|x| x.parse::<u64>().ok())
+    };
+    let offset = number("offset").unwrap_or_default();
+    let limit = number("limit")
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let instances = upstreams.snapshot();
+    let atomical_id = match container_id(&cache, &instances, &headers, &name).await {
+        Ok(atomical_id) => atomical_id,
+        Err(r) => return r,
+    };
+    let method = "blockchain.atomicals.get_container_items";
+    let params = vec![json!(name), json!(limit), json!(offset)];
+    let items = match lookup(&cache, &instances, &headers, method, params).await {
+        Ok(items) => items,
+        Err(r) => return r,
+    };
+    let count = match &items {
+        Value::Array(x) => x.len(),
+        Value::Object(x) => x.len(),
+        _ => 0,
+    } as u64;
+    R::ok(json!({
+        "container": name,
+        "atomical_id": atomical_id,
+        "offset": offset,
+        "limit": limit,
+        "items": items,
+        "next_offset": (count >= limit).then_some(offset + count),
+    }))
+}
+
+/// Resolve a single dmitem of a container with `get_by_container_item`.
+pub async fn handle_container_item(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path((name, item)): Path<(String, String)>,
+) -> R {
+    let name = name.to_lowercase();
+    if !is_realm_name(&name) {
+        let message = format!("Invalid container name: {}", name);
+        return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+    }
+    let instances = upstreams.snapshot();
+    let method = "blockchain.atomicals.get_by_container_item";
+    let params = vec![json!(name), json!(item)];
+    let found = match lookup(&cache, &instances, &headers, method, params).await {
+        Ok(found) => found,
+        Err(r) => return r,
+    };
+    if found.get("atomical_id").and_then(|x| x.as_str()).is_none() {
+        let message = format!("Item not found: {}/{}", name, item);
+        return R::error_with_status(StatusCode::NOT_FOUND, -1, message);
+    }
+    R::ok(found)
+}
+
+async fn container_id(
+    cache: &ResultCache,
+    instances: &[Instance],
+    headers: &HeaderMap,
+    name: &str,
+) -> Result<String, R> {
+    let method = "blockchain.atomicals.get_by_container";
+    let found = lookup(cache, instances, headers, method, vec![json!(name)]).await?;
+    match found.get("atomical_id").and_then(|x| x.as_str()) {
+        Some(atomical_id) => Ok(atomical_id.to_string()),
+        None => {
+            let message = format!("Container not found: {}", name);
+            Err(R::error_with_status(StatusCode::NOT_FOUND, -1, message))
+        }
+    }
+}
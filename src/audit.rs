@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::envs::BROADCAST_AUDIT_LOG;
+use crate::structs::{Params, R};
+use crate::upstream::now_millis;
+use crate::urn::transaction_from_hex;
+
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// One `blockchain.transaction.broadcast` attempt, a line of `BROADCAST_AUDIT_LOG`.
+#[derive(Serialize, Deserialize)]
+pub struct Attempt {
+    /// Milliseconds since the epoch.
+    pub at: u64,
+    pub client_ip: String,
+    /// The API key fingerprint or bearer token subject the call was made with, see
+    /// [`Caller::identity`](crate::auth::Caller::identity).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    /// Id of the transaction, `None` when the hex does not decode.
+    pub txid: Option<String>,
+    pub hex: Value,
+    /// Instance that answered, `None` when the call was refused or fanned out.
+    pub upstream: Option<u32>,
+    pub success: bool,
+    /// The txid returned by the upstream or the error message.
+    pub result: Option<Value>,
+}
+
+/// Open `BROADCAST_AUDIT_LOG` for appending, creating it if needed.
+pub fn open() -> anyhow::Result<()> {
+    let Some(path) = BROADCAST_AUDIT_LOG.as_deref() else {
+        return Ok(());
+    };
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = LOG.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Append a broadcast attempt and how it was answered to the audit log.
+pub fn record(client_ip: &str, caller: Option<String>, params: &Params, r: &R) {
+    let Some(log) = LOG.get() else {
+        return;
+    };
+    let hex = params.positional().first().cloned().unwrap_or_default();
+    let txid = hex
+        .as_str()
+        .and_then(|x| transaction_from_hex(x.trim()).ok())
+        .map(|x| x.compute_txid().to_string());
+    let attempt = Attempt {
+        at: now_millis(),
+        client_ip: client_ip.to_string(),
+        caller,
+        txid,
+        hex,
+        upstream: r.timing.as_ref().map(|x| x.instance),
+        success: r.success,
+        result: if r.success {
+            r.response.clone()
+        } else {
+            r.message.clone()
+        },
+    };
+    let mut line = serde_json::to_string(&attempt).unwrap();
+    line.push('\n');
+    if let Err(e) = log.lock().unwrap().write_all(line.as_bytes()) {
+        warn!("Failed to write BROADCAST_AUDIT_LOG: {}", e);
+    }
+}
+
+/// The latest `limit` attempts matching `txid` and `client_ip` when given, newest first. The
+/// log is read line by line and only the last matches are kept.
+pub fn search(
+    txid: Option<&str>,
+    client_ip: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<Vec<Attempt>> {
+    let Some(path) = BROADCAST_AUDIT_LOG.as_deref() else {
+        anyhow::bail!("BROADCAST_AUDIT_LOG is not set");
+    };
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+    let mut attempts = VecDeque::with_capacity(limit.min(1000));
+    for line in BufReader::new(File::open(path)?).lines() {
+        let Ok(attempt) = serde_json::from_str::<Attempt>(&line?) else {
+            continue;
+        };
+        if txid.is_some_and(|x| attempt.txid.as_deref() != Some(x))
+            || client_ip.is_some_and(|x| attempt.client_ip != x)
+        {
+            continue;
+        }
+        if attempts.len() == limit {
+            attempts.pop_front();
+        }
+        attempts.push_back(attempt);
+    }
+    Ok(attempts.into_iter().rev().collect())
+}
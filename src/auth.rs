@@ -0,0 +1,93 @@
+use axum::extract::Request;
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use bitcoin::hashes::sha256;
+
+use crate::jwt;
+use crate::limit::{api_key, is_known_key};
+use crate::structs::R;
+
+/// Who a request is made by, resolved by [`authenticate`] and kept in the request extensions.
+#[derive(Clone)]
+pub enum Caller {
+    /// A key of `API_KEYS`.
+    Key(String),
+    /// The subject of a verified bearer token.
+    Subject(String),
+    /// An API key that is not one of `API_KEYS`, refused by [`require_auth`].
+    UnknownKey,
+    /// Neither, with the reason its bearer token was refused if it had one.
+    Anonymous(Option<String>),
+}
+
+impl Caller {
+    /// How the caller is named in logs kept on disk: `key:` and a fingerprint of the API key,
+    /// which is never written as is, or `sub:` and the subject of the bearer token.
+    pub fn identity(&self) -> Option<String> {
+        match self {
+            Caller::Key(key) => {
+                let hash = <sha256::Hash as bitcoin::hashes::Hash>::hash(key.as_bytes());
+                Some(format!("key:{}", hex::encode(&hash[..8])))
+            }
+            Caller::Subject(subject) => Some(format!("sub:{}", subject)),
+            Caller::UnknownKey | Caller::Anonymous(_) => None,
+        }
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+}
+
+/// Resolve the [`Caller`] of every request for the rate limiter and [`require_auth`]. Unknown
+/// API keys and bearer tokens which fail verification are only refused by [`require_auth`],
+/// so that `/healthz`, `/metrics` and the `/admin` routes, which carry `ADMIN_TOKEN` in the
+/// same header, are answered whatever the client sends.
+pub async fn authenticate(mut request: Request, next: Next) -> Response {
+    let caller = caller(request.headers());
+    request.extensions_mut().insert(caller);
+    next.run(request).await
+}
+
+/// The [`Caller`] of a request with these headers.
+pub fn caller(headers: &HeaderMap) -> Caller {
+    match api_key(headers) {
+        Some(key) if is_known_key(&key) => Caller::Key(key),
+        Some(_) => Caller::UnknownKey,
+        None => match bearer(headers).filter(|_| jwt::is_enabled()) {
+            Some(token) => match jwt::verify(token) {
+                Ok(subject) => Caller::Subject(subject),
+                Err(e) => Caller::Anonymous(Some(format!("Invalid bearer token: {}", e))),
+            },
+            None => Caller::Anonymous(None),
+        },
+    }
+}
+
+/// Guard for the routes backed by the upstreams, which refuse unknown API keys and require an
+/// API key or a valid bearer token while JWTs are enabled.
+pub async fn require_auth(request: Request, next: Next) -> Response {
+    match request.extensions().get::<Caller>() {
+        Some(Caller::UnknownKey) => {
+            let message = "Invalid API key".to_string();
+            return R::error_with_status(StatusCode::UNAUTHORIZED, -1, message).into_response();
+        }
+        _ if !jwt::is_enabled() => return next.run(request).await,
+        _ => {}
+    }
+    match request.extensions().get::<Caller>() {
+        Some(Caller::Key(_) | Caller::Subject(_)) => next.run(request).await,
+        Some(Caller::Anonymous(Some(reason))) => {
+            R::error_with_status(StatusCode::UNAUTHORIZED, -1, reason.clone()).into_response()
+        }
+        _ => {
+            let message = "Missing bearer token".to_string();
+            R::error_with_status(StatusCode::UNAUTHORIZED, -1, message).into_response()
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use moka::future::Cache;
+use moka::notification::RemovalCause;
+use moka::Expiry;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::cache::stale_retention;
+use crate::envs::{
+    CACHE_TIME_TO_IDLE, MAX_CACHE_BYTES, MAX_CACHE_ENTRIES, MAX_IMMUTABLE_CACHE_ENTRIES,
+};
+use crate::metrics::{CACHE_EVICTIONS_EXPIRED, CACHE_EVICTIONS_SIZE};
+use crate::structs::R;
+
+/// Where results are cached, selected by `CACHE_BACKEND`.
+pub trait CacheBackend: Send + Sync {
+    /// A cached result, `immutable` as given by [`crate::cache::is_immutable`].
+    fn get(&self, key: u64, immutable: bool) -> BoxFuture<'_, Option<R>>;
+
+    /// Cache a result until its `expires_at`.
+    fn insert(&self, key: u64, r: R) -> BoxFuture<'_, ()>;
+
+    /// Drop every result that may have changed with a new block, such as histories,
+    /// balances, unspent outputs and states of atomicals, keeping the immutable ones and those
+    /// of methods that are not [height-sensitive](crate::cache::is_height_sensitive).
+    fn invalidate_on_block(&self) -> BoxFuture<'_, ()>;
+
+    /// Drop the result of a call, mutable or not.
+    fn invalidate(&self, key: u64) -> BoxFuture<'_, ()>;
+
+    fn invalidate_all(&self) -> BoxFuture<'_, ()>;
+
+    /// A JSON summary for `/status`.
+    fn stats(&self) -> Value;
+
+    /// The results held in the memory of the proxy, `None` for backends keeping them
+    /// elsewhere.
+    fn size(&self) -> Option<CacheSize> {
+        None
+    }
+}
+
+pub struct CacheSize {
+    pub entries: u64,
+    /// Bytes of serialized results with `MAX_CACHE_BYTES`, the number of results that expire
+    /// otherwise. Immutable results are counted in `entries` only.
+    pub weighted_size: u64,
+}
+
+/// Results in the memory of the proxy, bounded by `MAX_CACHE_BYTES` or `MAX_CACHE_ENTRIES`.
+/// Immutable results are kept apart, without a time to live, until `MAX_IMMUTABLE_CACHE_ENTRIES`
+/// forces them out.
+pub struct MokaBackend {
+    cache: Cache<u64, R>,
+    immutable: Cache<u64, R>,
+}
+
+impl MokaBackend {
+    pub fn new() -> Self {
+        let builder = match *MAX_CACHE_BYTES {
+            0 => Cache::builder().max_capacity(*MAX_CACHE_ENTRIES),
+            bytes => Cache::builder().max_capacity(bytes).weigher(weigh),
+        };
+        let cache = builder
+            .expire_after(CacheExpiry)
+            .support_invalidation_closures()
+            .time_to_idle(Duration::from_secs(*CACHE_TIME_TO_IDLE))
+            .eviction_listener(on_removal)
+            .build();
+        let immutable = Cache::builder()
+            .max_capacity(*MAX_IMMUTABLE_CACHE_ENTRIES)
+            .eviction_listener(on_removal)
+            .build();
+        Self { cache, immutable }
+    }
+}
+
+impl CacheBackend for MokaBackend {
+    /// Errors of immutable calls expire like any other result, so they are looked up in the
+    /// tier that expires as well.
+    fn get(&self, key: u64, immutable: bool) -> BoxFuture<'_, Option<R>> {
+        async move {
+            if immutable {
+                if let Some(r) = self.immutable.get(&key).await {
+                    return Some(r);
+                }
+            }
+            self.cache.get(&key).await
+        }
+        .boxed()
+    }
+
+    fn insert(&self, key: u64, r: R) -> BoxFuture<'_, ()> {
+        if r.immutable {
+            self.immutable.insert(key, r).boxed()
+        } else {
+            self.cache.insert(key, r).boxed()
+        }
+    }
+
+    fn invalidate_on_block(&self) -> BoxFuture<'_, ()> {
+        if let Err(e) = self.cache.invalidate_entries_if(|_, r| !r.block_stable) {
+            warn!("Failed to invalidate cache, dropping all entries: {}", e);
+            self.cache.invalidate_all();
+        }
+        async {}.boxed()
+    }
+
+    fn invalidate(&self, key: u64) -> BoxFuture<'_, ()> {
+        async move {
+            self.cache.invalidate(&key).await;
+            self.immutable.invalidate(&key).await;
+        }
+        .boxed()
+    }
+
+    fn invalidate_all(&self) -> BoxFuture<'_, ()> {
+        self.cache.invalidate_all();
+        self.immutable.invalidate_all();
+        async {}.boxed()
+    }
+
+    fn stats(&self) -> Value {
+        json!({
+            "backend": "moka",
+            "entries": self.cache.entry_count(),
+            "weighted_size": self.cache.weighted_size(),
+            "immutable_entries": self.immutable.entry_count(),
+            "evictions": {
+                "size": CACHE_EVICTIONS_SIZE.load(Ordering::Relaxed),
+                "expired": CACHE_EVICTIONS_EXPIRED.load(Ordering::Relaxed),
+            },
+        })
+    }
+
+    fn size(&self) -> Option<CacheSize> {
+        Some(CacheSize {
+            entries: self.cache.entry_count() + self.immutable.entry_count(),
+            weighted_size: self.cache.weighted_size(),
+        })
+    }
+}
+
+/// The size of a result as sent to clients, a list of atomicals can take megabytes while
+/// most results take a few bytes.
+fn weigh(_key: &u64, r: &R) -> u32 {
+    let size = serde_json::to_vec(r).map_or(0, |x| x.len());
+    size.try_into().unwrap_or(u32::MAX)
+}
+
+fn on_removal(_key: Arc<u64>, _r: R, cause: RemovalCause) {
+    let counter = match cause {
+        RemovalCause::Size => &CACHE_EVICTIONS_SIZE,
+        RemovalCause::Expired => &CACHE_EVICTIONS_EXPIRED,
+        RemovalCause::Explicit | RemovalCause::Replaced => return,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Expires each cache entry at the `expires_at` of its result, kept past it for
+/// [`stale_retention`].
+struct CacheExpiry;
+
+impl Expiry<u64, R> for CacheExpiry {
+    fn expire_after_create(&self, _key: &u64, value: &R, created_at: Instant) -> Option<Duration> {
+        value
+            .expires_at
+            .map(|x| x.saturating_duration_since(created_at) + stale_retention())
+    }
+
+    fn expire_after_update(
+        &self,
+        key: &u64,
+        value: &R,
+        updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.expire_after_create(key, value, updated_at)
+    }
+}
+
+/// A near cache in memory in front of a shared far one, results found in the far cache are
+/// kept near for the rest of their time to live.
+pub struct TieredBackend {
+    pub near: Arc<dyn CacheBackend>,
+    pub far: Arc<dyn CacheBackend>,
+}
+
+impl CacheBackend for TieredBackend {
+    fn get(&self, key: u64, immutable: bool) -> BoxFuture<'_, Option<R>> {
+        async move {
+            if let Some(r) = self.near.get(key, immutable).await {
+                return Some(r);
+            }
+            let r = self.far.get(key, immutable).await?;
+            self.near.insert(key, r.clone()).await;
+            Some(r)
+        }
+        .boxed()
+    }
+
+    fn insert(&self, key: u64, r: R) -> BoxFuture<'_, ()> {
+        async move {
+            self.near.insert(key, r.clone()).await;
+            self.far.insert(key, r).await;
+        }
+        .boxed()
+    }
+
+    fn invalidate_on_block(&self) -> BoxFuture<'_, ()> {
+        async move {
+            self.near.invalidate_on_block().await;
+            self.far.invalidate_on_block().await;
+        }
+        .boxed()
+    }
+
+    fn invalidate(&self, key: u64) -> BoxFuture<'_, ()> {
+        async move {
+            self.near.invalidate(key).await;
+            self.far.invalidate(key).await;
+        }
+        .boxed()
+    }
+
+    fn invalidate_all(&self) -> BoxFuture<'_, ()> {
+        async move {
+            self.near.invalidate_all().await;
+            self.far.invalidate_all().await;
+        }
+        .boxed()
+    }
+
+    fn stats(&self) -> Value {
+        json!({ "backend": "tiered", "near": self.near.stats(), "far": self.far.stats() })
+    }
+
+    fn size(&self) -> Option<CacheSize> {
+        self.near.size()
+    }
+}
+
+/// Caches nothing, every call goes upstream.
+pub struct NoopBackend;
+
+impl CacheBackend for NoopBackend {
+    fn get(&self, _key: u64, _immutable: bool) -> BoxFuture<'_, Option<R>> {
+        async { None }.boxed()
+    }
+
+    fn insert(&self, _key: u64, _r: R) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn invalidate_on_block(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn invalidate(&self, _key: u64) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn invalidate_all(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn stats(&self) -> Value {
+        json!({ "backend": "none" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cached(block_stable: bool) -> R {
+        R {
+            expires_at: Some(Instant::now() + Duration::from_secs(60)),
+            block_stable,
+            ..R::ok(json!(1))
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_results_that_do_not_change_with_blocks() {
+        let cache = MokaBackend::new();
+        cache.insert(1, cached(true)).await;
+        cache.insert(2, cached(false)).await;
+        cache
+            .insert(
+                3,
+                R {
+                    immutable: true,
+                    ..cached(false)
+                },
+            )
+            .await;
+        cache.invalidate_on_block().await;
+        assert!(cache.get(1, false).await.is_some());
+        assert!(cache.get(2, false).await.is_none());
+        assert!(cache.get(3, true).await.is_some());
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::atomic::Ordering;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use futures::StreamExt;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::cache::redis::connection_manager;
+use crate::envs::{CACHE_INVALIDATION_BUS, REDIS_KEY_PREFIX, REDIS_URL};
+use crate::structs::ResultCache;
+use crate::CACHED_BLOCK_HEIGHT;
+
+static PUBLISHER: OnceCell<ConnectionManager> = OnceCell::const_new();
+
+/// Tells the events of this replica from those of the others.
+static ORIGIN: LazyLock<u64> = LazyLock::new(rand::random);
+
+static CHANNEL: LazyLock<String> = LazyLock::new(|| format!("{}invalidate", *REDIS_KEY_PREFIX));
+
+/// A new block seen by one replica, published to the others sharing `REDIS_URL`.
+#[derive(Serialize, Deserialize)]
+struct Event {
+    origin: u64,
+    height: u64,
+}
+
+/// Join the invalidation bus with `CACHE_INVALIDATION_BUS`. Replicas drop the results that
+/// may have changed as soon as any of them sees a new block.
+pub async fn start(cache: ResultCache) -> anyhow::Result<()> {
+    if !*CACHE_INVALIDATION_BUS {
+        return Ok(());
+    }
+    let client = redis::Client::open(REDIS_URL.as_str())?;
+    let _ = PUBLISHER.set(connection_manager(&client).await?);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = subscribe(&client, &cache).await {
+                warn!("Invalidation bus failed: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    });
+    info!("Joined invalidation bus {}", *CHANNEL);
+    Ok(())
+}
+
+async fn subscribe(client: &redis::Client, cache: &ResultCache) -> anyhow::Result<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(CHANNEL.as_str()).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let Ok(event) = serde_json::from_slice::<Event>(message.get_payload_bytes()) else {
+            continue;
+        };
+        if event.origin == *ORIGIN {
+            continue;
+        }
+        // Only move forward, a replica lagging behind must not roll the others back.
+        if CACHED_BLOCK_HEIGHT.fetch_max(event.height, Ordering::SeqCst) < event.height {
+            cache.invalidate_on_block().await;
+            info!(
+                "New block height by bus: {}, invalidate cache",
+                event.height
+            );
+        }
+    }
+    anyhow::bail!("Subscription closed")
+}
+
+/// Tell the other replicas about a new block, a no-op without `CACHE_INVALIDATION_BUS`.
+pub fn publish_block(height: u64) {
+    let Some(publisher) = PUBLISHER.get() else {
+        return;
+    };
+    let mut publisher = publisher.clone();
+    let event = serde_json::to_string(&Event {
+        origin: *ORIGIN,
+        height,
+    })
+    .unwrap();
+    tokio::spawn(async move {
+        if let Err(e) = publisher.publish::<_, _, ()>(CHANNEL.as_str(), event).await {
+            warn!("Failed to publish to invalidation bus: {}", e);
+        }
+    });
+}
@@ -0,0 +1,122 @@
+use std::sync::OnceLock;
+
+use serde_json::Value;
+use tracing::{info, warn};
+
+use super::to_canonical_call;
+use crate::envs::DISK_CACHE_PATH;
+use crate::structs::Params;
+
+/// The tree results are kept in, named after the version of the key scheme. Bump it whenever
+/// [`to_canonical_call`] changes, results kept under keys that are never looked up again are
+/// then dropped on open instead of filling the disk.
+const TREE: &str = "calls-v2";
+
+static DB: OnceLock<sled::Tree> = OnceLock::new();
+
+/// Open the store at `DISK_CACHE_PATH`, results of immutable calls are kept there across
+/// restarts.
+pub fn open() -> anyhow::Result<()> {
+    let Some(path) = DISK_CACHE_PATH.as_deref() else {
+        return Ok(());
+    };
+    let _ = DB.set(open_tree(&sled::open(path)?)?);
+    Ok(())
+}
+
+/// The tree of the current key scheme in `db`, dropping those of older ones.
+fn open_tree(db: &sled::Db) -> sled::Result<sled::Tree> {
+    // Stores of the first scheme, `[method, params]` as JSON, used the default tree.
+    if !db.is_empty() {
+        info!("Dropping disk cache entries of an older key scheme");
+        db.clear()?;
+    }
+    for name in db.tree_names() {
+        if name != TREE.as_bytes() && name != db.name() {
+            info!(
+                "Dropping disk cache tree {} of an older key scheme",
+                String::from_utf8_lossy(&name)
+            );
+            db.drop_tree(name)?;
+        }
+    }
+    db.open_tree(TREE)
+}
+
+/// Keys are the canonical call hashed by [`super::to_cache_key`], so that calls sharing a
+/// result in memory share it on disk, and the prefix of a method can be scanned.
+fn to_key(method: &str, params: &Params) -> Vec<u8> {
+    to_canonical_call(method, params).into_bytes()
+}
+
+pub fn get(method: &str, params: &Params) -> Option<Value> {
+    let db = DB.get()?;
+    match db.get(to_key(method, params)) {
+        Ok(value) => serde_json::from_slice(&value?).ok(),
+        Err(e) => {
+            warn!("Disk cache get failed: {}", e);
+            None
+        }
+    }
+}
+
+pub fn insert(method: &str, params: &Params, result: &Value) {
+    let Some(db) = DB.get() else {
+        return;
+    };
+    let value = serde_json::to_vec(result).unwrap();
+    if let Err(e) = db.insert(to_key(method, params), value) {
+        warn!("Disk cache insert failed: {}", e);
+    }
+}
+
+pub fn remove(method: &str, params: &Params) {
+    let Some(db) = DB.get() else {
+        return;
+    };
+    if let Err(e) = db.remove(to_key(method, params)) {
+        warn!("Disk cache remove failed: {}", e);
+    }
+}
+
+/// Remove every result of `method`, whose keys all start with the same bytes.
+pub fn remove_method(method: &str) {
+    let Some(db) = DB.get() else {
+        return;
+    };
+    for key in db.scan_prefix(format!("{} ", method)).keys().flatten() {
+        if let Err(e) = db.remove(key) {
+            warn!("Disk cache remove failed: {}", e);
+        }
+    }
+}
+
+pub fn clear() {
+    let Some(db) = DB.get() else {
+        return;
+    };
+    if let Err(e) = db.clear() {
+        warn!("Disk cache clear failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_results_of_older_key_schemes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert(br#"["blockchain.transaction.get",["00"]]"#, b"\"00\"")
+            .unwrap();
+        db.open_tree("calls-v1")
+            .unwrap()
+            .insert(b"a", b"1")
+            .unwrap();
+        open_tree(&db).unwrap().insert(b"b", b"2").unwrap();
+        let tree = open_tree(&db).unwrap();
+        assert!(db.is_empty());
+        assert_eq!(db.tree_names().len(), 2);
+        assert_eq!(tree.get(b"b").unwrap().as_deref(), Some(&b"2"[..]));
+    }
+}
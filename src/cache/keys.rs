@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use moka::future::Cache;
+use serde_json::{json, Value};
+
+use crate::envs::{CACHE_TIME_TO_IDLE, MAX_CACHE_ENTRIES};
+use crate::structs::Params;
+
+/// A cached call, kept to purge the results of a method and to rank keys by hits.
+struct Key {
+    method: String,
+    params: Params,
+    hits: AtomicU64,
+}
+
+/// Calls behind the cache keys seen by this proxy, bounded like the cache itself.
+static KEYS: LazyLock<Cache<u64, Arc<Key>>> = LazyLock::new(|| {
+    Cache::builder()
+        .max_capacity(*MAX_CACHE_ENTRIES)
+        .time_to_idle(Duration::from_secs(*CACHE_TIME_TO_IDLE))
+        .build()
+});
+
+async fn entry(key: u64, method: &str, params: &Params) -> Arc<Key> {
+    KEYS.entry(key)
+        .or_insert_with(async {
+            Arc::new(Key {
+                method: method.to_string(),
+                params: params.clone(),
+                hits: AtomicU64::new(0),
+            })
+        })
+        .await
+        .into_value()
+}
+
+pub async fn record(key: u64, method: &str, params: &Params) {
+    entry(key, method, params).await;
+}
+
+/// Count a cache hit, also for keys cached by another proxy sharing the backend.
+pub async fn hit(key: u64, method: &str, params: &Params) {
+    let key = entry(key, method, params).await;
+    key.hits.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Keys of the cached calls of `method`.
+pub fn of_method(method: &str) -> Vec<u64> {
+    KEYS.iter()
+        .filter(|(_, x)| x.method == method)
+        .map(|(key, _)| *key)
+        .collect()
+}
+
+/// The `limit` calls served from the cache most often.
+pub fn hottest(limit: usize) -> Vec<Value> {
+    let mut keys = KEYS.iter().map(|(_, x)| x).collect::<Vec<_>>();
+    keys.sort_by_key(|x| std::cmp::Reverse(x.hits.load(Ordering::Relaxed)));
+    keys.iter()
+        .take(limit)
+        .map(|x| {
+            json!({
+                "method": x.method,
+                "params": x.params,
+                "hits": x.hits.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+pub async fn remove(key: u64) {
+    KEYS.invalidate(&key).await;
+}
+
+pub fn clear() {
+    KEYS.invalidate_all();
+}
@@ -0,0 +1,412 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use bitcoin::hashes::sha256;
+use serde_json::{Map, Value};
+use tracing::instrument;
+
+use crate::admin::is_admin;
+use crate::envs::{
+    CACHE_BACKEND, CACHE_BYPASS, CACHE_STALE_GRACE, CACHE_STALE_IF_ERROR, CACHE_TIME_TO_LIVE,
+    CACHE_TTL_OVERRIDES,
+};
+use crate::structs::{Params, ResultCache, R};
+use crate::{matches_method, CACHED_BLOCK_HEIGHT};
+
+pub use backend::{CacheBackend, MokaBackend, NoopBackend, TieredBackend};
+pub use redis::RedisBackend;
+
+mod backend;
+pub mod bus;
+pub mod disk;
+mod keys;
+mod redis;
+
+const TRANSACTION_GET: &str = "blockchain.transaction.get";
+const BLOCK_HEADER: &str = "blockchain.block.header";
+const BLOCK_HEADERS: &str = "blockchain.block.headers";
+
+/// Blocks deep enough for a reorg replacing them to be of no concern.
+const FINAL_DEPTH: u64 = 6;
+
+pub const X_NO_CACHE: HeaderName = HeaderName::from_static("x-no-cache");
+
+/// The backend selected by `CACHE_BACKEND`.
+pub async fn connect() -> anyhow::Result<ResultCache> {
+    let cache: ResultCache = match CACHE_BACKEND.as_str() {
+        "moka" => Arc::new(MokaBackend::new()),
+        "redis" => Arc::new(RedisBackend::connect().await?),
+        "tiered" => Arc::new(TieredBackend {
+            near: Arc::new(MokaBackend::new()),
+            far: Arc::new(RedisBackend::connect().await?),
+        }),
+        "none" => Arc::new(NoopBackend),
+        other => bail!(
+            "Unknown CACHE_BACKEND {}, expected moka, redis, tiered or none",
+            other
+        ),
+    };
+    Ok(cache)
+}
+
+/// Whether a request asks to skip the cache read with `X-No-Cache: 1` and may do so under
+/// `CACHE_BYPASS`. Its result is cached all the same.
+pub fn bypasses_cache(headers: &HeaderMap) -> bool {
+    if headers.get(X_NO_CACHE).is_none_or(|x| x != "1") {
+        return false;
+    }
+    match CACHE_BYPASS.as_str() {
+        "all" => true,
+        "admin" => is_admin(headers),
+        _ => false,
+    }
+}
+
+/// Whether the result of a call can be cached as immutable: raw transactions by txid once
+/// buried under `FINAL_DEPTH` blocks, see [`unsettled_txid`], verbose ones carry their
+/// confirmations, and headers of blocks buried under `FINAL_DEPTH` others.
+pub fn is_immutable(method: &str, params: &Params) -> bool {
+    let params = params.positional();
+    let height = |i: usize| params.get(i).and_then(Value::as_u64);
+    match method {
+        TRANSACTION_GET => params.get(1).is_none_or(|x| x == &Value::Bool(false)),
+        BLOCK_HEADER => height(0).is_some_and(is_final),
+        BLOCK_HEADERS => match (height(0), height(1)) {
+            (Some(start), Some(count)) => is_final(start.saturating_add(count.saturating_sub(1))),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_final(height: u64) -> bool {
+    height.saturating_add(FINAL_DEPTH) <= CACHED_BLOCK_HEIGHT.load(Ordering::SeqCst)
+}
+
+/// The txid of an immutable call whose transaction must be confirmed before its result is
+/// cached as immutable. The raw transaction alone does not tell, and one still in the mempool
+/// may be dropped or replaced, or have its witness malleated.
+pub fn unsettled_txid(method: &str, params: &Params) -> Option<String> {
+    if method != TRANSACTION_GET || !is_immutable(method, params) {
+        return None;
+    }
+    params.positional().first()?.as_str().map(|x| x.to_string())
+}
+
+/// Whether a transaction with `confirmations` is buried deep enough to be immutable.
+pub fn is_settled(confirmations: u64) -> bool {
+    confirmations >= FINAL_DEPTH
+}
+
+/// A cached result of a call. Immutable results missing from the backend are looked up in
+/// the disk cache, if any, and brought back.
+#[instrument(name = "cache_lookup", skip_all, fields(rpc.method = method))]
+pub async fn cache_get(cache: &ResultCache, key: u64, method: &str, params: &Params) -> Option<R> {
+    let immutable = is_immutable(method, params);
+    let r = cache.get(key, immutable).await;
+    if r.is_some() {
+        keys::hit(key, method, params).await;
+    }
+    if r.is_some() || !immutable {
+        return r;
+    }
+    let r = R {
+        expires_at: Some(Instant::now() + cache_ttl(method)),
+        immutable,
+        ..R::ok(disk::get(method, params)?)
+    };
+    cache.insert(key, r.clone()).await;
+    Some(r)
+}
+
+/// Cache a result, immutable ones on disk as well.
+pub async fn cache_insert(cache: &ResultCache, key: u64, method: &str, params: &Params, r: R) {
+    if let (true, Some(result)) = (r.immutable && r.success, &r.response) {
+        disk::insert(method, params, result);
+    }
+    keys::record(key, method, params).await;
+    let r = R {
+        block_stable: !is_height_sensitive(method),
+        ..r
+    };
+    cache.insert(key, r).await;
+}
+
+/// Methods whose results may change with a new block: histories, balances, unspent outputs,
+/// states of atomicals, confirmations, headers near the tip, fee estimates and the mempool.
+const HEIGHT_SENSITIVE_METHODS: [&str; 8] = [
+    "blockchain.scripthash.*",
+    "blockchain.address.*",
+    "blockchain.atomicals.*",
+    "blockchain.transaction.*",
+    "blockchain.block.*",
+    "blockchain.headers.*",
+    "blockchain.estimatefee",
+    "mempool.*",
+];
+
+/// Whether cached results of `method` are dropped on a new block, see
+/// [`CacheBackend::invalidate_on_block`]. Others, such as `server.*`, expire by their time to
+/// live only.
+pub fn is_height_sensitive(method: &str) -> bool {
+    HEIGHT_SENSITIVE_METHODS
+        .iter()
+        .any(|x| matches_method(x, method))
+}
+
+/// Drop the cached result of a call, or with `params` unset every result of `method` this
+/// proxy has cached. Returns the number of keys dropped.
+pub async fn purge(cache: &ResultCache, method: &str, params: Option<&Params>) -> usize {
+    let keys = match params {
+        Some(params) => {
+            disk::remove(method, params);
+            vec![to_cache_key(method, params)]
+        }
+        None => {
+            disk::remove_method(method);
+            keys::of_method(method)
+        }
+    };
+    for &key in &keys {
+        cache.invalidate(key).await;
+        keys::remove(key).await;
+    }
+    keys.len()
+}
+
+/// Drop every cached result, on disk as well.
+pub async fn purge_all(cache: &ResultCache) {
+    cache.invalidate_all().await;
+    keys::clear();
+    disk::clear();
+}
+
+/// The `limit` calls served from the cache most often.
+pub fn hottest(limit: usize) -> Vec<Value> {
+    keys::hottest(limit)
+}
+
+/// How long results of `method` stay cached, `CACHE_TTL_OVERRIDES` or `CACHE_TIME_TO_LIVE`.
+pub fn cache_ttl(method: &str) -> Duration {
+    let secs = CACHE_TTL_OVERRIDES
+        .get(method)
+        .copied()
+        .unwrap_or(*CACHE_TIME_TO_LIVE);
+    Duration::from_secs(secs)
+}
+
+/// How long results are kept past their time to live, see `CACHE_STALE_GRACE`.
+pub fn stale_grace() -> Duration {
+    Duration::from_secs(*CACHE_STALE_GRACE)
+}
+
+/// How long results are kept past their time to live, to be served within `CACHE_STALE_GRACE`
+/// or, while no upstream is connected, `CACHE_STALE_IF_ERROR`.
+pub fn stale_retention() -> Duration {
+    Duration::from_secs((*CACHE_STALE_GRACE).max(*CACHE_STALE_IF_ERROR))
+}
+
+/// Whether a cached result is past its time to live, immutable results never are.
+pub fn is_stale(r: &R) -> bool {
+    !r.immutable && r.expires_at.is_some_and(|x| x <= Instant::now())
+}
+
+/// Whether a cached result may be served, stale or not, while it is refreshed.
+pub fn within_grace(r: &R) -> bool {
+    r.expires_at
+        .is_some_and(|x| x + stale_grace() > Instant::now())
+}
+
+/// Seconds a stale result is past its time to live, `None` for fresh ones.
+pub fn staleness(r: &R) -> Option<u64> {
+    let expires_at = r.expires_at.filter(|_| is_stale(r))?;
+    Some(expires_at.elapsed().as_secs())
+}
+
+/// Calls differing only in the order of object keys or the form of numbers, `1` and `1.0`,
+/// share a key. Methods are case-sensitive, as they are for ElectrumX. The key is the head of a
+/// SHA-256 of the call, the same for every build of the proxy sharing a Redis server.
+pub fn to_cache_key(method: &str, params: &Params) -> u64 {
+    let canonical = to_canonical_call(method, params);
+    let hash = <sha256::Hash as bitcoin::hashes::Hash>::hash(canonical.as_bytes());
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+/// The method, a space and the canonical params of a call, hashed by [`to_cache_key`].
+fn to_canonical_call(method: &str, params: &Params) -> String {
+    let mut canonical = method.to_string();
+    canonical.push(' ');
+    match params {
+        Params::Positional(params) => write_array(params, &mut canonical),
+        Params::Named(params) => write_object(params, &mut canonical),
+    }
+    canonical
+}
+
+/// Compact JSON with sorted object keys and integral floats written as integers.
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Number(n) if n.is_f64() => match n.as_f64() {
+            Some(x) if x.fract() == 0.0 && x.abs() < 1e15 => out.push_str(&(x as i64).to_string()),
+            _ => out.push_str(&n.to_string()),
+        },
+        Value::Array(a) => write_array(a, out),
+        Value::Object(o) => write_object(o, out),
+        _ => out.push_str(&value.to_string()),
+    }
+}
+
+fn write_array(values: &[Value], out: &mut String) {
+    out.push('[');
+    for (i, x) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_canonical(x, out);
+    }
+    out.push(']');
+}
+
+fn write_object(values: &Map<String, Value>, out: &mut String) {
+    let mut entries = values.iter().collect::<Vec<_>>();
+    entries.sort_by_key(|(k, _)| *k);
+    out.push('{');
+    for (i, (k, v)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&Value::from(k.as_str()).to_string());
+        out.push(':');
+        write_canonical(v, out);
+    }
+    out.push('}');
+}
+
+/// A weak ETag of a cached result, the same for every representation of it, enveloped or
+/// raw, compressed or not.
+pub fn to_etag(payload: &Value) -> String {
+    let hash = <sha256::Hash as bitcoin::hashes::Hash>::hash(payload.to_string().as_bytes());
+    format!("W/\"{}\"", hex::encode(&hash[..16]))
+}
+
+/// A strong ETag of content served byte for byte, such as payloads of atomicals.
+pub fn to_strong_etag(bytes: &[u8]) -> String {
+    let hash = <sha256::Hash as bitcoin::hashes::Hash>::hash(bytes);
+    format!("\"{}\"", hex::encode(&hash[..16]))
+}
+
+/// Weak comparison of `If-None-Match` against an ETag.
+pub fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn params(value: Value) -> Params {
+        match value {
+            Value::Array(params) => Params::Positional(params),
+            Value::Object(params) => Params::Named(params),
+            _ => unreachable!(),
+        }
+    }
+
+    fn key(method: &str, value: Value) -> u64 {
+        to_cache_key(method, &params(value))
+    }
+
+    #[test]
+    fn canonicalizes_cache_keys() {
+        let method = "blockchain.atomicals.get";
+        assert_eq!(key(method, json!([1])), key(method, json!([1.0])));
+        assert_eq!(
+            key(method, json!({"a": 1, "b": [2, {"d": 4, "c": 3}]})),
+            key(method, json!({"b": [2.0, {"c": 3, "d": 4}], "a": 1}))
+        );
+        assert_ne!(key(method, json!([1])), key(method, json!([1.5])));
+        assert_ne!(key(method, json!([1])), key(method, json!(["1"])));
+        assert_ne!(key(method, json!([1, 2])), key(method, json!([2, 1])));
+        assert_ne!(
+            key(method, json!([])),
+            key("blockchain.atomicals.list", json!([]))
+        );
+    }
+
+    #[test]
+    fn keeps_method_case_in_cache_keys() {
+        let method = "blockchain.transaction.get";
+        let txid = json!(["ab".repeat(32)]);
+        assert_ne!(
+            key(method, txid.clone()),
+            key("Blockchain.Transaction.Get", txid)
+        );
+    }
+
+    #[test]
+    fn cache_keys_are_stable() {
+        // Shared through Redis by every build of the proxy, must never change.
+        assert_eq!(key("server.version", json!([])), 0xe98e_b3c8_4740_841b);
+    }
+
+    #[test]
+    fn matches_etags_weakly() {
+        let etag = HeaderValue::from_static("W/\"abc\"");
+        let matches = |x: &'static str| etag_matches(&HeaderValue::from_static(x), &etag);
+        assert!(matches("W/\"abc\""));
+        assert!(matches("\"abc\""));
+        assert!(matches("\"xyz\", W/\"abc\""));
+        assert!(matches("*"));
+        assert!(!matches("\"xyz\""));
+        assert!(!matches("\"ab\""));
+        let strong = HeaderValue::from_static("\"abc\"");
+        assert!(etag_matches(
+            &HeaderValue::from_static("W/\"abc\""),
+            &strong
+        ));
+    }
+
+    #[test]
+    fn etags_depend_on_content_only() {
+        assert_eq!(to_etag(&json!({"a": 1})), to_etag(&json!({"a": 1})));
+        assert_ne!(to_etag(&json!({"a": 1})), to_etag(&json!({"a": 2})));
+        assert!(to_etag(&json!(1)).starts_with("W/\""));
+        assert!(to_strong_etag(b"x").starts_with('"'));
+    }
+
+    #[test]
+    fn tells_height_sensitive_methods() {
+        assert!(is_height_sensitive("blockchain.scripthash.get_history"));
+        assert!(is_height_sensitive("blockchain.atomicals.get_state"));
+        assert!(is_height_sensitive("blockchain.estimatefee"));
+        assert!(!is_height_sensitive("server.features"));
+        assert!(!is_height_sensitive("blockchain.relayfee"));
+    }
+
+    #[test]
+    fn raw_transactions_wait_for_settlement() {
+        let txid = "ab".repeat(32);
+        let raw = params(json!([txid]));
+        assert_eq!(unsettled_txid(TRANSACTION_GET, &raw), Some(txid.clone()));
+        let verbose = params(json!([txid, true]));
+        assert_eq!(unsettled_txid(TRANSACTION_GET, &verbose), None);
+        assert_eq!(
+            unsettled_txid("blockchain.scripthash.get_balance", &raw),
+            None
+        );
+        assert!(!is_settled(FINAL_DEPTH - 1));
+        assert!(is_settled(FINAL_DEPTH));
+    }
+}
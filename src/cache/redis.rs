@@ -0,0 +1,177 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::cache::{stale_retention, CacheBackend};
+use crate::envs::{REDIS_IMMUTABLE_TTL, REDIS_KEY_PREFIX, REDIS_URL};
+use crate::structs::R;
+use crate::upstream::now_millis;
+use crate::CACHED_BLOCK_HEIGHT;
+
+/// Results in Redis, shared by every proxy using the same server and `REDIS_KEY_PREFIX`.
+pub struct RedisBackend {
+    redis: ConnectionManager,
+}
+
+/// A cached result as stored in Redis, with the fields of `R` that are not serialized.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    r: R,
+    status: Option<u16>,
+    /// Unix timestamp in milliseconds.
+    expires_at: u64,
+}
+
+impl RedisBackend {
+    /// Connect to `REDIS_URL`, reconnecting by itself whenever the connection drops.
+    pub async fn connect() -> anyhow::Result<Self> {
+        let client = redis::Client::open(REDIS_URL.as_str())?;
+        let redis = connection_manager(&client).await?;
+        Ok(Self { redis })
+    }
+
+    /// Look up a result. Errors of immutable calls are cached like any other result, so
+    /// their key is tried as well.
+    async fn get(&self, key: u64, immutable: bool) -> Option<R> {
+        let mut redis = self.redis.clone();
+        let mut keys = vec![to_key(key, false)];
+        if immutable {
+            keys.insert(0, to_key(key, true));
+        }
+        let mut mget = redis::cmd("MGET");
+        mget.arg(keys);
+        let values = match mget.query_async::<Vec<Option<Vec<u8>>>>(&mut redis).await {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("Redis get failed: {}", e);
+                return None;
+            }
+        };
+        let bytes = values.into_iter().flatten().next()?;
+        let entry = serde_json::from_slice::<Entry>(&bytes).ok()?;
+        let now = now_millis();
+        let expires_at = match entry.expires_at.checked_sub(now) {
+            Some(ttl) => Instant::now() + Duration::from_millis(ttl),
+            // Immutable results are kept without a time to live and never go stale.
+            None if entry.r.immutable => Instant::now(),
+            // Stale results are kept by Redis for `stale_retention` only.
+            None => Instant::now().checked_sub(Duration::from_millis(now - entry.expires_at))?,
+        };
+        Some(R {
+            status: entry.status.and_then(|x| StatusCode::from_u16(x).ok()),
+            expires_at: Some(expires_at),
+            ..entry.r
+        })
+    }
+
+    async fn insert(&self, key: u64, r: R) {
+        let ttl = r
+            .expires_at
+            .map(|x| x.saturating_duration_since(Instant::now()).as_millis() as u64)
+            .unwrap_or_default();
+        if ttl == 0 {
+            return;
+        }
+        let immutable = r.immutable;
+        let key = to_key(key, immutable);
+        let entry = Entry {
+            status: r.status.map(|x| x.as_u16()),
+            expires_at: now_millis() + ttl,
+            r,
+        };
+        let bytes = serde_json::to_vec(&entry).unwrap();
+        let mut redis = self.redis.clone();
+        let ttl = if immutable {
+            *REDIS_IMMUTABLE_TTL * 1000
+        } else {
+            ttl + stale_retention().as_millis() as u64
+        };
+        let result = redis.pset_ex::<_, _, ()>(key, bytes, ttl).await;
+        if let Err(e) = result {
+            warn!("Redis set failed: {}", e);
+        }
+    }
+
+    async fn invalidate(&self, key: u64) {
+        let mut redis = self.redis.clone();
+        let keys = [to_key(key, true), to_key(key, false)];
+        if let Err(e) = redis.del::<_, ()>(&keys).await {
+            warn!("Redis delete failed: {}", e);
+        }
+    }
+
+    /// Delete every key under `REDIS_KEY_PREFIX`, for every proxy sharing them.
+    async fn invalidate_all(&self) {
+        let mut redis = self.redis.clone();
+        let pattern = format!("{}*", *REDIS_KEY_PREFIX);
+        let keys = match redis.scan_match::<_, String>(pattern).await {
+            Ok(keys) => keys.collect::<Vec<_>>().await,
+            Err(e) => {
+                warn!("Redis scan failed: {}", e);
+                return;
+            }
+        };
+        let mut redis = self.redis.clone();
+        for keys in keys.chunks(1000) {
+            if let Err(e) = redis.del::<_, ()>(keys).await {
+                warn!("Redis delete failed: {}", e);
+            }
+        }
+    }
+}
+
+impl CacheBackend for RedisBackend {
+    fn get(&self, key: u64, immutable: bool) -> BoxFuture<'_, Option<R>> {
+        self.get(key, immutable).boxed()
+    }
+
+    fn insert(&self, key: u64, r: R) -> BoxFuture<'_, ()> {
+        self.insert(key, r).boxed()
+    }
+
+    /// Keys of results that change with new blocks carry the height, a new block leaves
+    /// them behind by itself.
+    fn invalidate_on_block(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn invalidate(&self, key: u64) -> BoxFuture<'_, ()> {
+        self.invalidate(key).boxed()
+    }
+
+    fn invalidate_all(&self) -> BoxFuture<'_, ()> {
+        self.invalidate_all().boxed()
+    }
+
+    fn stats(&self) -> Value {
+        json!({ "backend": "redis" })
+    }
+}
+
+/// A connection that reconnects by itself. Retries of the first attempt are capped at a few
+/// seconds apart, so that an unreachable server fails the start instead of stalling it.
+pub async fn connection_manager(client: &redis::Client) -> redis::RedisResult<ConnectionManager> {
+    let config = ConnectionManagerConfig::new()
+        .set_number_of_retries(5)
+        .set_max_delay(2000);
+    client.get_connection_manager_with_config(config).await
+}
+
+/// Keys of results that change with new blocks include the height, so that a new block
+/// leaves them behind for every proxy sharing the cache. They expire by their TTL.
+fn to_key(key: u64, immutable: bool) -> String {
+    if immutable {
+        format!("{}tx:{:016x}", *REDIS_KEY_PREFIX, key)
+    } else {
+        let height = CACHED_BLOCK_HEIGHT.load(Ordering::SeqCst);
+        format!("{}{}:{:016x}", *REDIS_KEY_PREFIX, height, key)
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::structs::R;
+
+/// Calls on their way upstream by cache key, with the channel their result is shared on.
+static CALLS: LazyLock<Mutex<HashMap<u64, broadcast::Sender<R>>>> = LazyLock::new(Default::default);
+
+/// Run `call` unless an identical call is already on its way upstream, in which case its
+/// result is shared instead. Should that call be dropped before it completes, `call` runs
+/// after all.
+pub async fn coalesce(key: u64, call: impl Future<Output = R>) -> R {
+    let pending = {
+        let mut calls = CALLS.lock().unwrap();
+        match calls.get(&key) {
+            Some(tx) => Some(tx.subscribe()),
+            None => {
+                calls.insert(key, broadcast::channel(1).0);
+                None
+            }
+        }
+    };
+    if let Some(mut rx) = pending {
+        return match rx.recv().await {
+            Ok(r) => r,
+            Err(_) => call.await,
+        };
+    }
+    let mut leader = Leader(Some(key));
+    let r = call.await;
+    if let Some(tx) = leader.finish() {
+        let _ = tx.send(r.clone());
+    }
+    r
+}
+
+/// Unregisters the call of the leader, also when its future is dropped halfway.
+struct Leader(Option<u64>);
+
+impl Leader {
+    fn finish(&mut self) -> Option<broadcast::Sender<R>> {
+        CALLS.lock().unwrap().remove(&self.0.take()?)
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,138 @@
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::Value;
+
+use crate::envs::MAX_BODY_SIZE;
+use crate::structs::R;
+
+#[derive(Clone, Copy)]
+enum Format {
+    Cbor,
+    MessagePack,
+}
+
+impl Format {
+    fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+        match mime {
+            "application/cbor" => Some(Format::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            _ => None,
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> anyhow::Result<Value> {
+        Ok(match self {
+            Format::Cbor => ciborium::de::from_reader(bytes)?,
+            Format::MessagePack => rmp_serde::from_slice(bytes)?,
+        })
+    }
+
+    fn encode(self, value: &Value) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Format::Cbor => {
+                let mut bytes = vec![];
+                ciborium::ser::into_writer(value, &mut bytes)?;
+                bytes
+            }
+            Format::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+}
+
+/// Content negotiation for CBOR and MessagePack. Request bodies in either format are turned
+/// into JSON for the handlers, and JSON responses are encoded in the first of them listed in
+/// `Accept`. Everything else passes through untouched.
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let accept = accepted(request.headers());
+    let request = match content_format(request.headers()) {
+        Some(format) => match to_json_request(format, request).await {
+            Ok(request) => request,
+            Err(response) => return response,
+        },
+        None => request,
+    };
+    let response = next.run(request).await;
+    match accept {
+        Some(format) => encode_response(format, response).await,
+        None => response,
+    }
+}
+
+fn accepted(headers: &HeaderMap) -> Option<Format> {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|x| x.to_str().ok())?
+        .split(',')
+        .find_map(Format::from_mime)
+}
+
+fn content_format(headers: &HeaderMap) -> Option<Format> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(Format::from_mime)
+}
+
+async fn to_json_request(format: Format, request: Request) -> Result<Request, Response> {
+    let (mut parts, body) = request.into_parts();
+    let bad_request = |message: String| {
+        R::error_with_status(StatusCode::BAD_REQUEST, -1, message).into_response()
+    };
+    let bytes = to_bytes(body, *MAX_BODY_SIZE).await.map_err(|_| {
+        let message = format!("Request body exceeds {} bytes", *MAX_BODY_SIZE);
+        R::error_with_status(StatusCode::PAYLOAD_TOO_LARGE, -1, message).into_response()
+    })?;
+    let value = format
+        .decode(&bytes)
+        .map_err(|e| bad_request(format!("Invalid {}: {}", format.mime(), e)))?;
+    let json = serde_json::to_vec(&value).map_err(|e| bad_request(e.to_string()))?;
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::from(json)))
+}
+
+async fn encode_response(format: Format, response: Response) -> Response {
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|x| x.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let encoded = serde_json::from_slice::<Value>(&bytes)
+        .map_err(anyhow::Error::from)
+        .and_then(|x| format.encode(&x));
+    let Ok(encoded) = encoded else {
+        // Not valid JSON after all, leave it as it is.
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.mime()),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("Accept"));
+    Response::from_parts(parts, Body::from(encoded))
+}
@@ -0,0 +1,104 @@
+use axum::http::StatusCode;
+use axum::Json;
+use bitcoin::{Address, Script};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::envs::BITCOIN_NETWORK;
+use crate::structs::R;
+use crate::urn::transaction_from_hex;
+
+#[derive(Deserialize)]
+pub struct DecodeTx {
+    hex: String,
+    /// Values in satoshis of the outputs spent by the inputs, in input order, to compute the fee.
+    #[serde(default)]
+    prevouts: Vec<u64>,
+}
+
+/// `POST /decode/tx`, decode a raw transaction locally without asking ElectrumX. The fee is
+/// included when the values of all prevouts are given.
+pub async fn handle_decode_tx(Json(body): Json<DecodeTx>) -> R {
+    let tx = match transaction_from_hex(body.hex.trim()) {
+        Ok(tx) => tx,
+        Err(e) => {
+            let message = format!("Invalid transaction: {}", e);
+            return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+        }
+    };
+    let inputs = tx
+        .input
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            json!({
+                "txid": x.previous_output.txid.to_string(),
+                "vout": x.previous_output.vout,
+                "sequence": x.sequence.0,
+                "script_sig": x.script_sig.to_hex_string(),
+                "witness": x.witness.iter().map(hex::encode).collect::<Vec<_>>(),
+                "value": body.prevouts.get(i),
+            })
+        })
+        .collect::<Vec<_>>();
+    let outputs = tx
+        .output
+        .iter()
+        .map(|x| {
+            let address = Address::from_script(&x.script_pubkey, *BITCOIN_NETWORK).ok();
+            json!({
+                "value": x.value.to_sat(),
+                "script_pubkey": x.script_pubkey.to_hex_string(),
+                "script_pubkey_asm": x.script_pubkey.to_asm_string(),
+                "type": script_type(&x.script_pubkey),
+                "address": address.map(|x| x.to_string()),
+            })
+        })
+        .collect::<Vec<_>>();
+    let vsize = tx.vsize();
+    let fee = (body.prevouts.len() == tx.input.len()).then(|| {
+        let spent = body.prevouts.iter().sum::<u64>();
+        let sent = tx.output.iter().map(|x| x.value.to_sat()).sum::<u64>();
+        spent.checked_sub(sent)
+    });
+    let fee = match fee {
+        Some(None) => {
+            let message = "Outputs exceed the prevouts".to_string();
+            return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+        }
+        fee => fee.flatten(),
+    };
+    R::ok(json!({
+        "txid": tx.compute_txid().to_string(),
+        "wtxid": tx.compute_wtxid().to_string(),
+        "version": tx.version.0,
+        "locktime": tx.lock_time.to_consensus_u32(),
+        "size": tx.total_size(),
+        "vsize": vsize,
+        "weight": tx.weight().to_wu(),
+        "inputs": inputs,
+        "outputs": outputs,
+        "fee": fee,
+        "feerate": fee.map(|x| Value::from(x as f64 / vsize as f64)),
+    }))
+}
+
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "p2wpkh"
+    } else if script.is_p2wsh() {
+        "p2wsh"
+    } else if script.is_p2tr() {
+        "p2tr"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else {
+        "unknown"
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::structs::R;
+
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+pub const X_STALE: HeaderName = HeaderName::from_static("x-stale");
+pub const X_UPSTREAM: HeaderName = HeaderName::from_static("x-upstream");
+pub const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+/// How a call was served upstream, reported in the diagnostic headers.
+#[derive(Clone, Debug)]
+pub struct Timing {
+    /// Index of the instance that answered.
+    pub instance: u32,
+    /// JSON-RPC id of the call to that instance.
+    pub id: u64,
+    /// Time spent waiting for an instance with capacity.
+    pub queue: Duration,
+    /// Round trip to the upstream, including retries and hedged requests.
+    pub upstream: Duration,
+}
+
+/// Add `X-Cache`, `X-Stale`, `X-Upstream` and the `Server-Timing` of the upstream call to the
+/// headers of a response built from `r`.
+pub fn insert_diagnostics(r: &R, headers: &mut HeaderMap) {
+    if r.cache == Some(true) {
+        headers.insert(X_CACHE, HeaderValue::from_static("HIT"));
+    }
+    if r.staleness.is_some() {
+        headers.insert(X_STALE, HeaderValue::from_static("true"));
+    }
+    let Some(timing) = &r.timing else {
+        return;
+    };
+    headers.insert(X_CACHE, HeaderValue::from_static("MISS"));
+    headers.insert(X_UPSTREAM, HeaderValue::from(timing.instance));
+    let value = format!(
+        "queue;dur={:.1}, upstream;dur={:.1}",
+        millis(timing.queue),
+        millis(timing.upstream)
+    );
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.append(SERVER_TIMING, value);
+    }
+}
+
+/// Append the total time spent on a request to its `Server-Timing`.
+pub async fn server_timing(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let mut response = next.run(request).await;
+    let value = format!("total;dur={:.1}", millis(started.elapsed()));
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response.headers_mut().append(SERVER_TIMING, value);
+    }
+    response
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -1,7 +1,28 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::sync::LazyLock;
 
+use bitcoin::Network;
+use ipnet::IpNet;
+use url::Url;
+
+use crate::acl::parse_net;
+use crate::structs::Params;
+use crate::upstream::{parse_endpoints, parse_socks5, Endpoint};
+
+/// The process environment before the `.env` file is loaded into it, forced at startup.
+pub static PROCESS_ENV: LazyLock<HashMap<String, String>> = LazyLock::new(|| env::vars().collect());
+
+/// Read the current value of a variable for a runtime reload with the precedence of startup:
+/// the process environment first, then the `.env` file as it is now.
+pub fn read_env(key: &str) -> Option<String> {
+    PROCESS_ENV.get(key).cloned().or_else(|| {
+        dotenvy::dotenv_iter()
+            .ok()
+            .and_then(|iter| iter.flatten().find(|(k, _)| k == key).map(|(_, v)| v))
+    })
+}
+
 pub static IP_LIMIT_PER_MILLS: LazyLock<u64> = LazyLock::new(|| {
     let per_second: u64 = env::var("IP_LIMIT_PER_SECOND")
         .unwrap_or("0".to_string())
@@ -24,6 +45,87 @@ pub static IP_LIMIT_BURST_SIZE: LazyLock<u32> = LazyLock::new(|| {
         .unwrap()
 });
 
+/// Expect a PROXY protocol header, v1 or v2, at the start of every connection, as sent by
+/// HAProxy or a network load balancer in TCP mode.
+pub static PROXY_PROTOCOL: LazyLock<bool> = LazyLock::new(|| {
+    env::var("PROXY_PROTOCOL")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Peers whose forwarding headers are trusted to tell the client IP, CIDR ranges separated by
+/// commas. Loopback and private networks by default.
+pub static TRUSTED_PROXIES: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or(
+            "127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,::1/128,fc00::/7".to_string(),
+        )
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_net(s).unwrap())
+        .collect()
+});
+
+/// Only clients in these CIDR ranges are served when set, separated by commas.
+pub static IP_ALLOWLIST: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    env::var("IP_ALLOWLIST")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_net(s).unwrap())
+        .collect()
+});
+
+/// Clients in these CIDR ranges are refused, separated by commas.
+pub static IP_DENYLIST: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    env::var("IP_DENYLIST")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_net(s).unwrap())
+        .collect()
+});
+
+/// Keys with a rate limit of their own, `key:per_mills:burst` separated by commas. A key is
+/// sent in `X-API-Key` or the `api_key` query param.
+pub static API_KEYS: LazyLock<Vec<(String, u64, u32)>> = LazyLock::new(|| {
+    env::var("API_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.split(':').collect::<Vec<_>>()[..] {
+            [key, per_mills, burst] => (
+                key.to_string(),
+                per_mills.parse().unwrap(),
+                burst.parse().unwrap(),
+            ),
+            _ => panic!("Invalid API_KEYS, expected key:per_mills:burst entries"),
+        })
+        .collect()
+});
+
+/// Secret of HS256 tokens, requiring a bearer token on the upstream routes when set.
+pub static JWT_SECRET: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("JWT_SECRET").ok().filter(|x| !x.is_empty()));
+
+/// PEM file of the public key of RS256 tokens, requiring a bearer token on the upstream
+/// routes when set.
+pub static JWT_PUBLIC_KEY: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("JWT_PUBLIC_KEY").ok().filter(|x| !x.is_empty()));
+
+/// Issuer tokens must come from, any when unset.
+pub static JWT_ISSUER: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("JWT_ISSUER").ok().filter(|x| !x.is_empty()));
+
+/// Audience tokens must be meant for, not checked when unset.
+pub static JWT_AUDIENCE: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("JWT_AUDIENCE").ok().filter(|x| !x.is_empty()));
+
 pub static CONCURRENCY_LIMIT: LazyLock<usize> = LazyLock::new(|| {
     env::var("CONCURRENCY_LIMIT")
         .unwrap_or("500".to_string())
@@ -31,9 +133,13 @@ pub static CONCURRENCY_LIMIT: LazyLock<usize> = LazyLock::new(|| {
         .unwrap()
 });
 
-pub static ELECTRUMX_WSS: LazyLock<String> = LazyLock::new(|| {
-    env::var("ELECTRUMX_WSS").unwrap_or("wss://electrumx.atomicals.xyz:50012".to_string())
-});
+pub const DEFAULT_ELECTRUMX_WSS: &str = "wss://electrumx.atomicals.xyz:50012";
+
+pub static ELECTRUMX_WSS: LazyLock<String> =
+    LazyLock::new(|| env::var("ELECTRUMX_WSS").unwrap_or(DEFAULT_ELECTRUMX_WSS.to_string()));
+
+pub static ELECTRUMX_ENDPOINTS: LazyLock<Vec<Endpoint>> =
+    LazyLock::new(|| parse_endpoints(&ELECTRUMX_WSS).unwrap());
 
 pub static ELECTRUMX_WS_INSTANCE: LazyLock<u32> = LazyLock::new(|| {
     env::var("ELECTRUMX_WS_INSTANCE")
@@ -59,6 +165,23 @@ pub static MAX_CACHE_ENTRIES: LazyLock<u64> = LazyLock::new(|| {
         .unwrap()
 });
 
+/// Bound of the tier of results that never change, which have no time to live.
+pub static MAX_IMMUTABLE_CACHE_ENTRIES: LazyLock<u64> = LazyLock::new(|| {
+    env::var("MAX_IMMUTABLE_CACHE_ENTRIES")
+        .unwrap_or("10000".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Bound of the cache in memory in bytes of serialized results, 0 to bound it by
+/// `MAX_CACHE_ENTRIES` instead.
+pub static MAX_CACHE_BYTES: LazyLock<u64> = LazyLock::new(|| {
+    env::var("MAX_CACHE_BYTES")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
 pub static CACHE_TIME_TO_LIVE: LazyLock<u64> = LazyLock::new(|| {
     env::var("CACHE_TIME_TO_LIVE")
         .unwrap_or("600".to_string())
@@ -73,10 +196,552 @@ pub static CACHE_TIME_TO_IDLE: LazyLock<u64> = LazyLock::new(|| {
         .unwrap()
 });
 
-pub static NO_CACHE_METHODS: LazyLock<HashSet<String>> = LazyLock::new(|| {
+pub static NO_CACHE_METHODS: LazyLock<Vec<String>> = LazyLock::new(|| {
     env::var("NO_CACHE_METHODS")
         .unwrap_or("blockchain.atomicals.get_global,blockchain.estimatefee,blockchain.scripthash.subscribe,blockchain.transaction.broadcast,server.peers.subscribe,server.ping,mempool.get_fee_histogram,blockchain.atomicals.dump,blockchain.scripthash.unsubscribe,blockchain.relayfee".to_string())
         .split(',')
         .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+});
+
+pub static ELECTRUMX_ACCEPT_INVALID_CERTS: LazyLock<bool> = LazyLock::new(|| {
+    env::var("ELECTRUMX_ACCEPT_INVALID_CERTS")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static CIRCUIT_BREAKER_WINDOW: LazyLock<usize> = LazyLock::new(|| {
+    env::var("CIRCUIT_BREAKER_WINDOW")
+        .unwrap_or("20".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static CIRCUIT_BREAKER_ERROR_RATE: LazyLock<usize> = LazyLock::new(|| {
+    env::var("CIRCUIT_BREAKER_ERROR_RATE")
+        .unwrap_or("50".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static CIRCUIT_BREAKER_TIMEOUTS: LazyLock<u32> = LazyLock::new(|| {
+    env::var("CIRCUIT_BREAKER_TIMEOUTS")
+        .unwrap_or("3".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static CIRCUIT_BREAKER_OPEN_SECS: LazyLock<u64> = LazyLock::new(|| {
+    env::var("CIRCUIT_BREAKER_OPEN_SECS")
+        .unwrap_or("30".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_MAX_LAG: LazyLock<u64> = LazyLock::new(|| {
+    env::var("UPSTREAM_MAX_LAG")
+        .unwrap_or("2".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_FAILOVER: LazyLock<bool> = LazyLock::new(|| {
+    env::var("UPSTREAM_FAILOVER")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_FAILBACK_INTERVAL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("UPSTREAM_FAILBACK_INTERVAL")
+        .unwrap_or("30".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_CONNECT_STAGGER_MS: LazyLock<u64> = LazyLock::new(|| {
+    env::var("UPSTREAM_CONNECT_STAGGER_MS")
+        .unwrap_or("250".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_PING_INTERVAL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("UPSTREAM_PING_INTERVAL")
+        .unwrap_or("30".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static ELECTRUMX_CLIENT_NAME: LazyLock<String> = LazyLock::new(|| {
+    env::var("ELECTRUMX_CLIENT_NAME").unwrap_or(format!("elex-proxy/{}", env!("CARGO_PKG_VERSION")))
+});
+
+pub static ELECTRUMX_PROTOCOL_VERSION: LazyLock<String> =
+    LazyLock::new(|| env::var("ELECTRUMX_PROTOCOL_VERSION").unwrap_or("1.4".to_string()));
+
+pub static ELECTRUMX_SOCKS5: LazyLock<Option<Url>> =
+    LazyLock::new(|| parse_socks5(&env::var("ELECTRUMX_SOCKS5").unwrap_or_default()).unwrap());
+
+pub static UPSTREAM_POOL_MIN: LazyLock<u32> = LazyLock::new(|| {
+    env::var("UPSTREAM_POOL_MIN")
+        .unwrap_or("1".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_POOL_MAX: LazyLock<u32> = LazyLock::new(|| {
+    env::var("UPSTREAM_POOL_MAX")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_POOL_TARGET_IN_FLIGHT: LazyLock<usize> = LazyLock::new(|| {
+    env::var("UPSTREAM_POOL_TARGET_IN_FLIGHT")
+        .unwrap_or("16".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_MAX_IN_FLIGHT: LazyLock<usize> = LazyLock::new(|| {
+    env::var("UPSTREAM_MAX_IN_FLIGHT")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_CAPACITY_WAIT_MS: LazyLock<u64> = LazyLock::new(|| {
+    env::var("UPSTREAM_CAPACITY_WAIT_MS")
+        .unwrap_or("200".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static UPSTREAM_QUEUE_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    env::var("UPSTREAM_QUEUE_SIZE")
+        .unwrap_or("1000".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Warn when the queue or the pending callbacks of a ws instance grow beyond this many
+/// requests, 0 to disable.
+pub static UPSTREAM_BACKLOG_WARN: LazyLock<usize> = LazyLock::new(|| {
+    env::var("UPSTREAM_BACKLOG_WARN")
+        .unwrap_or("500".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Number of upstream connection events kept for `/admin/upstreams/events`.
+pub static UPSTREAM_EVENT_LOG_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    env::var("UPSTREAM_EVENT_LOG_SIZE")
+        .unwrap_or("100".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static WS_CLIENT_MAX_IN_FLIGHT: LazyLock<usize> = LazyLock::new(|| {
+    env::var("WS_CLIENT_MAX_IN_FLIGHT")
+        .unwrap_or("32".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Messages waiting to be written to one `/ws` client before it is disconnected as too slow.
+pub static WS_CLIENT_QUEUE_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    env::var("WS_CLIENT_QUEUE_SIZE")
+        .unwrap_or("256".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static MAX_BATCH_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    env::var("MAX_BATCH_SIZE")
+        .unwrap_or("50".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static BROADCAST_TO_ALL: LazyLock<bool> = LazyLock::new(|| {
+    env::var("BROADCAST_TO_ALL")
+        .unwrap_or("true".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static FEE_AGGREGATION: LazyLock<bool> = LazyLock::new(|| {
+    env::var("FEE_AGGREGATION")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Calls taking at least this many milliseconds are logged as slow, 0 to disable.
+pub static SLOW_REQUEST_MS: LazyLock<u64> = LazyLock::new(|| {
+    env::var("SLOW_REQUEST_MS")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static HEDGE_DELAY_MS: LazyLock<u64> = LazyLock::new(|| {
+    env::var("HEDGE_DELAY_MS")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static PEER_DISCOVERY: LazyLock<bool> = LazyLock::new(|| {
+    env::var("PEER_DISCOVERY")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static PEER_DISCOVERY_MAX: LazyLock<usize> = LazyLock::new(|| {
+    env::var("PEER_DISCOVERY_MAX")
+        .unwrap_or("5".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static PEER_DISCOVERY_INTERVAL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("PEER_DISCOVERY_INTERVAL")
+        .unwrap_or("600".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static PEER_DISCOVERY_MIN_PROTOCOL: LazyLock<String> = LazyLock::new(|| {
+    env::var("PEER_DISCOVERY_MIN_PROTOCOL").unwrap_or(ELECTRUMX_PROTOCOL_VERSION.clone())
+});
+
+pub static PEER_DISCOVERY_SSL_ONLY: LazyLock<bool> = LazyLock::new(|| {
+    env::var("PEER_DISCOVERY_SSL_ONLY")
+        .unwrap_or("true".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static ADMIN_TOKEN: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("ADMIN_TOKEN").ok().filter(|x| !x.is_empty()));
+
+pub static THUMBNAIL_MAX_SIZE: LazyLock<u32> = LazyLock::new(|| {
+    env::var("THUMBNAIL_MAX_SIZE")
+        .unwrap_or("1024".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static THUMBNAIL_CACHE_ENTRIES: LazyLock<u64> = LazyLock::new(|| {
+    env::var("THUMBNAIL_CACHE_ENTRIES")
+        .unwrap_or("1000".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static THUMBNAIL_CACHE_TIME_TO_LIVE: LazyLock<u64> = LazyLock::new(|| {
+    env::var("THUMBNAIL_CACHE_TIME_TO_LIVE")
+        .unwrap_or("86400".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Bound in bytes of the decoded `/urn` payloads of `atom:btc:dat` URNs kept in memory.
+pub static URN_CACHE_BYTES: LazyLock<u64> = LazyLock::new(|| {
+    env::var("URN_CACHE_BYTES")
+        .unwrap_or("67108864".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static URN_CACHE_TIME_TO_LIVE: LazyLock<u64> = LazyLock::new(|| {
+    env::var("URN_CACHE_TIME_TO_LIVE")
+        .unwrap_or("604800".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static BITCOIN_NETWORK: LazyLock<Network> = LazyLock::new(|| {
+    env::var("BITCOIN_NETWORK")
+        .unwrap_or("bitcoin".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static COMPRESSION_MIN_SIZE: LazyLock<u16> = LazyLock::new(|| {
+    env::var("COMPRESSION_MIN_SIZE")
+        .unwrap_or("1024".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static COMPRESSION_ALGORITHMS: LazyLock<HashSet<String>> = LazyLock::new(|| {
+    env::var("COMPRESSION_ALGORITHMS")
+        .unwrap_or("gzip,br".to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+});
+
+pub static MAX_BODY_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    env::var("MAX_BODY_SIZE")
+        .unwrap_or("2097152".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static ALLOWED_METHODS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    env::var("ALLOWED_METHODS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+});
+
+pub static BLOCKED_METHODS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    env::var("BLOCKED_METHODS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+});
+
+pub static HTTP_ERROR_STATUS: LazyLock<bool> = LazyLock::new(|| {
+    env::var("HTTP_ERROR_STATUS")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static HEALTH_CHECK_METHOD: LazyLock<String> = LazyLock::new(|| {
+    env::var("HEALTH_CHECK_METHOD").unwrap_or("blockchain.atomicals.get_global".to_string())
+});
+
+pub static HEALTH_CHECK_PARAMS: LazyLock<Params> = LazyLock::new(|| {
+    let params = env::var("HEALTH_CHECK_PARAMS").unwrap_or_default();
+    if params.trim().is_empty() {
+        return vec![].into();
+    }
+    serde_json::from_str(&params).unwrap()
+});
+
+pub static HEALTH_CHECK_FIELDS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    env::var("HEALTH_CHECK_FIELDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
         .collect()
 });
+
+pub static HEALTH_CHECK_TIMEOUT: LazyLock<u64> = LazyLock::new(|| {
+    env::var("HEALTH_CHECK_TIMEOUT")
+        .unwrap_or("5".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Seconds between logged summaries of upstream latencies per method, 0 to disable.
+pub static LATENCY_LOG_INTERVAL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("LATENCY_LOG_INTERVAL")
+        .unwrap_or("300".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static READ_ONLY: LazyLock<bool> = LazyLock::new(|| {
+    env::var("READ_ONLY")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static READ_ONLY_MESSAGE: LazyLock<String> = LazyLock::new(|| {
+    env::var("READ_ONLY_MESSAGE")
+        .unwrap_or("Proxy is in read-only mode, broadcasting is paused".to_string())
+});
+
+/// File every `blockchain.transaction.broadcast` attempt is appended to, one JSON object per
+/// line.
+pub static BROADCAST_AUDIT_LOG: LazyLock<Option<String>> = LazyLock::new(|| {
+    env::var("BROADCAST_AUDIT_LOG")
+        .ok()
+        .filter(|x| !x.is_empty())
+});
+
+pub static DISABLE_BROADCAST: LazyLock<bool> = LazyLock::new(|| {
+    env::var("DISABLE_BROADCAST")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static PROXY_INFO_FILE: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("PROXY_INFO_FILE").ok().filter(|x| !x.is_empty()));
+
+/// `method=seconds` pairs separated by commas, e.g.
+/// `blockchain.atomicals.get_global=5,blockchain.estimatefee=30`.
+pub static CACHE_TTL_OVERRIDES: LazyLock<HashMap<String, u64>> = LazyLock::new(|| {
+    env::var("CACHE_TTL_OVERRIDES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let (method, ttl) = s.split_once('=').unwrap();
+            (method.trim().to_string(), ttl.trim().parse().unwrap())
+        })
+        .collect()
+});
+
+pub static NEGATIVE_CACHE_TTL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("NEGATIVE_CACHE_TTL")
+        .unwrap_or("10".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Seconds results stay cached past their time to live, served stale while refreshed in the
+/// background.
+pub static CACHE_STALE_GRACE: LazyLock<u64> = LazyLock::new(|| {
+    env::var("CACHE_STALE_GRACE")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Seconds results stay cached past their time to live, served stale while no upstream is
+/// connected.
+pub static CACHE_STALE_IF_ERROR: LazyLock<u64> = LazyLock::new(|| {
+    env::var("CACHE_STALE_IF_ERROR")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Who may skip the cache with `X-No-Cache: 1`: `admin` callers holding `ADMIN_TOKEN`, `all`
+/// or `none`.
+pub static CACHE_BYPASS: LazyLock<String> =
+    LazyLock::new(|| env::var("CACHE_BYPASS").unwrap_or("admin".to_string()));
+
+pub static CACHE_BACKEND: LazyLock<String> =
+    LazyLock::new(|| env::var("CACHE_BACKEND").unwrap_or("moka".to_string()));
+
+/// Share new blocks with the other replicas through Redis pub/sub on `REDIS_URL`.
+pub static CACHE_INVALIDATION_BUS: LazyLock<bool> = LazyLock::new(|| {
+    env::var("CACHE_INVALIDATION_BUS")
+        .unwrap_or("false".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static REDIS_URL: LazyLock<String> =
+    LazyLock::new(|| env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string()));
+
+pub static REDIS_KEY_PREFIX: LazyLock<String> =
+    LazyLock::new(|| env::var("REDIS_KEY_PREFIX").unwrap_or("elex-proxy:".to_string()));
+
+/// Seconds immutable results are kept in Redis, refreshed whenever one is cached again.
+pub static REDIS_IMMUTABLE_TTL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("REDIS_IMMUTABLE_TTL")
+        .unwrap_or("2592000".to_string())
+        .parse()
+        .unwrap()
+});
+
+pub static DISK_CACHE_PATH: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("DISK_CACHE_PATH").ok().filter(|x| !x.is_empty()));
+
+/// `text` for human readable log lines or `json` for one object per line with the fields of
+/// each event.
+pub static LOG_FORMAT: LazyLock<String> =
+    LazyLock::new(|| env::var("LOG_FORMAT").unwrap_or("text".to_string()));
+
+/// Which log lines are written, `info` by default. Takes a level or directives such as
+/// `info,elex_proxy::upstream=debug`, see `/admin/log-level` to change it at runtime.
+pub static RUST_LOG: LazyLock<String> =
+    LazyLock::new(|| env::var("RUST_LOG").unwrap_or("info".to_string()));
+
+/// Webhook receiving a POST when no upstream is connected any more and again once one is back.
+pub static ALERT_WEBHOOK_URL: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("ALERT_WEBHOOK_URL").ok().filter(|x| !x.is_empty()));
+
+/// Pinged with a GET while an upstream is healthy, for dead man's switch monitors.
+pub static HEARTBEAT_URL: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("HEARTBEAT_URL").ok().filter(|x| !x.is_empty()));
+
+/// Seconds between heartbeats.
+pub static HEARTBEAT_INTERVAL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("HEARTBEAT_INTERVAL")
+        .unwrap_or("60".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Which replica sends the alerts, the host name by default.
+pub static ALERT_SOURCE: LazyLock<String> = LazyLock::new(|| {
+    env::var("ALERT_SOURCE")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or("elex-proxy".to_string())
+});
+
+/// Push the metrics of `/metrics` to `STATSD_HOST` as well: `none`, `statsd`, or `dogstatsd`
+/// with labels as tags.
+pub static METRICS_PUSH: LazyLock<String> =
+    LazyLock::new(|| env::var("METRICS_PUSH").unwrap_or("none".to_string()));
+
+pub static STATSD_HOST: LazyLock<String> =
+    LazyLock::new(|| env::var("STATSD_HOST").unwrap_or("127.0.0.1:8125".to_string()));
+
+pub static STATSD_PREFIX: LazyLock<String> =
+    LazyLock::new(|| env::var("STATSD_PREFIX").unwrap_or("elex_proxy".to_string()));
+
+pub static STATSD_INTERVAL: LazyLock<u64> = LazyLock::new(|| {
+    env::var("STATSD_INTERVAL")
+        .unwrap_or("10".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Directory of the access log, one line per request, disabled when unset.
+pub static ACCESS_LOG_DIR: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("ACCESS_LOG_DIR").ok().filter(|x| !x.is_empty()));
+
+/// When to start a new access log file: `minutely`, `hourly`, `daily`, `weekly` or `never`.
+pub static ACCESS_LOG_ROTATION: LazyLock<String> =
+    LazyLock::new(|| env::var("ACCESS_LOG_ROTATION").unwrap_or("daily".to_string()));
+
+/// Number of access log files kept, the oldest are deleted on rotation. 0 keeps them all.
+pub static ACCESS_LOG_MAX_FILES: LazyLock<usize> = LazyLock::new(|| {
+    env::var("ACCESS_LOG_MAX_FILES")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// Size in bytes at which the access log file is rotated on top of `ACCESS_LOG_ROTATION`. 0
+/// rotates by time only.
+pub static ACCESS_LOG_MAX_SIZE: LazyLock<u64> = LazyLock::new(|| {
+    env::var("ACCESS_LOG_MAX_SIZE")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap()
+});
+
+/// OTLP gRPC collector to export request traces to, tracing is off when unset.
+pub static OTEL_EXPORTER_OTLP_ENDPOINT: LazyLock<Option<String>> = LazyLock::new(|| {
+    env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|x| !x.is_empty())
+});
+
+pub static OTEL_SERVICE_NAME: LazyLock<String> =
+    LazyLock::new(|| env::var("OTEL_SERVICE_NAME").unwrap_or("elex-proxy".to_string()));
@@ -0,0 +1,303 @@
+use axum::extract::{Extension, Path};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::{json, Value};
+
+use crate::address::to_scripthash;
+use crate::handle_request;
+use crate::structs::{ResultCache, R};
+use crate::upstream::{consensus_height, Instance, Upstreams};
+
+/// `GET /api/tx/:txid` of Esplora, built from the verbose `blockchain.transaction.get`.
+/// Prevouts and fees are not known to ElectrumX and left out.
+pub async fn handle_tx(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(txid): Path<String>,
+) -> Response {
+    let instances = upstreams.snapshot();
+    let method = "blockchain.transaction.get";
+    let params = vec![json!(txid), json!(true)];
+    match call(&cache, &instances, &headers, method, params).await {
+        Ok(tx) => Json(to_tx(&tx, consensus_height(&instances))).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// `GET /api/tx/:txid/hex`, the raw transaction.
+pub async fn handle_tx_hex(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(txid): Path<String>,
+) -> Response {
+    let instances = upstreams.snapshot();
+    let method = "blockchain.transaction.get";
+    match call(&cache, &instances, &headers, method, vec![json!(txid)]).await {
+        Ok(Value::String(hex)) => hex.into_response(),
+        Ok(_) => (StatusCode::BAD_GATEWAY, "Unexpected response").into_response(),
+        Err(response) => response,
+    }
+}
+
+/// `GET /api/address/:address`. ElectrumX only knows balances, so the funded sums are the
+/// balances and nothing counts as spent, which keeps `funded - spent` right.
+pub async fn handle_address(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+) -> Response {
+    let scripthash = match to_scripthash(&address) {
+        Ok(scripthash) => scripthash,
+        Err(e) => return invalid_address(e),
+    };
+    let instances = upstreams.snapshot();
+    let params = vec![json!(scripthash)];
+    let query = |method| call(&cache, &instances, &headers, method, params.clone());
+    let (balance, history, unspent) = tokio::join!(
+        query("blockchain.scripthash.get_balance"),
+        query("blockchain.scripthash.get_history"),
+        query("blockchain.scripthash.listunspent"),
+    );
+    let (balance, history, unspent) = match (balance, history, unspent) {
+        (Ok(balance), Ok(history), Ok(unspent)) => (balance, history, unspent),
+        (Err(response), _, _) | (_, Err(response), _) | (_, _, Err(response)) => return response,
+    };
+    let history = history.as_array().cloned().unwrap_or_default();
+    let unspent = unspent.as_array().cloned().unwrap_or_default();
+    let confirmed = |x: &Value| x.get("height").and_then(|x| x.as_i64()).unwrap_or_default() > 0;
+    let stats = |in_chain: bool, sum: &str| {
+        json!({
+            "funded_txo_count": unspent.iter().filter(|x| confirmed(x) == in_chain).count(),
+            "funded_txo_sum": balance.get(sum).and_then(|x| x.as_i64()).unwrap_or_default(),
+            "spent_txo_count": 0,
+            "spent_txo_sum": 0,
+            "tx_count": history.iter().filter(|x| confirmed(x) == in_chain).count(),
+        })
+    };
+    Json(json!({
+        "address": address,
+        "chain_stats": stats(true, "confirmed"),
+        "mempool_stats": stats(false, "unconfirmed"),
+    }))
+    .into_response()
+}
+
+/// `GET /api/address/:address/utxo`.
+pub async fn handle_address_utxo(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+) -> Response {
+    let scripthash = match to_scripthash(&address) {
+        Ok(scripthash) => scripthash,
+        Err(e) => return invalid_address(e),
+    };
+    let instances = upstreams.snapshot();
+    let method = "blockchain.scripthash.listunspent";
+    let params = vec![json!(scripthash)];
+    let unspent = match call(&cache, &instances, &headers, method, params).await {
+        Ok(unspent) => unspent,
+        Err(response) => return response,
+    };
+    let utxos = unspent
+        .as_array()
+        .map(|x| {
+            x.iter()
+                .map(|x| {
+                    let height = x.get("height").and_then(|x| x.as_u64()).unwrap_or_default();
+                    json!({
+                        "txid": x.get("tx_hash"),
+                        "vout": x.get("tx_pos"),
+                        "status": {
+                            "confirmed": height > 0,
+                            "block_height": (height > 0).then_some(height),
+                        },
+                        "value": x.get("value"),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    Json(utxos).into_response()
+}
+
+/// `GET /api/blocks/tip/height`, the tip most upstreams agree on.
+pub async fn handle_tip_height(Extension(upstreams): Extension<Upstreams>) -> Response {
+    match consensus_height(&upstreams.snapshot()) {
+        0 => (StatusCode::SERVICE_UNAVAILABLE, "No upstream available").into_response(),
+        height => height.to_string().into_response(),
+    }
+}
+
+fn invalid_address(e: anyhow::Error) -> Response {
+    let message = format!("Invalid address: {}", e);
+    (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+/// Run a call through the cache, errors are answered in plain text like Esplora does.
+async fn call(
+    cache: &ResultCache,
+    instances: &[Instance],
+    headers: &HeaderMap,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value, Response> {
+    let r = handle_request(
+        cache.clone(),
+        instances,
+        headers.clone(),
+        method.into(),
+        params,
+    )
+    .await;
+    match r {
+        R {
+            response: Some(response),
+            ..
+        } => Ok(response),
+        r => {
+            // Errors of ElectrumX are about the request, the proxy uses -1 for its own.
+            let status = match r.code.as_ref().and_then(|x| x.as_i64()) {
+                Some(-1) => r.status.unwrap_or(StatusCode::BAD_GATEWAY),
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let message = match r.message {
+                Some(Value::String(message)) => message,
+                Some(message) => message.to_string(),
+                None => "Unknown error".into(),
+            };
+            Err((status, message).into_response())
+        }
+    }
+}
+
+/// Map a verbose transaction of bitcoind to the transaction format of Esplora.
+fn to_tx(tx: &Value, tip: u64) -> Value {
+    let vin = tx
+        .get("vin")
+        .and_then(|x| x.as_array())
+        .map(|x| {
+            x.iter()
+                .map(|x| {
+                    json!({
+                        "txid": x.get("txid"),
+                        "vout": x.get("vout"),
+                        "prevout": null,
+                        "scriptsig": x.pointer("/scriptSig/hex"),
+                        "scriptsig_asm": x.pointer("/scriptSig/asm"),
+                        "witness": x.get("txinwitness"),
+                        "is_coinbase": x.get("coinbase").is_some(),
+                        "sequence": x.get("sequence"),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let vout = tx
+        .get("vout")
+        .and_then(|x| x.as_array())
+        .map(|x| {
+            x.iter()
+                .map(|x| {
+                    let script_type = x.pointer("/scriptPubKey/type").and_then(|x| x.as_str());
+                    let btc = x.get("value").and_then(|x| x.as_f64()).unwrap_or_default();
+                    json!({
+                        "scriptpubkey": x.pointer("/scriptPubKey/hex"),
+                        "scriptpubkey_asm": x.pointer("/scriptPubKey/asm"),
+                        "scriptpubkey_type": script_type.map(to_script_type),
+                        "scriptpubkey_address": x.pointer("/scriptPubKey/address"),
+                        "value": (btc * 100_000_000.0).round() as u64,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let confirmations = tx
+        .get("confirmations")
+        .and_then(|x| x.as_u64())
+        .unwrap_or_default();
+    let block_height = (confirmations > 0 && tip > 0).then(|| tip + 1 - confirmations);
+    json!({
+        "txid": tx.get("txid"),
+        "version": tx.get("version"),
+        "locktime": tx.get("locktime"),
+        "vin": vin,
+        "vout": vout,
+        "size": tx.get("size"),
+        "weight": tx.get("weight"),
+        "status": {
+            "confirmed": confirmations > 0,
+            "block_height": block_height,
+            "block_hash": tx.get("blockhash"),
+            "block_time": tx.get("blocktime"),
+        },
+    })
+}
+
+/// Script types of bitcoind under their Esplora names.
+fn to_script_type(script_type: &str) -> &str {
+    match script_type {
+        "pubkey" => "p2pk",
+        "pubkeyhash" => "p2pkh",
+        "scripthash" => "p2sh",
+        "witness_v0_keyhash" => "v0_p2wpkh",
+        "witness_v0_scripthash" => "v0_p2wsh",
+        "witness_v1_taproot" => "v1_p2tr",
+        "nulldata" => "op_return",
+        "witness_unknown" => "unknown",
+        other => other,
+    }
+}
+
+/// `GET /api/v1/fees/recommended` of mempool.space in sat/vB, from `estimatefee` for 1, 3, 6
+/// and 144 blocks and `relayfee`. Targets without an estimate fall back to the next slower
+/// one, and no target is cheaper than a slower one.
+pub async fn handle_fees_recommended(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+) -> Response {
+    let instances = upstreams.snapshot();
+    let estimate = |method, params| {
+        let call = call(&cache, &instances, &headers, method, params);
+        // BTC/kvB to sat/vB, -1 means no estimate.
+        async move {
+            call.await
+                .ok()?
+                .as_f64()
+                .filter(|x| *x > 0.0)
+                .map(|x| x * 100_000.0)
+        }
+    };
+    let (fastest, half_hour, hour, economy, minimum) = tokio::join!(
+        estimate("blockchain.estimatefee", vec![json!(1)]),
+        estimate("blockchain.estimatefee", vec![json!(3)]),
+        estimate("blockchain.estimatefee", vec![json!(6)]),
+        estimate("blockchain.estimatefee", vec![json!(144)]),
+        estimate("blockchain.relayfee", vec![]),
+    );
+    if [fastest, half_hour, hour, economy, minimum]
+        .iter()
+        .all(Option::is_none)
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "No fee estimate available").into_response();
+    }
+    let minimum = minimum.unwrap_or(1.0).ceil().max(1.0);
+    let economy = economy.unwrap_or(minimum).ceil().max(minimum);
+    let hour = hour.unwrap_or(economy).ceil().max(economy);
+    let half_hour = half_hour.unwrap_or(hour).ceil().max(hour);
+    let fastest = fastest.unwrap_or(half_hour).ceil().max(half_hour);
+    Json(json!({
+        "fastestFee": fastest as u64,
+        "halfHourFee": half_hour as u64,
+        "hourFee": hour as u64,
+        "economyFee": economy as u64,
+        "minimumFee": minimum as u64,
+    }))
+    .into_response()
+}
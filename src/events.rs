@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+/// New block headers, `{height, hex}` as announced by `blockchain.headers.subscribe`.
+static HEADERS: LazyLock<broadcast::Sender<Value>> = LazyLock::new(|| broadcast::channel(16).0);
+
+/// Atomicals global state, the result of `blockchain.atomicals.get_global` at each new height.
+static GLOBAL: LazyLock<broadcast::Sender<Value>> = LazyLock::new(|| broadcast::channel(16).0);
+
+/// Height and hex of the last published header.
+static LAST_HEADER: Mutex<(u64, String)> = Mutex::new((0, String::new()));
+
+/// Publish a header announced by an upstream. Every upstream announces each block, only the
+/// first announcement of a new tip, or of a replaced one after a reorg, goes out.
+pub fn publish_header(header: &Value) {
+    let Some(height) = header.get("height").and_then(|x| x.as_u64()) else {
+        return;
+    };
+    let hex = header
+        .get("hex")
+        .and_then(|x| x.as_str())
+        .unwrap_or_default();
+    {
+        let mut last = LAST_HEADER.lock().unwrap();
+        if height < last.0 || (height == last.0 && hex == last.1) {
+            return;
+        }
+        *last = (height, hex.to_string());
+    }
+    let _ = HEADERS.send(header.clone());
+}
+
+pub fn subscribe_headers() -> broadcast::Receiver<Value> {
+    HEADERS.subscribe()
+}
+
+/// Height of the last published global state.
+static LAST_GLOBAL_HEIGHT: AtomicU64 = AtomicU64::new(0);
+
+/// Publish the Atomicals global state polled by the `get_global` loop, only the first one
+/// seen at a new height goes out.
+pub fn publish_global(global: &Value) {
+    let height = global
+        .get("global")
+        .and_then(|x| x.get("height"))
+        .and_then(|x| x.as_u64());
+    let Some(height) = height else {
+        return;
+    };
+    if LAST_GLOBAL_HEIGHT.fetch_max(height, Ordering::SeqCst) < height {
+        let _ = GLOBAL.send(global.clone());
+    }
+}
+
+pub fn subscribe_global() -> broadcast::Receiver<Value> {
+    GLOBAL.subscribe()
+}
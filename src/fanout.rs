@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::envs::RESPONSE_TIMEOUT;
+use crate::structs::{JsonRpcResponse, R};
+use crate::unavailable;
+use crate::upstream::{is_upstream_failure, Instance};
+
+pub const BROADCAST_METHOD: &str = "blockchain.transaction.broadcast";
+
+/// Send the request to every available instance at once. Responses are delivered as they
+/// arrive, with `None` for instances that did not answer within `RESPONSE_TIMEOUT`; the
+/// channel closes once every instance is done.
+///
+/// Each request runs in its own task, so dropping the receiver early does not leave
+/// callbacks behind.
+pub fn fan_out(
+    instances: &[Instance],
+    method: &str,
+    params: &[Value],
+) -> mpsc::UnboundedReceiver<(u32, Option<JsonRpcResponse>)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    for instance in instances.iter().filter(|x| x.is_available()) {
+        let instance = instance.clone();
+        let (tx, method, params) = (tx.clone(), method.to_string(), params.to_vec());
+        tokio::spawn(async move {
+            let _in_flight = instance.track();
+            let Ok((id, response_rx)) = instance.call(method, params.into()).await else {
+                let _ = tx.send((instance.index, None));
+                return;
+            };
+            let _probe = instance.dispatched();
+            let timeout = Duration::from_secs(*RESPONSE_TIMEOUT);
+            let response = match tokio::time::timeout(timeout, response_rx).await {
+                Ok(Ok(rep)) => {
+                    let upstream_failure = rep.error.as_ref().is_some_and(is_upstream_failure);
+                    instance.record_outcome(rep.result.is_some() || !upstream_failure, false);
+                    Some(rep)
+                }
+                Ok(Err(_)) | Err(_) => {
+                    instance.record_outcome(false, true);
+                    instance.callbacks.write().await.remove(&id);
+                    None
+                }
+            };
+            let _ = tx.send((instance.index, response));
+        });
+    }
+    rx
+}
+
+/// Broadcast a transaction through every available upstream and return the first success.
+/// Should all of them fail, the most meaningful error is returned: a rejection of the
+/// transaction itself rather than a daemon failure or a timeout.
+pub async fn broadcast(instances: &[Instance], addr: &str, params: Vec<Value>) -> R {
+    let mut responses = fan_out(instances, BROADCAST_METHOD, &params);
+    let mut best_error: Option<Value> = None;
+    let mut count = 0;
+    while let Some((index, response)) = responses.recv().await {
+        count += 1;
+        let Some(rep) = response else {
+            warn!("{} <= broadcast via WS-{} timed out", addr, index);
+            continue;
+        };
+        if let Some(result) = rep.result {
+            info!("{} <= broadcast accepted by WS-{}", addr, index);
+            return R::ok(result);
+        }
+        if let Some(err) = rep.error {
+            warn!("{} <= broadcast rejected by WS-{}: {}", addr, index, &err);
+            if best_error.as_ref().is_none_or(is_upstream_failure) {
+                best_error = Some(err);
+            }
+        }
+    }
+    if count == 0 {
+        return unavailable(false);
+    }
+    match best_error.as_ref().and_then(|x| x.as_object()) {
+        Some(err) => R {
+            code: err.get("code").cloned(),
+            message: err.get("message").cloned(),
+            ..R::error(-1, String::new())
+        },
+        None => R::error(-1, "Response timeout".into()),
+    }
+}
+
+pub const FEE_METHODS: [&str; 2] = ["blockchain.estimatefee", "blockchain.relayfee"];
+
+/// Ask every available upstream and return the median of the fee rates they answered with,
+/// together with the number of answers. `estimatefee` returns -1 when the daemon has no
+/// estimate, such answers are ignored.
+pub async fn median_fee(
+    instances: &[Instance],
+    method: &str,
+    params: &[Value],
+) -> Option<(f64, usize)> {
+    let mut responses = fan_out(instances, method, params);
+    let mut fees = vec![];
+    while let Some((_, response)) = responses.recv().await {
+        if let Some(fee) = response.and_then(|x| x.result).and_then(|x| x.as_f64()) {
+            fees.push(fee);
+        }
+    }
+    median(fees)
+}
+
+/// The median of the fee rates and their number, negative ones are left out.
+fn median(mut fees: Vec<f64>) -> Option<(f64, usize)> {
+    fees.retain(|x| *x >= 0.0);
+    if fees.is_empty() {
+        return None;
+    }
+    fees.sort_by(f64::total_cmp);
+    let mid = fees.len() / 2;
+    let median = if fees.len().is_multiple_of(2) {
+        (fees[mid - 1] + fees[mid]) / 2.0
+    } else {
+        fees[mid]
+    };
+    Some((median, fees.len()))
+}
+
+/// Answer a fee request with the median over all upstreams.
+pub async fn aggregate_fee(
+    instances: &[Instance],
+    addr: &str,
+    method: &str,
+    params: Vec<Value>,
+) -> R {
+    match median_fee(instances, method, &params).await {
+        Some((fee, count)) => {
+            info!(
+                "{} <= {} median {} of {} upstreams",
+                addr, method, fee, count
+            );
+            R::ok(json!(fee))
+        }
+        None => R::error_with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            -1,
+            "No fee estimate available".into(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_median_fee() {
+        assert_eq!(median(vec![0.0003, 0.0001, 0.0002]), Some((0.0002, 3)));
+        assert_eq!(
+            median(vec![0.0004, 0.0001, 0.0002, 0.0003]),
+            Some((0.00025, 4))
+        );
+        assert_eq!(median(vec![0.0001]), Some((0.0001, 1)));
+    }
+
+    #[test]
+    fn ignores_missing_estimates() {
+        assert_eq!(median(vec![-1.0, 0.0001, -1.0]), Some((0.0001, 1)));
+        assert_eq!(median(vec![-1.0]), None);
+        assert_eq!(median(vec![]), None);
+    }
+}
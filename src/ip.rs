@@ -1,25 +1,82 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 
+use axum::extract::{ConnectInfo, Request};
 use axum::http::header::FORWARDED;
-use axum::http::HeaderMap;
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
 use forwarded_header_value::{ForwardedHeaderValue, Identifier};
 
+use crate::envs::TRUSTED_PROXIES;
+
 const X_REAL_IP: &str = "x-real-ip";
 const X_FORWARDED_FOR: &str = "x-forwarded-for";
 
+/// The client IP resolved by [`real_ip`].
 pub fn maybe_ip_from_headers(headers: &HeaderMap) -> String {
-    maybe_x_forwarded_for(headers)
-        .or_else(|| maybe_x_real_ip(headers))
-        .or_else(|| maybe_forwarded(headers))
+    maybe_x_real_ip(headers)
         .map(|ip| ip.to_string())
         .unwrap_or("unknown ip".to_string())
 }
 
-fn maybe_x_forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+/// The client IP resolved by [`real_ip`], `None` without a peer address.
+pub fn client_ip(request: &Request) -> Option<IpAddr> {
+    maybe_x_real_ip(request.headers())
+}
+
+/// Resolve the client IP once for every handler and leave it alone in `X-Real-IP`. Forwarding
+/// headers are only read when the peer is one of `TRUSTED_PROXIES`, otherwise anyone could
+/// pose as another client with a made up `X-Forwarded-For`; the peer address is used instead.
+pub async fn real_ip(mut request: Request, next: Next) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|x| x.0.ip());
+    let ip = match peer {
+        Some(peer) if is_trusted(peer) => forwarded_ip(request.headers()).or(Some(peer)),
+        peer => peer,
+    };
+    let headers = request.headers_mut();
+    headers.remove(X_FORWARDED_FOR);
+    headers.remove(FORWARDED);
+    headers.remove(X_REAL_IP);
+    if let Some(ip) = ip {
+        headers.insert(X_REAL_IP, HeaderValue::from_str(&ip.to_string()).unwrap());
+    }
+    next.run(request).await
+}
+
+/// Whether `ip` is one of `TRUSTED_PROXIES`.
+pub fn is_trusted(ip: IpAddr) -> bool {
+    TRUSTED_PROXIES.iter().any(|x| x.contains(&ip))
+}
+
+/// The client in front of the trusted proxies. Each proxy appends the address it got the
+/// request from, so the hops are walked from the right and the first one that is not a
+/// trusted proxy is the client.
+fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let from_hops = |hops: Vec<IpAddr>| {
+        let first = *hops.first()?;
+        Some(
+            hops.into_iter()
+                .rev()
+                .find(|x| !is_trusted(*x))
+                .unwrap_or(first),
+        )
+    };
+    from_hops(x_forwarded_for(headers))
+        .or_else(|| maybe_x_real_ip(headers))
+        .or_else(|| from_hops(forwarded_for(headers)))
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
     headers
-        .get(X_FORWARDED_FOR)
-        .and_then(|hv| hv.to_str().ok())
-        .and_then(|s| s.split(',').find_map(|s| s.trim().parse::<IpAddr>().ok()))
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|hv| hv.to_str().ok())
+        .flat_map(|s| s.split(','))
+        .filter_map(|s| s.trim().parse::<IpAddr>().ok())
+        .collect()
 }
 
 fn maybe_x_real_ip(headers: &HeaderMap) -> Option<IpAddr> {
@@ -29,19 +86,69 @@ fn maybe_x_real_ip(headers: &HeaderMap) -> Option<IpAddr> {
         .and_then(|s| s.parse::<IpAddr>().ok())
 }
 
-fn maybe_forwarded(headers: &HeaderMap) -> Option<IpAddr> {
-    headers.get_all(FORWARDED).iter().find_map(|hv| {
-        hv.to_str()
-            .ok()
-            .and_then(|s| ForwardedHeaderValue::from_forwarded(s).ok())
-            .and_then(|f| {
-                f.iter()
-                    .filter_map(|fs| fs.forwarded_for.as_ref())
-                    .find_map(|ff| match ff {
-                        Identifier::SocketAddr(a) => Some(a.ip()),
-                        Identifier::IpAddr(ip) => Some(*ip),
-                        _ => None,
-                    })
-            })
-    })
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|hv| hv.to_str().ok())
+        .filter_map(|s| ForwardedHeaderValue::from_forwarded(s).ok())
+        .flat_map(|f| {
+            f.iter()
+                .filter_map(|fs| match fs.forwarded_for.as_ref()? {
+                    Identifier::SocketAddr(a) => Some(a.ip()),
+                    Identifier::IpAddr(ip) => Some(*ip),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(entries: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn walks_hops_from_the_right() {
+        let forwarded = headers(&[(X_FORWARDED_FOR, "198.51.100.1, 203.0.113.7, 10.0.0.1")]);
+        assert_eq!(forwarded_ip(&forwarded), ip("203.0.113.7"));
+        let split = headers(&[
+            (X_FORWARDED_FOR, "198.51.100.1"),
+            (X_FORWARDED_FOR, "203.0.113.7, 192.168.1.2"),
+        ]);
+        assert_eq!(forwarded_ip(&split), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn falls_back_to_first_hop_when_all_are_trusted() {
+        let forwarded = headers(&[(X_FORWARDED_FOR, "10.0.0.2, 10.0.0.1")]);
+        assert_eq!(forwarded_ip(&forwarded), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn skips_invalid_hops() {
+        let forwarded = headers(&[(X_FORWARDED_FOR, "203.0.113.7, unknown, 10.0.0.1")]);
+        assert_eq!(forwarded_ip(&forwarded), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn falls_back_to_x_real_ip_and_forwarded() {
+        let real_ip = headers(&[(X_REAL_IP, "203.0.113.8")]);
+        assert_eq!(forwarded_ip(&real_ip), ip("203.0.113.8"));
+        let forwarded = headers(&[("forwarded", "for=203.0.113.9, for=10.0.0.1")]);
+        assert_eq!(forwarded_ip(&forwarded), ip("203.0.113.9"));
+        assert_eq!(forwarded_ip(&HeaderMap::new()), None);
+    }
 }
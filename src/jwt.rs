@@ -0,0 +1,128 @@
+use std::sync::OnceLock;
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::envs::{JWT_AUDIENCE, JWT_ISSUER, JWT_PUBLIC_KEY, JWT_SECRET};
+
+/// Keys tokens are verified with, by algorithm.
+static KEYS: OnceLock<Vec<(Algorithm, DecodingKey)>> = OnceLock::new();
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+/// Load `JWT_SECRET` for HS256 tokens and the PEM file at `JWT_PUBLIC_KEY` for RS256 ones.
+pub fn load_keys() -> anyhow::Result<()> {
+    let mut keys = vec![];
+    if let Some(secret) = JWT_SECRET.as_deref() {
+        keys.push((
+            Algorithm::HS256,
+            DecodingKey::from_secret(secret.as_bytes()),
+        ));
+    }
+    if let Some(path) = JWT_PUBLIC_KEY.as_deref() {
+        let pem = std::fs::read(path)?;
+        keys.push((Algorithm::RS256, DecodingKey::from_rsa_pem(&pem)?));
+    }
+    let _ = KEYS.set(keys);
+    Ok(())
+}
+
+/// Whether the upstream routes require a bearer token.
+pub fn is_enabled() -> bool {
+    KEYS.get().is_some_and(|x| !x.is_empty())
+}
+
+/// Verify the signature, expiry, `JWT_ISSUER` and `JWT_AUDIENCE` of a token and return its
+/// subject.
+pub fn verify(token: &str) -> jsonwebtoken::errors::Result<String> {
+    let header = decode_header(token)?;
+    let Some((algorithm, key)) = KEYS
+        .get()
+        .and_then(|keys| keys.iter().find(|(x, _)| *x == header.alg))
+    else {
+        return Err(ErrorKind::InvalidAlgorithm.into());
+    };
+    let mut validation = Validation::new(*algorithm);
+    validation.set_required_spec_claims(&["exp", "sub"]);
+    if let Some(issuer) = JWT_ISSUER.as_deref() {
+        validation.set_issuer(&[issuer]);
+    }
+    match JWT_AUDIENCE.as_deref() {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+    Ok(decode::<Claims>(token, key, &validation)?.claims.sub)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn token(header: Header, claims: Value, secret: &[u8]) -> String {
+        let _ = KEYS.set(vec![(Algorithm::HS256, DecodingKey::from_secret(SECRET))]);
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn kind(token: &str) -> ErrorKind {
+        verify(token).unwrap_err().into_kind()
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let exp = get_current_timestamp() + 3600;
+        let token = token(
+            Header::default(),
+            json!({"sub": "alice", "exp": exp}),
+            SECRET,
+        );
+        assert_eq!(verify(&token).unwrap(), "alice");
+    }
+
+    #[test]
+    fn refuses_expired_token() {
+        let exp = get_current_timestamp() - 3600;
+        let token = token(
+            Header::default(),
+            json!({"sub": "alice", "exp": exp}),
+            SECRET,
+        );
+        assert_eq!(kind(&token), ErrorKind::ExpiredSignature);
+    }
+
+    #[test]
+    fn refuses_bad_signature() {
+        let exp = get_current_timestamp() + 3600;
+        let token = token(
+            Header::default(),
+            json!({"sub": "alice", "exp": exp}),
+            b"other",
+        );
+        assert_eq!(kind(&token), ErrorKind::InvalidSignature);
+    }
+
+    #[test]
+    fn requires_sub_and_exp() {
+        let exp = get_current_timestamp() + 3600;
+        let token1 = token(Header::default(), json!({"exp": exp}), SECRET);
+        assert!(matches!(kind(&token1), ErrorKind::Json(_)));
+        let token2 = token(Header::default(), json!({"sub": "alice"}), SECRET);
+        assert_eq!(kind(&token2), ErrorKind::MissingRequiredClaim("exp".into()));
+    }
+
+    #[test]
+    fn refuses_algorithm_without_key() {
+        let exp = get_current_timestamp() + 3600;
+        let header = Header::new(Algorithm::HS384);
+        let token = token(header, json!({"sub": "alice", "exp": exp}), SECRET);
+        assert_eq!(kind(&token), ErrorKind::InvalidAlgorithm);
+    }
+}
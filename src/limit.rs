@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use axum::extract::Request;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use governor::clock::{Clock, DefaultClock, QuantaInstant};
+use governor::middleware::{StateInformationMiddleware, StateSnapshot};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{InsufficientCapacity, NotUntil, Quota, RateLimiter};
+
+use crate::auth::Caller;
+use crate::envs::{API_KEYS, IP_LIMIT_BURST_SIZE, IP_LIMIT_PER_MILLS};
+use crate::ip::client_ip;
+use crate::structs::R;
+
+const X_API_KEY: &str = "x-api-key";
+const API_KEY_PARAM: &str = "api_key";
+
+const X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+const X_RATELIMIT_AFTER: HeaderName = HeaderName::from_static("x-ratelimit-after");
+
+type Limiter<K, S> = RateLimiter<K, S, DefaultClock, StateInformationMiddleware>;
+
+/// A rate limit, one request is replenished every `per_mills` milliseconds up to `burst`.
+struct Limit<L> {
+    per_mills: u64,
+    burst: u32,
+    limiter: L,
+}
+
+fn quota(per_mills: u64, burst: u32) -> Quota {
+    Quota::with_period(Duration::from_millis(per_mills))
+        .unwrap()
+        .allow_burst(NonZeroU32::new(burst).unwrap())
+}
+
+/// A client without an API key.
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub enum Client {
+    Ip(IpAddr),
+    /// Subject of a verified bearer token.
+    Subject(String),
+}
+
+/// The limit of clients without an API key, each IP or token subject on its own.
+static CLIENT_LIMIT: LazyLock<Limit<Limiter<Client, DefaultKeyedStateStore<Client>>>> =
+    LazyLock::new(|| Limit {
+        per_mills: *IP_LIMIT_PER_MILLS,
+        burst: *IP_LIMIT_BURST_SIZE,
+        limiter: RateLimiter::keyed(quota(*IP_LIMIT_PER_MILLS, *IP_LIMIT_BURST_SIZE))
+            .with_middleware(),
+    });
+
+/// A limit of its own for every key of `API_KEYS`.
+static KEY_LIMITS: LazyLock<HashMap<String, Limit<Limiter<NotKeyed, InMemoryState>>>> =
+    LazyLock::new(|| {
+        API_KEYS
+            .iter()
+            .map(|(key, per_mills, burst)| {
+                let limit = Limit {
+                    per_mills: *per_mills,
+                    burst: *burst,
+                    limiter: RateLimiter::direct(quota(*per_mills, *burst)).with_middleware(),
+                };
+                (key.clone(), limit)
+            })
+            .collect()
+    });
+
+/// The API key of the request, from the `X-API-Key` header, where [`api_key_param`] moves the
+/// `api_key` query param.
+pub fn api_key(headers: &HeaderMap) -> Option<String> {
+    let key = headers.get(X_API_KEY)?;
+    key.to_str().ok().map(|x| x.to_string())
+}
+
+/// Move the `api_key` query param into `X-API-Key` unless the header is set, for clients which
+/// cannot set headers such as `EventSource`, and drop it from the URI before the access log
+/// and the request spans record it.
+pub async fn api_key_param(mut request: Request, next: Next) -> Response {
+    if let Some((key, uri)) = take_api_key(request.uri()) {
+        if let Ok(key) = HeaderValue::from_str(&key) {
+            request.headers_mut().entry(X_API_KEY).or_insert(key);
+        }
+        *request.uri_mut() = uri;
+    }
+    next.run(request).await
+}
+
+/// The `api_key` query param of `uri` and `uri` without it, the other params left as they are.
+fn take_api_key(uri: &Uri) -> Option<(String, Uri)> {
+    let (mut key, mut kept) = (None, vec![]);
+    for pair in uri.query()?.split('&') {
+        match url::form_urlencoded::parse(pair.as_bytes()).next() {
+            Some((name, value)) if name == API_KEY_PARAM => key = Some(value.into_owned()),
+            _ => kept.push(pair),
+        }
+    }
+    let key = key?;
+    let path_and_query = if kept.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), kept.join("&"))
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Some((key, Uri::from_parts(parts).ok()?))
+}
+
+/// Whether `key` is one of `API_KEYS`.
+pub fn is_known_key(key: &str) -> bool {
+    KEY_LIMITS.contains_key(key)
+}
+
+/// The limit a request was charged to by [`rate_limit`], kept in its extensions so that
+/// requests carrying several calls, batches and WebSocket messages, are charged for each call.
+#[derive(Clone)]
+pub enum Charge {
+    Key(String),
+    Client(Client),
+}
+
+impl Charge {
+    fn check_n(&self, n: NonZeroU32) -> (u64, u32, Checked) {
+        match self {
+            Charge::Key(key) => {
+                let limit = &KEY_LIMITS[key];
+                (limit.per_mills, limit.burst, limit.limiter.check_n(n))
+            }
+            Charge::Client(client) => {
+                let limit = &*CLIENT_LIMIT;
+                let checked = limit.limiter.check_key_n(client, n);
+                (limit.per_mills, limit.burst, checked)
+            }
+        }
+    }
+
+    /// Take `n` more calls from the limit, all or none of them.
+    pub fn take(&self, n: usize) -> Result<(), Refused> {
+        let Some(n) = u32::try_from(n).ok().and_then(NonZeroU32::new) else {
+            return Ok(());
+        };
+        let (per_mills, burst, checked) = self.check_n(n);
+        let wait_time = match checked {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(not_until)) => Some(wait_time(&not_until)),
+            Err(_) => None,
+        };
+        Err(Refused {
+            per_mills,
+            burst,
+            wait_time,
+        })
+    }
+}
+
+/// Calls over a rate limit, answered with 429 and `Retry-After` like every limited request.
+pub struct Refused {
+    per_mills: u64,
+    burst: u32,
+    /// Seconds until the calls are available, `None` when they are more than the burst.
+    wait_time: Option<u64>,
+}
+
+impl Refused {
+    pub fn message(&self) -> String {
+        match self.wait_time {
+            Some(wait_time) => format!("Too many requests, retry after {}s", wait_time),
+            None => format!("More calls at once than the burst of {}", self.burst),
+        }
+    }
+}
+
+/// Answer a request over its rate limit with an `R` like every other error, `Retry-After`
+/// tells the client how many seconds to wait.
+impl IntoResponse for Refused {
+    fn into_response(self) -> Response {
+        let mut response =
+            R::error_with_status(StatusCode::TOO_MANY_REQUESTS, -1, self.message()).into_response();
+        let Some(wait_time) = self.wait_time else {
+            return response;
+        };
+        let headers = response.headers_mut();
+        headers.insert(X_RATELIMIT_AFTER, wait_time.into());
+        headers.insert(X_RATELIMIT_LIMIT, self.burst.into());
+        headers.insert(X_RATELIMIT_REMAINING, 0.into());
+        headers.insert(
+            X_RATELIMIT_RESET,
+            (u64::from(self.burst) * self.per_mills)
+                .div_ceil(1000)
+                .into(),
+        );
+        headers.insert(RETRY_AFTER, wait_time.into());
+        response
+    }
+}
+
+type Checked = Result<Result<StateSnapshot, NotUntil<QuantaInstant>>, InsufficientCapacity>;
+
+/// Limit requests with an API key to the rate of the key and the others to the rate of their
+/// token subject or IP, as resolved by [`crate::auth::authenticate`]. Responses carry
+/// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`, the seconds until the
+/// burst is replenished, limited requests are answered with 429 and `Retry-After`.
+pub async fn rate_limit(mut request: Request, next: Next) -> Response {
+    let charge = match request.extensions().get::<Caller>() {
+        Some(Caller::Key(key)) => Charge::Key(key.clone()),
+        Some(Caller::Subject(subject)) => Charge::Client(Client::Subject(subject.clone())),
+        _ => match client_ip(&request) {
+            Some(ip) => Charge::Client(Client::Ip(ip)),
+            None => {
+                let message = "Unable to extract the client IP".to_string();
+                return R::error_with_status(StatusCode::INTERNAL_SERVER_ERROR, -1, message)
+                    .into_response();
+            }
+        },
+    };
+    match charge.check_n(NonZeroU32::MIN) {
+        (per_mills, burst, Ok(Ok(snapshot))) => {
+            request.extensions_mut().insert(charge);
+            let mut response = next.run(request).await;
+            insert_limits(response.headers_mut(), per_mills, burst, &snapshot);
+            response
+        }
+        (per_mills, burst, Ok(Err(not_until))) => Refused {
+            per_mills,
+            burst,
+            wait_time: Some(wait_time(&not_until)),
+        }
+        .into_response(),
+        // A burst is never below one.
+        (_, _, Err(_)) => unreachable!(),
+    }
+}
+
+fn insert_limits(headers: &mut HeaderMap, per_mills: u64, burst: u32, snapshot: &StateSnapshot) {
+    let remaining = snapshot.remaining_burst_capacity();
+    let reset = (u64::from(burst - remaining) * per_mills).div_ceil(1000);
+    headers.insert(X_RATELIMIT_LIMIT, burst.into());
+    headers.insert(X_RATELIMIT_REMAINING, remaining.into());
+    headers.insert(X_RATELIMIT_RESET, reset.into());
+}
+
+fn wait_time(not_until: &NotUntil<QuantaInstant>) -> u64 {
+    let wait = not_until.wait_time_from(DefaultClock::default().now());
+    (wait.as_millis() as u64).div_ceil(1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_api_keys_out_of_uris() {
+        let uri: Uri = "/proxy/server.version?api_key=a%2Bb&params=%5B%5D"
+            .parse()
+            .unwrap();
+        let (key, uri) = take_api_key(&uri).unwrap();
+        assert_eq!(key, "a+b");
+        assert_eq!(uri, "/proxy/server.version?params=%5B%5D");
+        let (_, uri) = take_api_key(&"/events?api_key=x".parse().unwrap()).unwrap();
+        assert_eq!(uri, "/events");
+        assert!(take_api_key(&"/proxy?params=%5B%5D".parse().unwrap()).is_none());
+        assert!(take_api_key(&"/proxy".parse().unwrap()).is_none());
+    }
+}
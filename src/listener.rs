@@ -0,0 +1,163 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use proxy_protocol::version2::ProxyCommand;
+use proxy_protocol::{version1, version2, ProxyHeader};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpListener;
+use tower::Layer;
+use tracing::{debug, error};
+
+use crate::ip::is_trusted;
+
+/// Serve `app` behind a load balancer speaking the PROXY protocol, v1 or v2. Every connection
+/// must come from `TRUSTED_PROXIES` and start with a PROXY header, the client address it carries
+/// becomes the `ConnectInfo` of the connection in place of the address of the load balancer.
+pub async fn serve_proxy_protocol(listener: TcpListener, app: Router) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        // Anyone else could claim any address in the header.
+        if !is_trusted(peer.ip()) {
+            debug!("{} Untrusted peer on the PROXY protocol listener", peer);
+            continue;
+        }
+        let app = app.clone();
+        tokio::spawn(async move {
+            let header = tokio::time::timeout(Duration::from_secs(5), read_header(&mut stream));
+            let addr = match header.await {
+                Ok(Ok(addr)) => addr.unwrap_or(peer),
+                Ok(Err(e)) => {
+                    debug!("{} Invalid PROXY header: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("{} No PROXY header received within 5 seconds", peer);
+                    return;
+                }
+            };
+            let service = TowerToHyperService::new(Extension(ConnectInfo(addr)).layer(app));
+            // Errors only mean the client went away.
+            let _ = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+/// Read the PROXY header at the start of a connection, not a byte further. Returns the address
+/// of the client, `None` when the load balancer speaks for itself, e.g. for health checks.
+async fn read_header<S>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = vec![0; 16];
+    stream.read_exact(&mut buf[..6]).await?;
+    if buf.starts_with(b"PROXY ") {
+        // A single line of at most 107 bytes.
+        buf.truncate(6);
+        while !buf.ends_with(b"\r\n") {
+            anyhow::ensure!(buf.len() < 107, "v1 header too long");
+            buf.push(stream.read_u8().await?);
+        }
+    } else {
+        // 16 bytes followed by the length of the addresses.
+        stream.read_exact(&mut buf[6..]).await?;
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        buf.resize(16 + len, 0);
+        stream.read_exact(&mut buf[16..]).await?;
+    }
+    let addr = match proxy_protocol::parse(&mut &buf[..])? {
+        ProxyHeader::Version1 { addresses } => match addresses {
+            version1::ProxyAddresses::Ipv4 { source, .. } => Some(source.into()),
+            version1::ProxyAddresses::Ipv6 { source, .. } => Some(source.into()),
+            version1::ProxyAddresses::Unknown => None,
+        },
+        ProxyHeader::Version2 {
+            command: ProxyCommand::Local,
+            ..
+        } => None,
+        ProxyHeader::Version2 { addresses, .. } => match addresses {
+            version2::ProxyAddresses::Ipv4 { source, .. } => Some(source.into()),
+            version2::ProxyAddresses::Ipv6 { source, .. } => Some(source.into()),
+            _ => None,
+        },
+        _ => None,
+    };
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A v2 header of a TCP over IPv4 connection from 203.0.113.7:51000.
+    fn v2_ipv4() -> Vec<u8> {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend(51000u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_header() {
+        let mut stream = &b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\nGET / HTTP/1.1\r\n"[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn reads_v1_ipv6_and_unknown_headers() {
+        let mut stream = &b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 443\r\n"[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:51000".parse().unwrap()));
+        let mut stream = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_long_v1_header() {
+        let line = format!("PROXY TCP4 {}\r\n", "1".repeat(120));
+        assert!(read_header(&mut line.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_header() {
+        let mut bytes = v2_ipv4();
+        bytes.extend(b"GET");
+        let mut stream = &bytes[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_local_header() {
+        let mut bytes = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        bytes.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &bytes[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_truncated_and_missing_headers() {
+        let bytes = v2_ipv4();
+        assert!(read_header(&mut &bytes[..20]).await.is_err());
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"[..])
+            .await
+            .is_err());
+    }
+}
@@ -1,238 +1,1054 @@
-#![feature(lazy_cell)]
-
 use std::any::Any;
-use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
 use std::time::Duration;
 
-use axum::extract::Extension;
 use axum::extract::Json;
+use axum::extract::{DefaultBodyLimit, Extension, Request};
 use axum::extract::{Path, Query};
 use axum::http;
 use axum::http::StatusCode;
-use axum::http::{header, HeaderMap};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::middleware;
+use axum::middleware::Next;
 use axum::response::IntoResponse;
 use axum::response::Response;
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::Router;
 use bytes::Bytes;
-use dotenv::dotenv;
-use futures::{SinkExt, StreamExt};
+use dotenvy::dotenv;
+use futures::future::join_all;
 use http_body_util::Full;
-use moka::future::Cache;
 use once_cell::sync::Lazy;
-use rand::Rng;
-use serde_json::Value;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::{mpsc, oneshot};
-use tokio::sync::{Mutex, RwLock};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::Message;
+use serde_json::{json, Value};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
+use tokio::sync::oneshot::error::RecvError;
+use tokio::time::error::Elapsed;
+use tokio::time::Instant;
 use tower::limit::ConcurrencyLimitLayer;
-use tower_governor::governor::GovernorConfigBuilder;
-use tower_governor::key_extractor::SmartIpKeyExtractor;
-use tower_governor::GovernorLayer;
 use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::CorsLayer;
-use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info, warn};
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::trace::{DefaultMakeSpan, TraceLayer};
+use tracing::{error, info, instrument, warn, Level, Span};
+use url::form_urlencoded;
 
-use crate::cache::to_cache_key;
+use crate::access::access_log;
+use crate::acl::filter_ip;
+use crate::address::handle_address_method;
+use crate::admin::{
+    handle_block, handle_blocks, handle_broadcast, handle_broadcasts, handle_cache_clear,
+    handle_cache_hottest, handle_cache_purge, handle_log_level, handle_read_only,
+    handle_reload_upstreams, handle_set_log_level, handle_unblock, handle_upstream_events,
+    refuse_write, require_admin,
+};
+use crate::atomicals::{
+    handle_container_item, handle_container_items, handle_realm, handle_ticker,
+};
+use crate::auth::{authenticate, require_auth};
+use crate::cache::{
+    bus, bypasses_cache, cache_get, cache_insert, cache_ttl, etag_matches, is_immutable,
+    is_settled, is_stale, stale_grace, staleness, to_cache_key, to_etag, unsettled_txid,
+    within_grace,
+};
+use crate::coalesce::coalesce;
+use crate::codec::negotiate;
+use crate::decode::handle_decode_tx;
+use crate::diagnostics::{insert_diagnostics, server_timing, Timing};
 use crate::envs::{
-    CACHE_TIME_TO_IDLE, CACHE_TIME_TO_LIVE, CONCURRENCY_LIMIT, ELECTRUMX_WSS,
-    ELECTRUMX_WS_INSTANCE, IP_LIMIT_BURST_SIZE, IP_LIMIT_PER_MILLS, MAX_CACHE_ENTRIES,
-    NO_CACHE_METHODS, PROXY_HOST, RESPONSE_TIMEOUT,
+    ALLOWED_METHODS, API_KEYS, BLOCKED_METHODS, BROADCAST_TO_ALL, CACHE_BACKEND,
+    COMPRESSION_ALGORITHMS, COMPRESSION_MIN_SIZE, CONCURRENCY_LIMIT, FEE_AGGREGATION,
+    HEALTH_CHECK_FIELDS, HEALTH_CHECK_METHOD, HEALTH_CHECK_PARAMS, HEALTH_CHECK_TIMEOUT,
+    HEDGE_DELAY_MS, HTTP_ERROR_STATUS, IP_ALLOWLIST, IP_DENYLIST, MAX_BATCH_SIZE, MAX_BODY_SIZE,
+    NEGATIVE_CACHE_TTL, NO_CACHE_METHODS, PEER_DISCOVERY, PROCESS_ENV, PROXY_HOST, PROXY_PROTOCOL,
+    RESPONSE_TIMEOUT, SLOW_REQUEST_MS, TRUSTED_PROXIES, UPSTREAM_CAPACITY_WAIT_MS,
+};
+use crate::esplora::{
+    handle_address, handle_address_utxo, handle_fees_recommended, handle_tip_height, handle_tx,
+    handle_tx_hex,
+};
+use crate::events::publish_global;
+use crate::fanout::{aggregate_fee, broadcast, median_fee, BROADCAST_METHOD, FEE_METHODS};
+use crate::ip::{maybe_ip_from_headers, real_ip};
+use crate::limit::{api_key_param, rate_limit, Charge};
+use crate::listener::serve_proxy_protocol;
+use crate::metrics::{
+    cache_hit_ratio, handle_metrics, log_latencies, record_cache, record_latency, record_request,
+    watch_backlogs, CACHE_HITS, CACHE_MISSES, METHODS, NO_UPSTREAM, REQUESTS, STARTED_AT,
+    UPSTREAM_OVERLOADED,
+};
+use crate::monitor::{send_heartbeats, watch_outages};
+use crate::projection::{lookup, paginate, project_fields};
+use crate::proxy::{BUILD, PROXY_RESPONSE};
+use crate::rpc::{handle_rpc, to_response};
+use crate::sse::handle_events;
+use crate::structs::{AppError, JsonRpcResponse, Params, ResultCache, R};
+use crate::upstream::{
+    consensus_height, is_unknown_item, is_upstream_failure, select_instance, select_other_instance,
+    select_synced_instance, spawn_discovery, upstream_error_status, InFlight, Instance, Upstreams,
 };
-use crate::ip::maybe_ip_from_headers;
-use crate::proxy::PROXY_RESPONSE;
-use crate::structs::{AppError, Callbacks, JsonRpcRequest, JsonRpcResponse, MokaCache, R};
 use crate::urn::handle_urn;
+use crate::validate::validate;
+use crate::ws::handle_ws;
 
+mod access;
+mod acl;
+mod address;
+mod admin;
+mod atomicals;
+mod audit;
+mod auth;
 mod cache;
+mod coalesce;
+mod codec;
+mod decode;
+mod diagnostics;
 mod envs;
+mod esplora;
+mod events;
+mod fanout;
 mod ip;
+mod jwt;
+mod limit;
+mod listener;
+mod metrics;
+mod monitor;
+mod projection;
 mod proxy;
+mod rpc;
+mod sse;
+mod statsd;
 mod structs;
+mod subscriptions;
+mod telemetry;
+mod upstream;
 mod urn;
+mod validate;
+mod ws;
 
-// The use of `AtomicU32` is to ensure not exceeding the integer range of other systems.
-static ID_COUNTER: Lazy<AtomicU32> = Lazy::new(|| AtomicU32::new(1));
-static CACHED_BLOCK_HEIGHT: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
+pub static CACHED_BLOCK_HEIGHT: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
 
-fn get_next_id() -> u32 {
-    // Reset the counter when it reaches the maximum value.
-    if ID_COUNTER.load(Ordering::SeqCst) == u32::MAX {
-        ID_COUNTER.store(1, Ordering::SeqCst);
-    }
-    ID_COUNTER.fetch_add(1, Ordering::SeqCst)
+async fn handle_get(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    headers: HeaderMap,
+    Path(method): Path<String>,
+    Query(query): Query<Value>,
+) -> Response {
+    let instances = upstreams.snapshot();
+    let raw = is_raw(&headers, &query);
+    let if_none_match = headers.get(header::IF_NONE_MATCH).cloned();
+    let params = match query.get("params").and_then(|x| x.as_str()) {
+        None | Some("") => Ok(Value::Null),
+        Some(x) => serde_json::from_str(x).map_err(|e| format!("Invalid params: {}", e)),
+    };
+    let r = match params.and_then(to_params) {
+        Ok(params) => handle_request(cache, &instances, headers, method, params).await,
+        Err(message) => invalid_params(message),
+    };
+    let r = project_fields(paginate(r, &query), &query);
+    respond(raw, if_none_match, r)
 }
 
-async fn handle_get(
-    Extension(callbacks): Extension<Vec<(UnboundedSender<JsonRpcRequest>, Callbacks)>>,
-    Extension(cache): Extension<MokaCache>,
+async fn handle_post(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
     headers: HeaderMap,
     Path(method): Path<String>,
     Query(query): Query<Value>,
-) -> Result<R, AppError> {
-    let item = random_callback(&callbacks);
-    let sender = item.0.clone();
-    let calls = item.1.clone();
-    let r = match query.get("params") {
-        None => handle_request(cache, sender, calls, headers, method, vec![]).await,
-        Some(v) => {
-            let x = v
-                .as_str()
-                .map(|s| if s.is_empty() { "[]" } else { s })
-                .unwrap();
-            let params = serde_json::from_str(x).unwrap();
-            handle_request(cache, sender, calls, headers, method, params).await
+    body: Bytes,
+) -> Response {
+    let instances = upstreams.snapshot();
+    let raw = is_raw(&headers, &query);
+    let if_none_match = headers.get(header::IF_NONE_MATCH).cloned();
+    // The body is either `{"params": ...}` or the params array itself.
+    let params = to_body(&headers, &body).map(|body| match body {
+        Some(body @ Value::Array(_)) => body,
+        Some(mut body) => body.get_mut("params").map(Value::take).unwrap_or_default(),
+        None => Value::Null,
+    });
+    let r = match params.and_then(to_params) {
+        Ok(params) => handle_request(cache, &instances, headers, method, params).await,
+        Err(message) => invalid_params(message),
+    };
+    let r = project_fields(paginate(r, &query), &query);
+    respond(raw, if_none_match, r)
+}
+
+/// Parse the body of `POST /proxy/:method` by its content type. Legacy wallets send
+/// `params=[...]` form-urlencoded, anything else is read as JSON, including `text/plain`.
+fn to_body(headers: &HeaderMap, body: &[u8]) -> Result<Option<Value>, String> {
+    if body.trim_ascii().is_empty() {
+        return Ok(None);
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        let params = form_urlencoded::parse(body).find(|(k, _)| k == "params");
+        return match params {
+            Some((_, params)) => serde_json::from_str::<Value>(&params)
+                .map(|params| Some(json!({ "params": params })))
+                .map_err(|e| format!("Invalid params: {}", e)),
+            None => Ok(None),
+        };
+    }
+    serde_json::from_slice(body)
+        .map(Some)
+        .map_err(|e| format!("Invalid body: {}", e))
+}
+
+/// Accept params as an array, or as an object for methods taking named params.
+fn to_params(params: Value) -> Result<Params, String> {
+    match params {
+        Value::Null => Ok(vec![].into()),
+        Value::Array(params) => Ok(Params::Positional(params)),
+        Value::Object(params) => Ok(Params::Named(params)),
+        Value::Bool(_) => Err("Params must be an array or an object, got a boolean".into()),
+        Value::Number(_) => Err("Params must be an array or an object, got a number".into()),
+        Value::String(_) => Err("Params must be an array or an object, got a string".into()),
+    }
+}
+
+fn invalid_params(message: String) -> R {
+    R::error_with_status(StatusCode::BAD_REQUEST, -1, message)
+}
+
+/// Whether the caller asked with `X-Raw-Response` or `?raw` for the plain JSON-RPC response of
+/// the upstream instead of the `R` envelope.
+fn is_raw(headers: &HeaderMap, query: &Value) -> bool {
+    let flag = |x: &str| matches!(x, "1" | "true");
+    let header = headers.get("x-raw-response").and_then(|x| x.to_str().ok());
+    let query = query.get("raw").and_then(|x| x.as_str());
+    header.is_some_and(flag) || query.is_some_and(flag)
+}
+
+/// Answer in the requested format. Results served from the cache carry an ETag of their
+/// payload, and a matching `If-None-Match` is answered with 304.
+fn respond(raw: bool, if_none_match: Option<HeaderValue>, r: R) -> Response {
+    let etag = match (r.cache, &r.response) {
+        (Some(true), Some(response)) => HeaderValue::from_str(&to_etag(response)).ok(),
+        _ => None,
+    };
+    let cache_control = cache_control(&r);
+    let mut headers = HeaderMap::new();
+    insert_diagnostics(&r, &mut headers);
+    if let Some(etag) = &etag {
+        if if_none_match.is_some_and(|x| etag_matches(&x, etag)) {
+            headers.insert(header::ETAG, etag.clone());
+            headers.insert(header::CACHE_CONTROL, cache_control);
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
         }
+    }
+    let mut response = if raw {
+        let status = r.status.unwrap_or(StatusCode::OK);
+        (status, headers, Json(to_response(Value::Null, r))).into_response()
+    } else {
+        r.into_response()
     };
-    Ok(r)
+    let headers = response.headers_mut();
+    if let Some(etag) = etag {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(header::CACHE_CONTROL, cache_control);
+    response
 }
 
-fn random_callback(
-    callbacks: &[(UnboundedSender<JsonRpcRequest>, Callbacks)],
-) -> &(UnboundedSender<JsonRpcRequest>, Callbacks) {
-    let mut rng = rand::thread_rng();
-    let index = rng.gen_range(0..callbacks.len());
-    &callbacks[index]
+/// `public` for as long as the result stays in the cache, so that browsers and CDNs can
+/// share it, for a year for results that never change, `no-store` for everything that is
+/// never cached, errors included.
+fn cache_control(r: &R) -> HeaderValue {
+    let Some(expires_at) = r.expires_at else {
+        return HeaderValue::from_static("no-store");
+    };
+    if r.immutable {
+        return HeaderValue::from_static("public, max-age=31536000, immutable");
+    }
+    let max_age = expires_at
+        .saturating_duration_since(std::time::Instant::now())
+        .as_secs();
+    let value = match stale_grace().as_secs() {
+        0 => format!("public, max-age={}", max_age),
+        grace => format!(
+            "public, max-age={}, stale-while-revalidate={}",
+            max_age, grace
+        ),
+    };
+    HeaderValue::from_str(&value).unwrap()
 }
 
-async fn handle_post(
-    Extension(callbacks): Extension<Vec<(UnboundedSender<JsonRpcRequest>, Callbacks)>>,
-    Extension(cache): Extension<MokaCache>,
+/// Run a batch of `{method, params}` calls concurrently, each as if it was sent on its own,
+/// and return their results in the same order. Every call counts against the rate limit, the
+/// request itself was charged for the first.
+async fn handle_batch(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    Extension(charge): Extension<Charge>,
     headers: HeaderMap,
-    Path(method): Path<String>,
-    body: Option<Json<Value>>,
-) -> Result<R, AppError> {
-    let item = random_callback(&callbacks);
-    let sender = item.0.clone();
-    let calls = item.1.clone();
-    let r = match body {
-        None => handle_request(cache, sender, calls, headers, method, vec![]).await,
-        Some(v) => match v.0.get("params") {
-            None => handle_request(cache, sender, calls, headers, method, vec![]).await,
-            Some(v) => {
-                let x = v.as_array().unwrap();
-                handle_request(cache, sender, calls, headers, method, x.clone()).await
+    Json(calls): Json<Vec<Value>>,
+) -> Response {
+    if calls.len() > *MAX_BATCH_SIZE {
+        let message = format!("Batch exceeds {} calls", *MAX_BATCH_SIZE);
+        return R::error_with_status(StatusCode::BAD_REQUEST, -1, message).into_response();
+    }
+    if let Err(refused) = charge.take(calls.len().saturating_sub(1)) {
+        return refused.into_response();
+    }
+    let instances = upstreams.snapshot();
+    let results = join_all(calls.iter().map(|call| {
+        let method = call.get("method").and_then(|x| x.as_str());
+        let params = to_params(call.get("params").cloned().unwrap_or_default());
+        let (cache, instances, headers) = (cache.clone(), &instances, headers.clone());
+        async move {
+            match (method, params) {
+                (Some(method), Ok(params)) => {
+                    handle_request(cache, instances, headers, method.into(), params).await
+                }
+                (None, _) => R::error(-1, "Missing method".into()),
+                (_, Err(message)) => R::error(-1, message),
             }
-        },
-    };
-    Ok(r)
+        }
+    }))
+    .await;
+    Json(results).into_response()
 }
 
+/// Answer a call and log how it went with structured fields, see `LOG_FORMAT`.
 async fn handle_request(
-    cache: MokaCache,
-    ws_tx: UnboundedSender<JsonRpcRequest>,
-    callbacks: Callbacks,
+    cache: ResultCache,
+    instances: &[Instance],
     headers: HeaderMap,
     method: String,
-    params: Vec<Value>,
+    params: impl Into<Params>,
+) -> R {
+    let started = Instant::now();
+    let client_ip = maybe_ip_from_headers(&headers);
+    let params = params.into();
+    let caller = (method == BROADCAST_METHOD)
+        .then(|| auth::caller(&headers).identity())
+        .flatten();
+    let r = dispatch(cache, instances, headers, method.clone(), &params).await;
+    if method == BROADCAST_METHOD {
+        audit::record(&client_ip, caller, &params, &r);
+    }
+    let elapsed = started.elapsed();
+    let timing = r.timing.as_ref();
+    if *SLOW_REQUEST_MS > 0 && elapsed >= Duration::from_millis(*SLOW_REQUEST_MS) {
+        warn!(
+            "{} => {}(params {:016x}) slow, {}ms in total, {}",
+            &client_ip,
+            &method,
+            to_cache_key(&method, &params),
+            elapsed.as_millis(),
+            match timing {
+                Some(timing) => format!(
+                    "{}ms queued and {}ms via WS-{}",
+                    timing.queue.as_millis(),
+                    timing.upstream.as_millis(),
+                    timing.instance
+                ),
+                None => "not sent upstream".to_string(),
+            }
+        );
+    }
+    info!(
+        %client_ip,
+        %method,
+        rpc_id = timing.map(|x| x.id),
+        upstream = timing.map(|x| x.instance),
+        duration_ms = elapsed.as_millis() as u64,
+        cache_hit = r.cache == Some(true),
+        outcome = if r.success { "ok" } else { "error" },
+        error = r.message.as_ref().and_then(|x| x.as_str()),
+    );
+    r
+}
+
+async fn dispatch(
+    cache: ResultCache,
+    instances: &[Instance],
+    headers: HeaderMap,
+    method: String,
+    params: &Params,
 ) -> R {
-    let id = get_next_id();
     let addr = maybe_ip_from_headers(&headers);
-    let cache_key = to_cache_key(&method, &params);
-    let no_cache = NO_CACHE_METHODS.contains(&method);
-    if !no_cache && cache.contains_key(&cache_key) {
-        if let Some(v) = cache.get(&cache_key).await {
-            info!(
-                "{} => {}, {}({:?}) matched cache({})",
-                &addr, &id, &method, &params, &cache_key
-            );
-            return R {
-                cache: Some(true),
-                ..v
-            };
+    if !is_method_allowed(&method) {
+        warn!("{} => {}({:?}) not allowed", &addr, &method, &params);
+        let message = format!("Method not allowed: {}", method);
+        return R::error_with_status(StatusCode::FORBIDDEN, -1, message);
+    }
+    if let Some(message) = refuse_write(&method) {
+        warn!("{} => {}({:?}) {}", &addr, &method, &params, message);
+        return R::error_with_status(StatusCode::SERVICE_UNAVAILABLE, -1, message);
+    }
+    if let Err(message) = validate(&method, params) {
+        warn!("{} => {}({:?}) {}", &addr, &method, &params, message);
+        return R::error_with_status(StatusCode::BAD_REQUEST, -1, message);
+    }
+    record_request(&method);
+    let cache_key = to_cache_key(&method, params);
+    let no_cache = NO_CACHE_METHODS.iter().any(|x| matches_method(x, &method));
+    let bypass = bypasses_cache(&headers);
+    if bypass {
+        info!("{} => {}({:?}) bypassing cache", &addr, &method, &params);
+    }
+    let mut fallback = None;
+    if !no_cache && !bypass {
+        match cache_get(&cache, cache_key, &method, params).await {
+            Some(v) if !is_stale(&v) || within_grace(&v) => {
+                let stale = is_stale(&v);
+                info!(
+                    "{} => {}({:?}) matched {}cache({})",
+                    &addr,
+                    &method,
+                    &params,
+                    if stale { "stale " } else { "" },
+                    &cache_key
+                );
+                record_cache(true);
+                if stale {
+                    revalidate(cache, instances, &addr, &method, params, cache_key);
+                }
+                return R {
+                    cache: Some(true),
+                    staleness: staleness(&v),
+                    ..v
+                };
+            }
+            // Past the grace period, only served while no upstream is connected.
+            v => fallback = v,
         }
     }
-    info!("{} => {}, {}({:?})", &addr, &id, &method, &params);
-    let (response_tx, response_rx) = oneshot::channel();
-    {
-        callbacks.write().await.insert(id, response_tx);
+    if let Some(v) = fallback.filter(|_| !instances.iter().any(|x| x.state.is_connected())) {
+        warn!(
+            "{} => {}({:?}) no upstream connected, matched stale cache({})",
+            &addr, &method, &params, &cache_key
+        );
+        record_cache(true);
+        return R {
+            cache: Some(true),
+            staleness: staleness(&v),
+            ..v
+        };
+    }
+    if !no_cache {
+        record_cache(false);
     }
-    let request = JsonRpcRequest {
-        id: Some(id),
+    // Fanned out calls take positional params, named ones go to a single upstream.
+    if let Params::Positional(positional) = &params {
+        if method == BROADCAST_METHOD && *BROADCAST_TO_ALL {
+            info!("{} => {}({:?}) via all upstreams", &addr, &method, &params);
+            return broadcast(instances, &addr, positional.clone()).await;
+        }
+        if FEE_METHODS.contains(&method.as_str()) && *FEE_AGGREGATION {
+            info!("{} => {}({:?}) via all upstreams", &addr, &method, &params);
+            return aggregate_fee(instances, &addr, &method, positional.clone()).await;
+        }
+    }
+    if method == BROADCAST_METHOD {
+        return forward(
+            &cache, instances, &addr, &method, params, cache_key, no_cache,
+        )
+        .await;
+    }
+    let call = forward(
+        &cache, instances, &addr, &method, params, cache_key, no_cache,
+    );
+    coalesce(cache_key, call).await
+}
+
+/// Refresh a stale cached result in the background, concurrent stale hits share one refresh.
+fn revalidate(
+    cache: ResultCache,
+    instances: &[Instance],
+    addr: &str,
+    method: &str,
+    params: &Params,
+    cache_key: u64,
+) {
+    let instances = instances.to_vec();
+    let (addr, method, params) = (addr.to_string(), method.to_string(), params.clone());
+    tokio::spawn(async move {
+        let call = forward(
+            &cache, &instances, &addr, &method, &params, cache_key, false,
+        );
+        coalesce(cache_key, call).await;
+    });
+}
+
+/// Send a call to an upstream, retrying or hedging it when cacheable, and cache the result.
+#[instrument(name = "upstream", skip_all, fields(rpc.method = method, instance, rpc.id))]
+async fn forward(
+    cache: &ResultCache,
+    instances: &[Instance],
+    addr: &str,
+    method: &str,
+    params: &Params,
+    cache_key: u64,
+    no_cache: bool,
+) -> R {
+    let select: fn(&[Instance]) -> Option<&Instance> = if no_cache {
+        select_instance
+    } else {
+        select_synced_instance
+    };
+    let queued = Instant::now();
+    let Some((instance, _in_flight)) = acquire_instance(instances, select).await else {
+        let overloaded = instances.iter().any(is_at_capacity);
+        warn!(
+            "{} => {}({:?}) no upstream could take the call",
+            &addr, &method, &params
+        );
+        return unavailable(overloaded);
+    };
+    let queue = queued.elapsed();
+    let dispatched = Instant::now();
+    let (id, response_rx) = match instance.call(method.to_string(), params.clone()).await {
+        Ok(call) => call,
+        Err(e) => {
+            // A closed queue means the connection task of the instance is gone.
+            let overloaded = matches!(e, TrySendError::Full(_));
+            return unavailable(overloaded);
+        }
+    };
+    let _probe = instance.dispatched();
+    info!(
+        "{} => {}, {}({:?}) via WS-{}",
+        &addr, &id, &method, &params, instance.index
+    );
+    let hedge = !no_cache && *HEDGE_DELAY_MS > 0;
+    let (mut instance, mut id, mut reply) = wait_response(
+        instances,
+        (instance, id, response_rx),
+        hedge,
+        addr,
         method,
         params,
+    )
+    .await;
+    // Cacheable methods are idempotent, retry them once should the connection drop.
+    if matches!(reply, Reply::Disconnected) && !no_cache {
+        if let Some((other, _retry_in_flight)) = acquire_other_instance(instances, instance.index) {
+            warn!(
+                "{} <= {}, WS-{} disconnected, retrying via WS-{}",
+                &addr, &id, instance.index, other.index
+            );
+            if let Ok((other_id, other_rx)) = other.call(method.to_string(), params.clone()).await {
+                let _retry_probe = other.dispatched();
+                (instance, id, reply) = wait_response(
+                    instances,
+                    (other, other_id, other_rx),
+                    false,
+                    addr,
+                    method,
+                    params,
+                )
+                .await;
+            }
+        }
+    }
+    let timing = Timing {
+        instance: instance.index,
+        id,
+        queue,
+        upstream: dispatched.elapsed(),
     };
-    ws_tx.send(request).unwrap();
-    match tokio::time::timeout(Duration::from_secs(*RESPONSE_TIMEOUT), response_rx).await {
-        Ok(Ok(rep)) => {
+    record_latency(method, timing.upstream);
+    Span::current()
+        .record("instance", instance.index)
+        .record("rpc.id", id);
+    let r = match reply {
+        Reply::Response(rep) => {
+            let upstream_failure = rep.error.as_ref().is_some_and(is_upstream_failure);
+            instance.record_outcome(rep.result.is_some() || !upstream_failure, false);
             if let Some(result) = rep.result {
-                let r = R::ok(result);
-                if !no_cache {
-                    cache.insert(cache_key, r.clone()).await;
+                if no_cache {
+                    R::ok(result)
+                } else {
+                    let unsettled = unsettled_txid(method, params);
+                    let r = R {
+                        expires_at: Some(std::time::Instant::now() + cache_ttl(method)),
+                        immutable: is_immutable(method, params) && unsettled.is_none(),
+                        ..R::ok(result)
+                    };
+                    cache_insert(cache, cache_key, method, params, r.clone()).await;
+                    if let Some(txid) = unsettled {
+                        settle(cache, instance, txid, cache_key, method, params, &r);
+                    }
+                    r
                 }
-                r
             } else if let Some(err) = rep.error {
-                let err = err.as_object().unwrap();
-                R {
-                    success: false,
-                    code: err.get("code").cloned(),
-                    message: err.get("message").cloned(),
-                    response: None,
-                    health: None,
-                    cache: None,
+                let status = upstream_error_status(method, &err);
+                // Absorb repeated lookups of unknown txids and atomicals for a short while,
+                // never errors caused by what the client sent.
+                let unknown = is_unknown_item(method, &err);
+                let r = match err.as_object() {
+                    Some(err) => R {
+                        code: err.get("code").cloned(),
+                        message: err.get("message").cloned(),
+                        ..R::error(-1, String::new())
+                    },
+                    None => R {
+                        message: Some(err),
+                        ..R::error(-1, String::new())
+                    },
+                };
+                let r = with_error_status(r, status);
+                if unknown && !no_cache && *NEGATIVE_CACHE_TTL > 0 {
+                    let ttl = Duration::from_secs(*NEGATIVE_CACHE_TTL);
+                    let r = R {
+                        expires_at: Some(std::time::Instant::now() + ttl),
+                        ..r
+                    };
+                    cache_insert(cache, cache_key, method, params, r.clone()).await;
+                    r
+                } else {
+                    r
                 }
             } else {
-                R::error(-1, "No response".into())
+                with_error_status(R::error(-1, "No response".into()), StatusCode::BAD_GATEWAY)
             }
         }
-        Ok(Err(_)) | Err(_) => {
+        Reply::Disconnected => {
+            warn!("{} <= {}, WS-{} disconnected", &addr, &id, instance.index);
+            let r = R::error(-1, "Upstream disconnected".into());
+            with_error_status(r, StatusCode::BAD_GATEWAY)
+        }
+        Reply::Timeout => {
             warn!(
                 "{} <= {}, No response received within {} seconds",
                 &addr, &id, *RESPONSE_TIMEOUT
             );
+            instance.record_outcome(false, true);
             {
-                callbacks.write().await.remove(&id);
+                instance.callbacks.write().await.remove(&id);
+            }
+            let r = R::error(-1, "Response timeout".into());
+            with_error_status(r, StatusCode::GATEWAY_TIMEOUT)
+        }
+    };
+    R {
+        timing: Some(timing),
+        ..r
+    }
+}
+
+/// Ask the upstream that returned a raw transaction for its confirmations in the background,
+/// and cache it again as immutable once it is settled.
+fn settle(
+    cache: &ResultCache,
+    instance: &Instance,
+    txid: String,
+    cache_key: u64,
+    method: &str,
+    params: &Params,
+    r: &R,
+) {
+    let (cache, instance) = (cache.clone(), instance.clone());
+    let (method, params, r) = (method.to_string(), params.clone(), r.clone());
+    tokio::spawn(async move {
+        let verbose = vec![Value::String(txid), Value::Bool(true)];
+        let Ok((id, response_rx)) = instance.call(method.clone(), verbose.into()).await else {
+            return;
+        };
+        let timeout = Duration::from_secs(*RESPONSE_TIMEOUT);
+        let confirmations = match tokio::time::timeout(timeout, response_rx).await {
+            Ok(Ok(rep)) => rep
+                .result
+                .and_then(|x| x.get("confirmations").and_then(Value::as_u64)),
+            Ok(Err(_)) | Err(_) => {
+                instance.callbacks.write().await.remove(&id);
+                None
+            }
+        };
+        if confirmations.is_some_and(is_settled) {
+            let r = R {
+                immutable: true,
+                ..r
+            };
+            cache_insert(&cache, cache_key, &method, &params, r).await;
+        }
+    });
+}
+
+/// 503 for a call no upstream could take, counted in `/metrics`.
+pub fn unavailable(overloaded: bool) -> R {
+    let (counter, message) = if overloaded {
+        (&UPSTREAM_OVERLOADED, "Upstream overloaded")
+    } else {
+        (&NO_UPSTREAM, "No upstream available")
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    R::error_with_status(StatusCode::SERVICE_UNAVAILABLE, -1, message.into())
+}
+
+/// Errors of the upstream are answered with 200 unless `HTTP_ERROR_STATUS` is set, which
+/// older clients expect.
+fn with_error_status(r: R, status: StatusCode) -> R {
+    if !*HTTP_ERROR_STATUS {
+        return r;
+    }
+    R {
+        status: Some(status),
+        ..r
+    }
+}
+
+/// Whether `method` passes `ALLOWED_METHODS`, when set, and is not in `BLOCKED_METHODS`.
+pub fn is_method_allowed(method: &str) -> bool {
+    let matches = |pattern: &String| matches_method(pattern, method);
+    (ALLOWED_METHODS.is_empty() || ALLOWED_METHODS.iter().any(matches))
+        && !BLOCKED_METHODS.iter().any(matches)
+}
+
+/// Whether `method` matches a method list entry, where `*` stands for any number of
+/// characters, e.g. `blockchain.atomicals.*` or `blockchain.*.subscribe`.
+pub fn matches_method(pattern: &str, method: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = method.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Select an instance and count the request against its `UPSTREAM_MAX_IN_FLIGHT`. While
+/// every instance is at its cap, wait up to `UPSTREAM_CAPACITY_WAIT_MS` for one to free up.
+async fn acquire_instance(
+    instances: &[Instance],
+    select: fn(&[Instance]) -> Option<&Instance>,
+) -> Option<(&Instance, InFlight)> {
+    let deadline = Instant::now() + Duration::from_millis(*UPSTREAM_CAPACITY_WAIT_MS);
+    loop {
+        match select(instances) {
+            Some(instance) => {
+                if let Some(in_flight) = instance.try_track() {
+                    return Some((instance, in_flight));
+                }
+                // Lost the race for the last slot, let the winner run before selecting again.
+                tokio::task::yield_now().await;
+            }
+            None if !instances.iter().any(is_at_capacity) => return None,
+            None if Instant::now() >= deadline => return None,
+            None => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Like [`acquire_instance`] for a second instance besides `exclude`, without waiting for one
+/// to free up.
+fn acquire_other_instance(instances: &[Instance], exclude: u32) -> Option<(&Instance, InFlight)> {
+    let instance = select_other_instance(instances, exclude)?;
+    Some((instance, instance.try_track()?))
+}
+
+fn is_at_capacity(instance: &Instance) -> bool {
+    instance.is_available() && !instance.has_capacity()
+}
+
+/// Wait for the response to a dispatched request. When `hedge` is set and no response
+/// arrived after `HEDGE_DELAY_MS`, the request is sent to a second instance as well and the
+/// first answer wins, the other callback is cancelled.
+///
+/// Returns the instance whose reply is returned.
+async fn wait_response<'a>(
+    instances: &'a [Instance],
+    (instance, id, response_rx): (&'a Instance, u64, oneshot::Receiver<JsonRpcResponse>),
+    hedge: bool,
+    addr: &str,
+    method: &str,
+    params: &Params,
+) -> (&'a Instance, u64, Reply) {
+    let deadline = Instant::now() + Duration::from_secs(*RESPONSE_TIMEOUT);
+    let primary = tokio::time::timeout_at(deadline, response_rx);
+    tokio::pin!(primary);
+    if hedge {
+        tokio::select! {
+            r = &mut primary => return (instance, id, r.into()),
+            _ = tokio::time::sleep(Duration::from_millis(*HEDGE_DELAY_MS)) => {}
+        }
+        // No hedge while every other instance is at `UPSTREAM_MAX_IN_FLIGHT`.
+        if let Some((other, _in_flight)) = acquire_other_instance(instances, instance.index) {
+            if let Ok((other_id, other_rx)) = other.call(method.into(), params.clone()).await {
+                let _probe = other.dispatched();
+                info!(
+                    "{} => {}, hedged {} via WS-{}",
+                    addr, other_id, method, other.index
+                );
+                let secondary = tokio::time::timeout_at(deadline, other_rx);
+                tokio::pin!(secondary);
+                let (remaining, failed, failure) = tokio::select! {
+                    r = &mut primary => match r {
+                        Ok(Ok(rep)) => return finish((instance, id, rep), (other, other_id)).await,
+                        r => ((other, other_id), (instance, id), Reply::from(r)),
+                    },
+                    r = &mut secondary => match r {
+                        Ok(Ok(rep)) => return finish((other, other_id, rep), (instance, id)).await,
+                        r => ((instance, id), (other, other_id), Reply::from(r)),
+                    },
+                };
+                // The first to finish failed, the other one is all that is left.
+                if let Reply::Timeout = failure {
+                    failed.0.record_outcome(false, true);
+                    failed.0.callbacks.write().await.remove(&failed.1);
+                }
+                let r = if remaining.0.index == instance.index {
+                    primary.await
+                } else {
+                    secondary.await
+                };
+                return (remaining.0, remaining.1, r.into());
             }
-            R::error(-1, "Response timeout".into())
         }
     }
+    (instance, id, primary.await.into())
 }
 
+/// Return the winning response of a hedged request and cancel the callback of the loser.
+async fn finish<'a>(
+    (instance, id, rep): (&'a Instance, u64, JsonRpcResponse),
+    (loser, loser_id): (&Instance, u64),
+) -> (&'a Instance, u64, Reply) {
+    {
+        loser.callbacks.write().await.remove(&loser_id);
+    }
+    (instance, id, Reply::Response(rep))
+}
+
+/// What became of a request sent upstream.
+enum Reply {
+    Response(JsonRpcResponse),
+    /// The connection dropped before the response arrived.
+    Disconnected,
+    Timeout,
+}
+
+impl From<Result<Result<JsonRpcResponse, RecvError>, Elapsed>> for Reply {
+    fn from(value: Result<Result<JsonRpcResponse, RecvError>, Elapsed>) -> Self {
+        match value {
+            Ok(Ok(rep)) => Reply::Response(rep),
+            Ok(Err(_)) => Reply::Disconnected,
+            Err(_) => Reply::Timeout,
+        }
+    }
+}
+
+/// Probe every ws instance with `HEALTH_CHECK_METHOD` concurrently. The proxy is healthy as
+/// long as one of them is.
 async fn handle_health(
-    Extension(callbacks): Extension<Vec<(UnboundedSender<JsonRpcRequest>, Callbacks)>>,
+    Extension(upstreams): Extension<Upstreams>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    let id = get_next_id();
-    let item = random_callback(&callbacks);
+    let instances = upstreams.snapshot();
     let addr = maybe_ip_from_headers(&headers);
-    info!("{} => {}, Detecting server health", &addr, &id);
+    info!("{} => Detecting server health", &addr);
+    let healthy = join_all(instances.iter().map(|x| probe(&addr, x))).await;
+    let consensus = consensus_height(&instances);
+    let upstreams = instances
+        .iter()
+        .zip(&healthy)
+        .map(|(instance, healthy)| {
+            let mut summary = instance.summary(consensus);
+            summary["healthy"] = json!(healthy);
+            summary
+        })
+        .collect();
+    if !healthy.iter().any(|x| *x) {
+        warn!("{} <= No healthy upstream", &addr);
+    }
+    R {
+        upstreams: Some(Value::Array(upstreams)),
+        ..R::health(healthy.into_iter().any(|x| x))
+    }
+}
 
-    let (response_tx, response_rx) = oneshot::channel();
-    {
-        item.1.write().await.insert(id, response_tx);
+/// Liveness probe, answers as long as the process serves requests.
+async fn handle_healthz() -> R {
+    R::health(true)
+}
+
+/// Readiness probe, 503 until at least one upstream is connected and answers
+/// `HEALTH_CHECK_METHOD`, so no traffic is routed to a proxy without upstreams.
+async fn handle_readyz(Extension(upstreams): Extension<Upstreams>, headers: HeaderMap) -> R {
+    let instances = upstreams.snapshot();
+    let addr = maybe_ip_from_headers(&headers);
+    let probes = instances.iter().map(|x| probe(&addr, x));
+    if join_all(probes).await.into_iter().any(|x| x) {
+        return R::health(true);
+    }
+    R {
+        status: Some(StatusCode::SERVICE_UNAVAILABLE),
+        ..R::health(false)
+    }
+}
+
+/// Call `HEALTH_CHECK_METHOD` on one instance and record the round trip as its latency.
+async fn probe(addr: &str, instance: &Instance) -> bool {
+    if !instance.state.is_connected() {
+        return false;
     }
-    let request = JsonRpcRequest {
-        id: Some(id),
-        method: "blockchain.atomicals.get_global".into(),
-        params: vec![],
+    let started = Instant::now();
+    let call = instance.call(HEALTH_CHECK_METHOD.clone(), HEALTH_CHECK_PARAMS.clone());
+    let Ok((id, response_rx)) = call.await else {
+        return false;
     };
-    item.0.send(request).unwrap();
-    match tokio::time::timeout(Duration::from_secs(5), response_rx).await {
-        Ok(Ok(rep)) => R::health(rep.result.is_some()),
+    let timeout = Duration::from_secs(*HEALTH_CHECK_TIMEOUT);
+    match tokio::time::timeout(timeout, response_rx).await {
+        Ok(Ok(rep)) => {
+            instance.state.set_latency(started.elapsed());
+            rep.result.as_ref().is_some_and(is_healthy)
+        }
         Ok(Err(_)) | Err(_) => {
             warn!(
-                "{} <= {}, Check server health timeout, no response received within 5 seconds",
-                &addr, &id
+                "{} <= {}, WS-{} health check timeout, no response received within {} seconds",
+                addr, &id, instance.index, *HEALTH_CHECK_TIMEOUT
             );
             {
-                item.1.write().await.remove(&id);
+                instance.callbacks.write().await.remove(&id);
             }
-            R::health(false)
+            false
         }
     }
 }
 
+/// Runtime statistics for operators, counted since the start of the proxy.
+async fn handle_status(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+) -> R {
+    let instances = upstreams.snapshot();
+    let mut in_flight = vec![];
+    for x in &instances {
+        in_flight.push(json!({
+            "instance": x.index,
+            "in_flight": x.state.in_flight(),
+            "queued": x.queued(),
+            "pending_callbacks": x.pending_callbacks().await,
+        }));
+    }
+    let methods = METHODS.lock().unwrap().clone();
+    R::ok(json!({
+        "uptime": STARTED_AT.elapsed().as_secs(),
+        "requests": REQUESTS.load(Ordering::Relaxed),
+        "cache_hits": CACHE_HITS.load(Ordering::Relaxed),
+        "cache_misses": CACHE_MISSES.load(Ordering::Relaxed),
+        "cache_hit_ratio": cache_hit_ratio(),
+        "cache": cache.stats(),
+        "methods": methods,
+        "in_flight": in_flight,
+        "connections": instances
+            .iter()
+            .map(|x| {
+                let mut summary = x.state.connections.summary();
+                summary["instance"] = json!(x.index);
+                summary
+            })
+            .collect::<Vec<_>>(),
+        "block_height": CACHED_BLOCK_HEIGHT.load(Ordering::SeqCst),
+    }))
+}
+
+/// Build metadata and the protocol versions negotiated with each upstream, to audit a
+/// deployment remotely.
+async fn handle_version(Extension(upstreams): Extension<Upstreams>) -> R {
+    let mut version = json!(*BUILD);
+    version["upstreams"] = upstreams
+        .snapshot()
+        .iter()
+        .map(|x| {
+            let (server, protocol) = x.state.versions();
+            json!({
+                "instance": x.index,
+                "server_version": server,
+                "protocol_version": protocol,
+            })
+        })
+        .collect();
+    R::ok(version)
+}
+
+/// Whether the result of the health check has every one of `HEALTH_CHECK_FIELDS`.
+fn is_healthy(result: &Value) -> bool {
+    HEALTH_CHECK_FIELDS
+        .iter()
+        .all(|x| lookup(result, x).is_some_and(|x| !x.is_null()))
+}
+
+/// Median `estimatefee` and `relayfee` across all upstreams, `blocks` defaults to 6.
+async fn handle_fees(Extension(upstreams): Extension<Upstreams>, Query(query): Query<Value>) -> R {
+    let instances = upstreams.snapshot();
+    let blocks = query
+        .get("blocks")
+        .and_then(|x| x.as_str())
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or(6);
+    let estimate_params = [json!(blocks)];
+    let (estimate, relay) = tokio::join!(
+        median_fee(&instances, FEE_METHODS[0], &estimate_params),
+        median_fee(&instances, FEE_METHODS[1], &[]),
+    );
+    if estimate.is_none() && relay.is_none() {
+        return R::error_with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            -1,
+            "No fee estimate available".into(),
+        );
+    }
+    R::ok(json!({
+        "blocks": blocks,
+        "estimatefee": estimate.map(|x| x.0),
+        "relayfee": relay.map(|x| x.0),
+        "upstreams": estimate.map(|x| x.1).max(relay.map(|x| x.1)),
+    }))
+}
+
+/// gzip and brotli as enabled by `COMPRESSION_ALGORITHMS` for responses of at least
+/// `COMPRESSION_MIN_SIZE` bytes. Images, which are compressed already, and event streams are
+/// left alone, as are responses that carry a `Content-Encoding` of their own. The cache holds
+/// uncompressed results, so every response is compressed exactly once.
+fn compression() -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::new(*COMPRESSION_MIN_SIZE)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE);
+    CompressionLayer::new()
+        .gzip(COMPRESSION_ALGORITHMS.contains("gzip"))
+        .br(COMPRESSION_ALGORITHMS.contains("br"))
+        .compress_when(predicate)
+}
+
 async fn handle_proxy() -> impl IntoResponse {
     Json(PROXY_RESPONSE.clone())
 }
 
+/// Answer requests rejected for exceeding `MAX_BODY_SIZE` with an `R` like every other error,
+/// whether the limit was hit on `Content-Length` or while reading the body.
+async fn limit_body(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE {
+        return response;
+    }
+    let message = format!("Request body exceeds {} bytes", *MAX_BODY_SIZE);
+    R::error_with_status(StatusCode::PAYLOAD_TOO_LARGE, -1, message).into_response()
+}
+
 fn handle_panic(err: Box<dyn Any + Send + 'static>) -> http::Response<Full<Bytes>> {
     let details = if let Some(s) = err.downcast_ref::<String>() {
         s.clone()
@@ -254,31 +1070,54 @@ fn handle_panic(err: Box<dyn Any + Send + 'static>) -> http::Response<Full<Bytes
 
 #[tokio::main]
 async fn main() {
+    LazyLock::force(&PROCESS_ENV);
     dotenv().ok();
-    tracing_subscriber::fmt::init();
-    let governor_conf = Arc::new(
-        GovernorConfigBuilder::default()
-            .per_millisecond(*IP_LIMIT_PER_MILLS)
-            .burst_size(*IP_LIMIT_BURST_SIZE)
-            .key_extractor(SmartIpKeyExtractor)
-            .use_headers()
-            .finish()
-            .unwrap(),
-    );
-    let cache: MokaCache = Cache::builder()
-        .max_capacity(*MAX_CACHE_ENTRIES)
-        .time_to_live(Duration::from_secs(*CACHE_TIME_TO_LIVE))
-        .time_to_idle(Duration::from_secs(*CACHE_TIME_TO_IDLE))
-        .build();
-    let mut calls = vec![];
-    for i in 0..*ELECTRUMX_WS_INSTANCE {
-        let (ws_tx, callbacks, ws_rx_stream) = new_callbacks();
-        calls.push((ws_tx.clone(), callbacks.clone()));
-        try_new_client(i, callbacks, ws_rx_stream, cache.clone());
-    }
+    let telemetry = telemetry::init();
+    LazyLock::force(&STARTED_AT);
+    // Parsed up front, a typo should stop the proxy rather than fail every request.
+    LazyLock::force(&API_KEYS);
+    LazyLock::force(&IP_ALLOWLIST);
+    LazyLock::force(&IP_DENYLIST);
+    LazyLock::force(&TRUSTED_PROXIES);
+    let cache = cache::connect()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to set up CACHE_BACKEND: {}", e));
+    info!("Caching results with {}", *CACHE_BACKEND);
+    cache::bus::start(cache.clone())
+        .await
+        .unwrap_or_else(|e| panic!("Failed to join CACHE_INVALIDATION_BUS: {}", e));
+    cache::disk::open().unwrap_or_else(|e| panic!("Failed to open DISK_CACHE_PATH: {}", e));
+    audit::open().unwrap_or_else(|e| panic!("Failed to open BROADCAST_AUDIT_LOG: {}", e));
+    jwt::load_keys().unwrap_or_else(|e| panic!("Failed to load JWT_PUBLIC_KEY: {}", e));
+    let upstreams = Upstreams::start(cache.clone());
+    // Routes backed by the upstreams, which require an API key or a bearer token while JWTs
+    // are enabled.
+    let upstream_routes = Router::new()
+        .route("/urn/*urn", get(handle_urn))
+        .route("/realm/:name", get(handle_realm))
+        .route("/ticker/:ticker", get(handle_ticker))
+        .route("/container/:name/items", get(handle_container_items))
+        .route("/container/:name/items/:item", get(handle_container_item))
+        .route("/address/:address/:method", get(handle_address_method))
+        .route("/decode/tx", post(handle_decode_tx))
+        .route("/api/tx/:txid", get(handle_tx))
+        .route("/api/tx/:txid/hex", get(handle_tx_hex))
+        .route("/api/address/:address", get(handle_address))
+        .route("/api/address/:address/utxo", get(handle_address_utxo))
+        .route("/api/blocks/tip/height", get(handle_tip_height))
+        .route("/api/v1/fees/recommended", get(handle_fees_recommended))
+        .route("/proxy", get(handle_proxy).post(handle_proxy))
+        .route("/proxy/health", get(handle_health).post(handle_health))
+        .route("/proxy/fees", get(handle_fees))
+        .route("/proxy/batch", post(handle_batch))
+        .route("/rpc", post(handle_rpc))
+        .route("/ws", get(handle_ws))
+        .route("/events", get(handle_events))
+        .route("/proxy/:method", get(handle_get).post(handle_post))
+        .route_layer(middleware::from_fn(require_auth));
     let app = Router::new()
         .fallback(|uri: http::Uri| async move {
-            let body = R::error(-1, format!("No route: {}", &uri));
+            let body = R::error(-1, format!("No route: {}", uri));
             let body = serde_json::to_string(&body).unwrap();
             Response::builder()
                 .status(StatusCode::NOT_FOUND)
@@ -287,33 +1126,109 @@ async fn main() {
                 .unwrap()
         })
         .route("/", get(|| async { "Hello, Atomicals!" }))
-        .route("/urn/*urn", get(handle_urn))
-        .route("/proxy", get(handle_proxy).post(handle_proxy))
-        .route("/proxy/health", get(handle_health).post(handle_health))
-        .route("/proxy/:method", get(handle_get).post(handle_post))
-        .layer(GovernorLayer {
-            config: governor_conf,
-        })
+        .merge(upstream_routes)
+        .route("/metrics", get(handle_metrics))
+        .route("/healthz", get(handle_healthz))
+        .route("/readyz", get(handle_readyz))
+        .route("/status", get(handle_status))
+        .route("/version", get(handle_version))
+        .route(
+            "/admin/upstreams/reload",
+            post(handle_reload_upstreams).route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/upstreams/events",
+            get(handle_upstream_events).route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/log-level",
+            get(handle_log_level)
+                .post(handle_set_log_level)
+                .route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/read-only",
+            post(handle_read_only).route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/broadcast",
+            post(handle_broadcast).route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/blocks",
+            get(handle_blocks)
+                .post(handle_block)
+                .delete(handle_unblock)
+                .route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/broadcasts",
+            get(handle_broadcasts).route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/cache/purge",
+            post(handle_cache_purge).route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/cache/clear",
+            post(handle_cache_clear).route_layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/cache/hottest",
+            get(handle_cache_hottest).route_layer(middleware::from_fn(require_admin)),
+        )
+        .layer(middleware::from_fn(negotiate))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(*MAX_BODY_SIZE))
+        .layer(middleware::from_fn(limit_body))
+        .layer(compression())
+        .layer(middleware::from_fn(server_timing))
+        .layer(middleware::from_fn(rate_limit))
+        .layer(middleware::from_fn(authenticate))
+        .layer(middleware::from_fn(filter_ip))
         .layer(ConcurrencyLimitLayer::new(*CONCURRENCY_LIMIT))
         .layer(CatchPanicLayer::custom(handle_panic))
-        .layer(TraceLayer::new_for_http())
+        .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().level(Level::INFO)))
+        .layer(middleware::from_fn(access_log))
+        .layer(middleware::from_fn(api_key_param))
+        .layer(middleware::from_fn(real_ip))
         .layer(CorsLayer::permissive())
-        .layer(Extension(calls.clone()))
+        .layer(Extension(upstreams.clone()))
         .layer(Extension(cache.clone()));
+    if *PEER_DISCOVERY {
+        spawn_discovery(upstreams.clone());
+    }
+    tokio::spawn(log_latencies());
+    tokio::spawn(watch_backlogs(upstreams.clone()));
+    tokio::spawn(watch_outages(upstreams.clone()));
+    tokio::spawn(send_heartbeats(upstreams.clone()));
+    statsd::start(upstreams.clone(), cache.clone())
+        .unwrap_or_else(|e| panic!("Failed to set up METRICS_PUSH: {}", e));
+    #[cfg(unix)]
+    {
+        let upstreams = upstreams.clone();
+        tokio::spawn(async move {
+            let mut hangup = signal(SignalKind::hangup()).unwrap();
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading upstreams");
+                if let Err(e) = upstreams.reload() {
+                    error!("Failed to reload upstreams: {:?}", e);
+                }
+            }
+        });
+    }
     tokio::spawn(async move {
-        let cloned_calls = calls.clone();
         loop {
-            let callback = random_callback(&cloned_calls);
             let r = handle_request(
                 cache.clone(),
-                callback.0.clone(),
-                callback.1.clone(),
+                &upstreams.snapshot(),
                 HeaderMap::new(),
                 "blockchain.atomicals.get_global".into(),
                 vec![],
             )
             .await;
             if let Some(v) = r.response {
+                publish_global(&v);
                 if v.is_object() {
                     let height = v
                         .as_object()
@@ -326,14 +1241,10 @@ async fn main() {
                         .unwrap()
                         .as_u64()
                         .unwrap();
-                    if CACHED_BLOCK_HEIGHT.load(Ordering::SeqCst) != height {
-                        CACHED_BLOCK_HEIGHT.store(height, Ordering::SeqCst);
-                        cache.invalidate_all();
-                        info!(
-                            "New block height by loop: {}, invalidate all cache: {} entries",
-                            height,
-                            cache.entry_count()
-                        );
+                    if CACHED_BLOCK_HEIGHT.fetch_max(height, Ordering::SeqCst) < height {
+                        cache.invalidate_on_block().await;
+                        bus::publish_block(height);
+                        info!("New block height by loop: {}, invalidate cache", height);
                     }
                 }
             }
@@ -343,141 +1254,17 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind((*PROXY_HOST).clone())
         .await
         .unwrap();
-    info!("Listening on {}", *PROXY_HOST);
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
-}
-
-fn new_callbacks() -> (
-    UnboundedSender<JsonRpcRequest>,
-    Callbacks,
-    Arc<Mutex<UnboundedReceiverStream<JsonRpcRequest>>>,
-) {
-    let (ws_tx, ws_rx) = mpsc::unbounded_channel::<JsonRpcRequest>();
-    let callbacks: Callbacks = Arc::new(RwLock::new(HashMap::new()));
-    let ws_rx_stream = Arc::new(Mutex::new(UnboundedReceiverStream::new(ws_rx)));
-    (ws_tx, callbacks, ws_rx_stream)
-}
-
-fn try_new_client(
-    ins: u32,
-    callbacks: Callbacks,
-    ws_rx_stream: Arc<Mutex<UnboundedReceiverStream<JsonRpcRequest>>>,
-    cache: MokaCache,
-) {
-    tokio::spawn(async move {
-        let list = ELECTRUMX_WSS.split(',').collect::<Vec<&str>>();
-        info!("WS-{} ElectrumX WSS: {:?}", ins, &list);
-        let mut index = 0;
-        loop {
-            let wss = list.get(index).unwrap();
-            info!("WS-{} Try to connect to ElectrumX: {}", ins, &wss);
-            match connect_async(*wss).await {
-                Ok((ws, _)) => {
-                    info!("WS-{} Connected to ElectrumX: {}", ins, &wss);
-                    let (mut write, mut read) = ws.split();
-                    let subscribe_request = JsonRpcRequest {
-                        id: Some(0),
-                        method: "blockchain.headers.subscribe".into(),
-                        params: vec![],
-                    };
-                    let subscribe_result = write
-                        .send(Message::Text(
-                            serde_json::to_string(&subscribe_request).unwrap(),
-                        ))
-                        .await;
-                    if let Err(e) = subscribe_result {
-                        error!("WS-{} Failed to subscribe: {:?}", ins, e);
-                        continue;
-                    }
-                    let ws_rx_stream = Arc::clone(&ws_rx_stream);
-                    let send_handle = tokio::spawn(async move {
-                        let mut guard = ws_rx_stream.lock().await;
-                        while let Some(message) = guard.next().await {
-                            let request_text = serde_json::to_string(&message).unwrap();
-                            debug!("WS-{} Request sent: {}", ins, &request_text);
-                            if let Err(e) = write.send(Message::Text(request_text)).await {
-                                error!("WS-{} Failed to send message to ElectrumX: {:?}", ins, e);
-                                break;
-                            }
-                        }
-                    });
-                    while let Some(Ok(msg)) = read.next().await {
-                        if msg.is_text() {
-                            if let Ok(text) = msg.to_text() {
-                                debug!("WS-{} Response received: {}", ins, text);
-                                if let Ok(resp) = serde_json::from_str::<JsonRpcResponse>(text) {
-                                    if let Some(callback) = callbacks.write().await.remove(&resp.id)
-                                    {
-                                        info!("WS-{} <= {}, Request matched", ins, &resp.id);
-                                        let _ = callback.send(resp);
-                                    } else if resp.id == 0 {
-                                        info!("WS-{} Ignore response: {}", ins, text);
-                                    } else {
-                                        warn!("WS-{} No matching request found: {}", ins, text);
-                                    }
-                                } else {
-                                    match serde_json::from_str::<JsonRpcRequest>(text) {
-                                        Ok(req) => {
-                                            debug!("WS-{} Remote request received: {}", ins, text);
-                                            if req.method == "blockchain.headers.subscribe" {
-                                                let new_height = req.params.first().map(|v| {
-                                                    if let Some(v) = v.as_object() {
-                                                        if let Some(height) = v.get("height") {
-                                                            return height.as_u64();
-                                                        }
-                                                    }
-                                                    None
-                                                });
-                                                if let Some(Some(height)) = new_height {
-                                                    if CACHED_BLOCK_HEIGHT.load(Ordering::SeqCst)
-                                                        != height
-                                                    {
-                                                        CACHED_BLOCK_HEIGHT
-                                                            .store(height, Ordering::SeqCst);
-                                                        cache.invalidate_all();
-                                                        info!(
-                                    "New block height by subscribe: {}, invalidate all cache: {} entries",
-                                    height,
-                                    cache.entry_count()
-                                );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            error!(
-                                                "WS-{} Failed to parse ws response: {}, {:?}",
-                                                ins, text, e,
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                        } else if msg.is_close() {
-                            warn!("WS-{} Connection closed: {}", ins, &wss);
-                            // Close the send handle to stop the send task.
-                            if !send_handle.is_finished() {
-                                send_handle.abort();
-                            }
-                            break;
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("WS-{} Failed to connect to ElectrumX: {:?}", ins, e);
-                    tokio::time::sleep(Duration::from_secs(3)).await;
-                }
-            }
-            if index >= list.len() - 1 {
-                index = 0;
-            } else {
-                index += 1;
-            }
-        }
-    });
+    if *PROXY_PROTOCOL {
+        info!("Listening on {} with the PROXY protocol", *PROXY_HOST);
+        serve_proxy_protocol(listener, app).await;
+    } else {
+        info!("Listening on {}", *PROXY_HOST);
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    }
+    telemetry.shutdown();
 }
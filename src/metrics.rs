@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::Extension;
+use axum::http::header;
+use axum::response::IntoResponse;
+
+use tracing::{info, warn};
+
+use crate::envs::{LATENCY_LOG_INTERVAL, UPSTREAM_BACKLOG_WARN};
+use crate::statsd::push_latency;
+use crate::structs::ResultCache;
+use crate::upstream::Upstreams;
+
+/// Calls refused because no upstream was connected.
+pub static NO_UPSTREAM: AtomicU64 = AtomicU64::new(0);
+/// Calls refused because every upstream was at capacity or its queue was full.
+pub static UPSTREAM_OVERLOADED: AtomicU64 = AtomicU64::new(0);
+
+pub static STARTED_AT: LazyLock<Instant> = LazyLock::new(Instant::now);
+/// Calls that passed validation, whether answered from the cache or not.
+pub static REQUESTS: AtomicU64 = AtomicU64::new(0);
+pub static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+/// Cacheable calls that had to go upstream.
+pub static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+/// Cached results dropped to make room under `MAX_CACHE_ENTRIES`.
+pub static CACHE_EVICTIONS_SIZE: AtomicU64 = AtomicU64::new(0);
+/// Cached results dropped at the end of their time to live or idle.
+pub static CACHE_EVICTIONS_EXPIRED: AtomicU64 = AtomicU64::new(0);
+/// Calls per method. Methods are chosen by clients, past `MAX_METHODS` distinct ones the
+/// rest is counted as `other`.
+pub static METHODS: LazyLock<Mutex<HashMap<String, u64>>> = LazyLock::new(Default::default);
+
+const MAX_METHODS: usize = 256;
+
+/// Upper bounds in seconds of the buckets of upstream round trips.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Round trips to the upstream of one method.
+#[derive(Clone, Default)]
+pub struct Histogram {
+    /// Calls per bucket of `LATENCY_BUCKETS`, the last one for slower calls.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    /// The calls recorded since `earlier`.
+    fn since(&self, earlier: &Histogram) -> Histogram {
+        let mut buckets = self.buckets;
+        for (x, y) in buckets.iter_mut().zip(earlier.buckets) {
+            *x -= y;
+        }
+        Histogram {
+            buckets,
+            count: self.count - earlier.count,
+            sum: self.sum - earlier.sum,
+        }
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, `None` past the last bucket.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let rank = (self.count as f64 * q).ceil() as u64;
+        let mut seen = 0;
+        for (i, x) in self.buckets.iter().enumerate() {
+            seen += x;
+            if seen >= rank {
+                return LATENCY_BUCKETS.get(i).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Upstream round trips per method, with the same cap on distinct methods as `METHODS`.
+pub static LATENCIES: LazyLock<Mutex<HashMap<String, Histogram>>> = LazyLock::new(Default::default);
+
+/// The name `method` is counted under in a map keyed by method.
+fn method_key<'a, V>(map: &HashMap<String, V>, method: &'a str) -> &'a str {
+    if map.len() < MAX_METHODS || map.contains_key(method) {
+        method
+    } else {
+        "other"
+    }
+}
+
+pub fn record_request(method: &str) {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    let mut methods = METHODS.lock().unwrap();
+    let key = method_key(&methods, method);
+    *methods.entry(key.to_string()).or_default() += 1;
+}
+
+/// Record the round trip of a call to the upstream, answered or not.
+pub fn record_latency(method: &str, duration: Duration) {
+    let secs = duration.as_secs_f64();
+    let mut latencies = LATENCIES.lock().unwrap();
+    let key = method_key(&latencies, method);
+    let histogram = latencies.entry(key.to_string()).or_default();
+    let bucket = LATENCY_BUCKETS
+        .iter()
+        .position(|x| secs <= *x)
+        .unwrap_or(LATENCY_BUCKETS.len());
+    histogram.buckets[bucket] += 1;
+    histogram.count += 1;
+    histogram.sum += secs;
+    drop(latencies);
+    push_latency(key, duration);
+}
+
+/// Check the backlogs of the ws instances every 10 seconds and warn about those beyond
+/// `UPSTREAM_BACKLOG_WARN`, before they eat up the memory.
+pub async fn watch_backlogs(upstreams: Upstreams) {
+    if *UPSTREAM_BACKLOG_WARN == 0 {
+        return;
+    }
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        for x in upstreams.snapshot() {
+            let (queued, pending) = (x.queued(), x.pending_callbacks().await);
+            if queued > *UPSTREAM_BACKLOG_WARN || pending > *UPSTREAM_BACKLOG_WARN {
+                warn!(
+                    "WS-{} Backlog growing: {} queued, {} waiting for a response",
+                    x.index, queued, pending
+                );
+            }
+        }
+    }
+}
+
+/// Log the calls, mean, p50 and p99 of each method every `LATENCY_LOG_INTERVAL`, for the
+/// calls sent upstream in between.
+pub async fn log_latencies() {
+    if *LATENCY_LOG_INTERVAL == 0 {
+        return;
+    }
+    let mut previous = HashMap::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs(*LATENCY_LOG_INTERVAL)).await;
+        let latencies = LATENCIES.lock().unwrap().clone();
+        let mut methods = latencies
+            .iter()
+            .map(|(method, x)| {
+                let earlier = previous.get(method).cloned().unwrap_or_default();
+                (method, x.since(&earlier))
+            })
+            .filter(|(_, x)| x.count > 0)
+            .collect::<Vec<_>>();
+        methods.sort_by(|a, b| b.1.sum.total_cmp(&a.1.sum));
+        for (method, x) in methods {
+            let bound = |q| match x.quantile(q) {
+                Some(x) => format!("<={}s", x),
+                None => format!(">{}s", LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1]),
+            };
+            info!(
+                "Upstream latency of {}: {} calls, mean {:.3}s, p50 {}, p99 {}",
+                method,
+                x.count,
+                x.sum / x.count as f64,
+                bound(0.5),
+                bound(0.99)
+            );
+        }
+        previous = latencies;
+    }
+}
+
+pub fn record_cache(hit: bool) {
+    let counter = if hit { &CACHE_HITS } else { &CACHE_MISSES };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Share of cacheable calls answered from the cache, `None` before the first one.
+pub fn cache_hit_ratio() -> Option<f64> {
+    let hits = CACHE_HITS.load(Ordering::Relaxed);
+    let total = hits + CACHE_MISSES.load(Ordering::Relaxed);
+    (total > 0).then(|| hits as f64 / total as f64)
+}
+
+/// `GET /metrics` in the Prometheus text format.
+pub async fn handle_metrics(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+) -> impl IntoResponse {
+    let mut text = String::new();
+    counter(
+        &mut text,
+        "elex_proxy_upstream_unavailable_total",
+        "Calls answered with 503 because no upstream could take them.",
+        &[
+            ("reason=\"no_upstream\"", &NO_UPSTREAM),
+            ("reason=\"overloaded\"", &UPSTREAM_OVERLOADED),
+        ],
+    );
+    counter(
+        &mut text,
+        "elex_proxy_cache_requests_total",
+        "Cacheable calls by whether they were answered from the cache.",
+        &[
+            ("result=\"hit\"", &CACHE_HITS),
+            ("result=\"miss\"", &CACHE_MISSES),
+        ],
+    );
+    counter(
+        &mut text,
+        "elex_proxy_cache_evictions_total",
+        "Cached results dropped before being purged or replaced.",
+        &[
+            ("cause=\"size\"", &CACHE_EVICTIONS_SIZE),
+            ("cause=\"expired\"", &CACHE_EVICTIONS_EXPIRED),
+        ],
+    );
+    if let Some(size) = cache.size() {
+        gauge(
+            &mut text,
+            "elex_proxy_cache_entries",
+            "Results cached in memory.",
+            size.entries,
+        );
+        gauge(
+            &mut text,
+            "elex_proxy_cache_weighted_size",
+            "Total weight of the results cached in memory.",
+            size.weighted_size,
+        );
+    }
+    let instances = upstreams.snapshot();
+    let connections = instances
+        .iter()
+        .flat_map(|x| {
+            x.state.connections.counters().map(move |(event, value)| {
+                let labels = format!("instance=\"{}\",event=\"{}\"", x.index, event.name());
+                (labels, value)
+            })
+        })
+        .collect::<Vec<_>>();
+    counter(
+        &mut text,
+        "elex_proxy_upstream_connection_events_total",
+        "Connection attempts, connects, disconnects and failures by ws instance.",
+        &connections
+            .iter()
+            .map(|(labels, value)| (labels.as_str(), *value))
+            .collect::<Vec<_>>(),
+    );
+    let mut backlogs = vec![];
+    for x in &instances {
+        let labels = format!("instance=\"{}\"", x.index);
+        backlogs.push((
+            labels,
+            x.queued(),
+            x.pending_callbacks().await,
+            x.state.in_flight(),
+        ));
+    }
+    gauges(
+        &mut text,
+        "elex_proxy_upstream_queue_depth",
+        "Requests waiting in the queue of a ws instance.",
+        backlogs.iter().map(|x| (x.0.as_str(), x.1 as u64)),
+    );
+    gauges(
+        &mut text,
+        "elex_proxy_upstream_pending_callbacks",
+        "Requests sent by a ws instance and waiting for their response.",
+        backlogs.iter().map(|x| (x.0.as_str(), x.2 as u64)),
+    );
+    gauges(
+        &mut text,
+        "elex_proxy_upstream_in_flight",
+        "Outstanding calls on a ws instance.",
+        backlogs.iter().map(|x| (x.0.as_str(), x.3 as u64)),
+    );
+    histograms(
+        &mut text,
+        "elex_proxy_upstream_latency_seconds",
+        "Round trip of calls to the upstream by method.",
+        &LATENCIES.lock().unwrap(),
+    );
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text)
+}
+
+fn counter(text: &mut String, name: &str, help: &str, values: &[(&str, &AtomicU64)]) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} counter", name);
+    for (labels, value) in values {
+        let _ = writeln!(
+            text,
+            "{}{{{}}} {}",
+            name,
+            labels,
+            value.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn gauge(text: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} gauge", name);
+    let _ = writeln!(text, "{} {}", name, value);
+}
+
+fn gauges<'a>(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} gauge", name);
+    for (labels, value) in values {
+        let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn histograms(text: &mut String, name: &str, help: &str, values: &HashMap<String, Histogram>) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} histogram", name);
+    let mut methods = values.iter().collect::<Vec<_>>();
+    methods.sort_by_key(|(method, _)| *method);
+    for (method, x) in methods {
+        let method = escape_label(method);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(x.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                text,
+                "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                name, method, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            text,
+            "{}_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+            name, method, x.count
+        );
+        let _ = writeln!(text, "{}_sum{{method=\"{}\"}} {}", name, method, x.sum);
+        let _ = writeln!(text, "{}_count{{method=\"{}\"}} {}", name, method, x.count);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
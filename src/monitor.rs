@@ -0,0 +1,85 @@
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use axum::http::header::CONTENT_TYPE;
+use futures::future::join_all;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::envs::{ALERT_SOURCE, ALERT_WEBHOOK_URL, HEARTBEAT_INTERVAL, HEARTBEAT_URL};
+use crate::probe;
+use crate::upstream::{now_millis, Upstreams};
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+});
+
+/// Check the upstreams every 5 seconds and post to `ALERT_WEBHOOK_URL` when the last
+/// connected one is lost, and again once one is back with the length of the outage.
+pub async fn watch_outages(upstreams: Upstreams) {
+    let Some(url) = ALERT_WEBHOOK_URL.as_deref() else {
+        return;
+    };
+    let mut down_since: Option<Instant> = None;
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let instances = upstreams.snapshot();
+        let connected = instances.iter().any(|x| x.state.is_connected());
+        match (connected, down_since) {
+            (false, None) => {
+                down_since = Some(Instant::now());
+                warn!("No upstream connected, alerting");
+                let text = format!("{}: no upstream connected", *ALERT_SOURCE);
+                post(url, json!({"event": "down", "text": text})).await;
+            }
+            (true, Some(since)) => {
+                down_since = None;
+                let downtime = since.elapsed().as_secs();
+                info!("Upstreams back after {}s, alerting", downtime);
+                let text = format!("{}: upstreams back after {}s", *ALERT_SOURCE, downtime);
+                let body = json!({"event": "up", "text": text, "downtime_secs": downtime});
+                post(url, body).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// GET `HEARTBEAT_URL` every `HEARTBEAT_INTERVAL` seconds as long as an upstream answers
+/// `HEALTH_CHECK_METHOD`, a dead man's switch monitor pages once the pings stop.
+pub async fn send_heartbeats(upstreams: Upstreams) {
+    let Some(url) = HEARTBEAT_URL.as_deref() else {
+        return;
+    };
+    let interval = Duration::from_secs(*HEARTBEAT_INTERVAL);
+    loop {
+        tokio::time::sleep(interval).await;
+        let instances = upstreams.snapshot();
+        let probes = instances.iter().map(|x| probe("heartbeat", x));
+        if !join_all(probes).await.into_iter().any(|x| x) {
+            debug!("No healthy upstream, heartbeat skipped");
+            continue;
+        }
+        let request = CLIENT.get(url).send();
+        if let Err(e) = request.await.and_then(|x| x.error_for_status()) {
+            warn!("Failed to send heartbeat to HEARTBEAT_URL: {}", e);
+        }
+    }
+}
+
+/// Post an alert, `text` makes it readable as is by Slack-compatible webhooks.
+async fn post(url: &str, mut body: Value) {
+    body["source"] = json!(*ALERT_SOURCE);
+    body["at"] = json!(now_millis());
+    let request = CLIENT
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    match request.send().await.and_then(|x| x.error_for_status()) {
+        Ok(_) => {}
+        Err(e) => warn!("Failed to post alert to ALERT_WEBHOOK_URL: {}", e),
+    }
+}
@@ -0,0 +1,98 @@
+use serde_json::{Map, Value};
+
+use crate::structs::R;
+
+/// Keep only the `?fields=` of a result, a comma separated list of dotted paths such as
+/// `global.height` or JSON Pointers such as `/global/height`. The fields keep their place in
+/// the result, missing ones are left out.
+pub fn project_fields(r: R, query: &Value) -> R {
+    let Some(fields) = query.get("fields").and_then(|x| x.as_str()) else {
+        return r;
+    };
+    R {
+        response: r.response.map(|x| project(&x, fields)),
+        ..r
+    }
+}
+
+fn project(value: &Value, fields: &str) -> Value {
+    let mut projected = Value::Object(Map::new());
+    for field in fields.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        if let Some(x) = lookup(value, field) {
+            insert(&mut projected, &to_path(field), x.clone());
+        }
+    }
+    projected
+}
+
+/// The value at a dotted path or JSON Pointer, see [`project_fields`].
+pub fn lookup<'a>(value: &'a Value, field: &str) -> Option<&'a Value> {
+    let pointer = to_path(field).iter().fold(String::new(), |pointer, x| {
+        pointer + "/" + &x.replace('~', "~0").replace('/', "~1")
+    });
+    value.pointer(&pointer)
+}
+
+fn to_path(field: &str) -> Vec<String> {
+    match field.strip_prefix('/') {
+        Some(pointer) => pointer
+            .split('/')
+            .map(|x| x.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None => field.split('.').map(str::to_string).collect(),
+    }
+}
+
+fn insert(target: &mut Value, path: &[String], value: Value) {
+    let Some((key, rest)) = path.split_first() else {
+        *target = value;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(map) = target else {
+        return;
+    };
+    let child = map.entry(key.clone()).or_insert(Value::Null);
+    insert(child, rest, value);
+}
+
+/// Slice a list result by `?offset=` and `?limit=`, setting `total` to its whole length.
+/// Lists are either the result itself or its `result` field, as with the Atomicals list
+/// methods. The whole list stays cached, each page is cut from it.
+pub fn paginate(r: R, query: &Value) -> R {
+    let number = |key| {
+        query
+            .get(key)
+            .and_then(|x| x.as_str())
+            .and_then(|x| x.parse::<usize>().ok())
+    };
+    let (offset, limit) = (number("offset"), number("limit"));
+    if offset.is_none() && limit.is_none() {
+        return r;
+    }
+    let Some(mut response) = r.response else {
+        return r;
+    };
+    let list = match &mut response {
+        Value::Array(list) => Some(list),
+        Value::Object(x) => x.get_mut("result").and_then(|x| x.as_array_mut()),
+        _ => None,
+    };
+    let Some(list) = list else {
+        return R {
+            response: Some(response),
+            ..r
+        };
+    };
+    let total = list.len();
+    let offset = offset.unwrap_or_default().min(total);
+    let end = limit.map_or(total, |x| offset.saturating_add(x).min(total));
+    *list = list.drain(offset..end).collect();
+    R {
+        response: Some(response),
+        total: Some(total),
+        ..r
+    }
+}
@@ -1,6 +1,9 @@
 use std::sync::LazyLock;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::envs::PROXY_INFO_FILE;
 
 #[derive(Serialize, Deserialize, Clone)]
 struct UsageInfo {
@@ -23,9 +26,11 @@ struct Info {
     build: Build,
 }
 
+/// Build metadata embedded at compile time by `build.rs`.
 #[derive(Serialize, Deserialize, Clone)]
-struct Build {
+pub struct Build {
     version: String,
+    commit: String,
     #[serde(rename = "buildAt")]
     timestamp: String,
     #[serde(rename = "target")]
@@ -40,7 +45,38 @@ pub struct Response {
     info: Info,
 }
 
-pub static PROXY_RESPONSE: LazyLock<Response> = LazyLock::new(|| {
+/// The document served at `/` and `/proxy`, `info` extended by the JSON object in
+/// `PROXY_INFO_FILE` if set.
+pub static PROXY_RESPONSE: LazyLock<Value> = LazyLock::new(|| {
+    let mut response = serde_json::to_value(default_response()).unwrap();
+    if let Some(path) = PROXY_INFO_FILE.as_deref() {
+        let text = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to read PROXY_INFO_FILE {}: {}", path, e));
+        let info: Value = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid JSON in PROXY_INFO_FILE {}: {}", path, e));
+        merge(&mut response["info"], info);
+    }
+    response
+});
+
+/// Merge `patch` into `target`: objects are merged key by key, `null` removes a key and any
+/// other value replaces the existing one.
+fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(&key);
+                } else {
+                    merge(target.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
+
+fn default_response() -> Response {
     Response {
     success: true,
     info: Info {
@@ -53,12 +89,15 @@ pub static PROXY_RESPONSE: LazyLock<Response> = LazyLock::new(|| {
         health_check: "GET /proxy/health".to_string(),
         github: "https://github.com/WizzWallet/elex-proxy".to_string(),
         license: "MIT".to_string(),
-        build: Build {
-            version: env!("CARGO_PKG_VERSION").to_string(),
-            timestamp: env!("VERGEN_BUILD_TIMESTAMP").to_string(),
-            target: env!("VERGEN_CARGO_TARGET_TRIPLE").to_string(),
-            rustc: env!("VERGEN_RUSTC_SEMVER").to_string(),
-        },
+        build: BUILD.clone(),
     },
 }
+}
+
+pub static BUILD: LazyLock<Build> = LazyLock::new(|| Build {
+    version: env!("CARGO_PKG_VERSION").to_string(),
+    commit: env!("GIT_COMMIT").to_string(),
+    timestamp: env!("VERGEN_BUILD_TIMESTAMP").to_string(),
+    target: env!("VERGEN_CARGO_TARGET_TRIPLE").to_string(),
+    rustc: env!("VERGEN_RUSTC_SEMVER").to_string(),
 });
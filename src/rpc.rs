@@ -0,0 +1,239 @@
+use axum::body::Bytes;
+use axum::extract::Extension;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::future::join_all;
+use serde_json::{json, Value};
+
+use crate::envs::MAX_BATCH_SIZE;
+use crate::handle_request;
+use crate::limit::Charge;
+use crate::structs::{Params, ResultCache, R};
+use crate::upstream::{Instance, Upstreams};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+pub const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// In the range left to servers, as Ethereum nodes use it for rate limits.
+pub const LIMIT_EXCEEDED: i64 = -32005;
+
+/// JSON-RPC 2.0 endpoint for Electrum client libraries, single calls and batches.
+///
+/// Calls go through the same cache and routing as `/proxy/:method`. Error codes of ElectrumX
+/// are passed through, errors of the proxy itself are reported as internal errors. Every call
+/// of a batch counts against the rate limit.
+pub async fn handle_rpc(
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    Extension(charge): Extension<Charge>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let response = match serde_json::from_slice::<Value>(&body) {
+        Ok(request) => {
+            // The request itself was charged for one call.
+            if let Err(refused) = charge.take(count_calls(&request).saturating_sub(1)) {
+                return refused.into_response();
+            }
+            dispatch(&cache, &upstreams.snapshot(), &headers, request).await
+        }
+        Err(e) => Some(parse_error(e)),
+    };
+    match response {
+        Some(response) => Json(response).into_response(),
+        // Nothing but notifications.
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// Run a single call or a batch, `None` when there is nothing to respond with.
+pub async fn dispatch(
+    cache: &ResultCache,
+    instances: &[Instance],
+    headers: &HeaderMap,
+    request: Value,
+) -> Option<Value> {
+    match request {
+        Value::Array(calls) if calls.is_empty() => {
+            Some(error(Value::Null, INVALID_REQUEST, "Empty batch".into()))
+        }
+        Value::Array(calls) if calls.len() > *MAX_BATCH_SIZE => {
+            let message = format!("Batch exceeds {} calls", *MAX_BATCH_SIZE);
+            Some(error(Value::Null, INVALID_REQUEST, message))
+        }
+        Value::Array(calls) => {
+            let responses = join_all(calls.iter().map(|x| call(cache, instances, headers, x)))
+                .await
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        request => call(cache, instances, headers, &request).await,
+    }
+}
+
+/// Calls in a request, those of a batch over `MAX_BATCH_SIZE` are refused without running.
+pub fn count_calls(request: &Value) -> usize {
+    match request {
+        Value::Array(calls) if calls.len() <= *MAX_BATCH_SIZE => calls.len(),
+        _ => 1,
+    }
+}
+
+pub fn parse_error(e: serde_json::Error) -> Value {
+    error(Value::Null, PARSE_ERROR, e.to_string())
+}
+
+/// Run one call, `None` for a notification, a call without `id`.
+async fn call(
+    cache: &ResultCache,
+    instances: &[Instance],
+    headers: &HeaderMap,
+    call: &Value,
+) -> Option<Value> {
+    let id = call.get("id").cloned();
+    let method = call.get("method").and_then(|x| x.as_str());
+    let (Some("2.0"), Some(method)) = (call.get("jsonrpc").and_then(|x| x.as_str()), method) else {
+        let id = id.unwrap_or_default();
+        return Some(error(id, INVALID_REQUEST, "Invalid Request".into()));
+    };
+    let params = match call.get("params") {
+        None => vec![].into(),
+        Some(Value::Array(params)) => Params::Positional(params.clone()),
+        Some(Value::Object(params)) => Params::Named(params.clone()),
+        Some(_) => {
+            let message = "Params must be an array or an object".to_string();
+            return id.map(|id| error(id, INVALID_PARAMS, message));
+        }
+    };
+    let r = handle_request(
+        cache.clone(),
+        instances,
+        headers.clone(),
+        method.into(),
+        params,
+    )
+    .await;
+    Some(to_response(id?, r))
+}
+
+/// Turn the result of a call into a JSON-RPC 2.0 response with the given id.
+pub fn to_response(id: Value, r: R) -> Value {
+    if let Some(result) = r.response {
+        return json!({ "jsonrpc": "2.0", "id": id, "result": result });
+    }
+    let code = r
+        .code
+        .and_then(|x| x.as_i64())
+        .filter(|x| *x != -1)
+        .unwrap_or(INTERNAL_ERROR);
+    let message = match r.message {
+        Some(Value::String(message)) => message,
+        Some(message) => message.to_string(),
+        None => "Internal error".into(),
+    };
+    error(id, code, message)
+}
+
+pub fn error(id: Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cache::NoopBackend;
+
+    use super::*;
+
+    async fn run(request: Value) -> Option<Value> {
+        let cache: ResultCache = Arc::new(NoopBackend);
+        dispatch(&cache, &[], &HeaderMap::new(), request).await
+    }
+
+    fn code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_requests() {
+        let response = run(json!({"id": 1, "method": "server.ping"}))
+            .await
+            .unwrap();
+        assert_eq!(
+            (code(&response), &response["id"]),
+            (INVALID_REQUEST, &json!(1))
+        );
+        let response = run(json!({"jsonrpc": "2.0", "id": 2})).await.unwrap();
+        assert_eq!(code(&response), INVALID_REQUEST);
+        let response = run(json!(42)).await.unwrap();
+        assert_eq!(
+            (code(&response), &response["id"]),
+            (INVALID_REQUEST, &Value::Null)
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_params() {
+        let call = json!({"jsonrpc": "2.0", "id": "a", "method": "server.ping", "params": 1});
+        let response = run(call).await.unwrap();
+        assert_eq!(
+            (code(&response), &response["id"]),
+            (INVALID_PARAMS, &json!("a"))
+        );
+        let notification = json!({"jsonrpc": "2.0", "method": "server.ping", "params": 1});
+        assert_eq!(run(notification).await, None);
+    }
+
+    #[tokio::test]
+    async fn answers_batches_without_notifications() {
+        assert_eq!(code(&run(json!([])).await.unwrap()), INVALID_REQUEST);
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "server.ping", "params": "x"},
+            {"jsonrpc": "2.0", "method": "server.ping", "params": "x"},
+            {"id": 3},
+        ]);
+        let responses = run(batch).await.unwrap();
+        let ids = responses
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![json!(1), json!(3)]);
+        let notifications = json!([{"jsonrpc": "2.0", "method": "server.ping", "params": 1}]);
+        assert_eq!(run(notifications).await, None);
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_batches() {
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "server.ping"});
+        let batch = Value::Array(vec![call; *MAX_BATCH_SIZE + 1]);
+        assert_eq!(code(&run(batch.clone()).await.unwrap()), INVALID_REQUEST);
+        assert_eq!(count_calls(&batch), 1);
+    }
+
+    #[test]
+    fn counts_calls() {
+        assert_eq!(count_calls(&json!({"jsonrpc": "2.0"})), 1);
+        assert_eq!(count_calls(&json!([{}, {}, {}])), 3);
+    }
+
+    #[test]
+    fn converts_results() {
+        let response = to_response(json!(7), R::ok(json!("pong")));
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "id": 7, "result": "pong"})
+        );
+        let upstream = to_response(json!(7), R::error(-32601, "unknown method".into()));
+        assert_eq!(code(&upstream), -32601);
+        assert_eq!(upstream["error"]["message"], "unknown method");
+        let proxy = to_response(json!(7), R::error(-1, "No upstream".into()));
+        assert_eq!(code(&proxy), INTERNAL_ERROR);
+    }
+}
@@ -0,0 +1,36 @@
+use std::convert::Infallible;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{stream, Stream};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::{subscribe_global, subscribe_headers};
+
+/// Server-Sent Events for web frontends that do not want to speak WebSocket: a `block` event
+/// with `{height, hex}` for every new block, and a `global` event with the result of
+/// `blockchain.atomicals.get_global` once the Atomicals indexer reports a new height.
+pub async fn handle_events() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let blocks = to_events(subscribe_headers(), "block");
+    let global = to_events(subscribe_global(), "global");
+    Sse::new(stream::select(blocks, global)).keep_alive(KeepAlive::default())
+}
+
+fn to_events(
+    rx: broadcast::Receiver<Value>,
+    name: &'static str,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(rx, move |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(value) => {
+                    let event = Event::default().event(name).data(value.to_string());
+                    return Some((Ok(event), rx));
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::bail;
+
+use cadence::prelude::*;
+use cadence::{BufferedUdpMetricSink, Metric, MetricBuilder, QueuingMetricSink, StatsdClient};
+use tracing::{info, warn};
+
+use crate::envs::{METRICS_PUSH, STATSD_HOST, STATSD_INTERVAL, STATSD_PREFIX};
+use crate::metrics::{
+    CACHE_EVICTIONS_EXPIRED, CACHE_EVICTIONS_SIZE, CACHE_HITS, CACHE_MISSES, NO_UPSTREAM,
+    UPSTREAM_OVERLOADED,
+};
+use crate::structs::ResultCache;
+use crate::upstream::Upstreams;
+
+/// The client pushing to `STATSD_HOST`, set by [`start`] unless `METRICS_PUSH` is `none`.
+static CLIENT: OnceLock<StatsdClient> = OnceLock::new();
+
+/// Push metrics to `STATSD_HOST` as selected by `METRICS_PUSH`, for deployments without a
+/// Prometheus scraping `/metrics`.
+pub fn start(upstreams: Upstreams, cache: ResultCache) -> anyhow::Result<()> {
+    match METRICS_PUSH.as_str() {
+        "none" => return Ok(()),
+        "statsd" | "dogstatsd" => {}
+        x => bail!(
+            "Unknown METRICS_PUSH {}, expected none, statsd or dogstatsd",
+            x
+        ),
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_nonblocking(true)?;
+    let sink = BufferedUdpMetricSink::from(STATSD_HOST.as_str(), socket)?;
+    let client = StatsdClient::builder(&STATSD_PREFIX, QueuingMetricSink::from(sink))
+        .with_error_handler(|e| warn!("Failed to push metrics: {}", e))
+        .build();
+    let _ = CLIENT.set(client);
+    info!("Pushing metrics to {} as {}", *STATSD_HOST, *METRICS_PUSH);
+    tokio::spawn(push_metrics(upstreams, cache));
+    Ok(())
+}
+
+/// Whether labels go out as DogStatsD tags. Plain StatsD has none, their values are appended
+/// to the name instead.
+fn has_tags() -> bool {
+    *METRICS_PUSH == "dogstatsd"
+}
+
+fn key(name: &str, labels: &[(&str, String)]) -> String {
+    if has_tags() {
+        return name.to_string();
+    }
+    let mut key = name.to_string();
+    for (_, value) in labels {
+        key.push('.');
+        key.extend(value.chars().map(|x| match x {
+            '.' | ':' | '|' | '@' | '#' | ' ' => '_',
+            x => x,
+        }));
+    }
+    key
+}
+
+fn send<'m, T: Metric + From<String>>(
+    mut builder: MetricBuilder<'m, '_, T>,
+    labels: &'m [(&str, String)],
+) {
+    if has_tags() {
+        for (name, value) in labels {
+            builder = builder.with_tag(name, value);
+        }
+    }
+    builder.send();
+}
+
+/// Push a round trip to the upstream as a timer, the counterpart of the latency histogram.
+pub fn push_latency(method: &str, duration: Duration) {
+    let Some(client) = CLIENT.get() else {
+        return;
+    };
+    let labels = [("method", method.to_string())];
+    let key = key("upstream_latency", &labels);
+    send(
+        client.time_with_tags(&key, duration.as_millis() as u64),
+        &labels,
+    );
+}
+
+/// Push the counters of `/metrics` every `STATSD_INTERVAL` seconds, as the increase since
+/// the last push, and its gauges as they are.
+async fn push_metrics(upstreams: Upstreams, cache: ResultCache) {
+    let Some(client) = CLIENT.get() else {
+        return;
+    };
+    let mut previous = HashMap::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs((*STATSD_INTERVAL).max(1))).await;
+        let mut count = |name: &str, labels: &[(&str, String)], value: &AtomicU64| {
+            let value = value.load(Ordering::Relaxed);
+            let key = key(name, labels);
+            let last = previous.insert(format!("{}{:?}", key, labels), value);
+            let delta = value.saturating_sub(last.unwrap_or_default());
+            if delta > 0 {
+                send(client.count_with_tags(&key, delta as i64), labels);
+            }
+        };
+        count(
+            "upstream_unavailable",
+            &[("reason", "no_upstream".to_string())],
+            &NO_UPSTREAM,
+        );
+        count(
+            "upstream_unavailable",
+            &[("reason", "overloaded".to_string())],
+            &UPSTREAM_OVERLOADED,
+        );
+        count(
+            "cache_requests",
+            &[("result", "hit".to_string())],
+            &CACHE_HITS,
+        );
+        count(
+            "cache_requests",
+            &[("result", "miss".to_string())],
+            &CACHE_MISSES,
+        );
+        count(
+            "cache_evictions",
+            &[("cause", "size".to_string())],
+            &CACHE_EVICTIONS_SIZE,
+        );
+        count(
+            "cache_evictions",
+            &[("cause", "expired".to_string())],
+            &CACHE_EVICTIONS_EXPIRED,
+        );
+        let instances = upstreams.snapshot();
+        for x in &instances {
+            for (event, value) in x.state.connections.counters() {
+                let labels = [
+                    ("instance", x.index.to_string()),
+                    ("event", event.name().to_string()),
+                ];
+                count("upstream_connection_events", &labels, value);
+            }
+        }
+        let gauge = |name: &str, labels: &[(&str, String)], value: u64| {
+            send(client.gauge_with_tags(&key(name, labels), value), labels);
+        };
+        if let Some(size) = cache.size() {
+            gauge("cache_entries", &[], size.entries);
+            gauge("cache_weighted_size", &[], size.weighted_size);
+        }
+        for x in &instances {
+            let labels = [("instance", x.index.to_string())];
+            gauge("upstream_queue_depth", &labels, x.queued() as u64);
+            let pending = x.pending_callbacks().await as u64;
+            gauge("upstream_pending_callbacks", &labels, pending);
+            gauge("upstream_in_flight", &labels, x.state.in_flight() as u64);
+        }
+    }
+}
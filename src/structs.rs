@@ -1,32 +1,71 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::Arc;
+use std::time::Instant;
 
 use axum::body::Body;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
-use moka::future::Cache;
 use serde::{Deserialize, Serialize};
-use serde_json::{Number, Value};
+use serde_json::{Map, Number, Value};
 use tokio::sync::{oneshot, RwLock};
 
-pub type MokaCache = Cache<u64, R>;
+use crate::cache::CacheBackend;
+use crate::diagnostics::{insert_diagnostics, Timing};
+
+/// The cache of results, shared by all handlers.
+pub type ResultCache = Arc<dyn CacheBackend>;
 
 #[derive(Serialize, Deserialize)]
 pub struct JsonRpcRequest {
     pub method: String,
-    pub params: Vec<Value>,
-    pub id: Option<u32>,
+    pub params: Params,
+    pub id: Option<u64>,
+}
+
+/// Params of a JSON-RPC call, positional as most ElectrumX methods take them or named, passed
+/// through to the upstream as they are.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Params {
+    Positional(Vec<Value>),
+    Named(Map<String, Value>),
+}
+
+impl Params {
+    /// The positional params, empty for named ones.
+    pub fn positional(&self) -> &[Value] {
+        match self {
+            Params::Positional(params) => params,
+            Params::Named(_) => &[],
+        }
+    }
+}
+
+impl From<Vec<Value>> for Params {
+    fn from(params: Vec<Value>) -> Self {
+        Params::Positional(params)
+    }
+}
+
+impl fmt::Debug for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Params::Positional(params) => params.fmt(f),
+            Params::Named(params) => params.fmt(f),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct JsonRpcResponse {
     pub result: Option<Value>,
     pub error: Option<Value>,
-    pub id: u32,
+    pub id: u64,
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct R {
     pub success: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -39,6 +78,28 @@ pub struct R {
     pub health: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cache: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstreams: Option<Value>,
+    /// Length of the whole list when the response is a page of it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    /// Seconds a result served from the cache is past its time to live.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staleness: Option<u64>,
+    #[serde(skip)]
+    pub status: Option<StatusCode>,
+    /// When the cached result expires, `None` for results that are not cached.
+    #[serde(skip)]
+    pub expires_at: Option<Instant>,
+    /// Whether the cached result stays valid across blocks, see [`crate::cache::is_immutable`].
+    #[serde(skip)]
+    pub immutable: bool,
+    /// Whether the cached result is kept when a new block arrives although it may change, see
+    /// [`crate::cache::is_height_sensitive`].
+    #[serde(skip)]
+    pub block_stable: bool,
+    #[serde(skip)]
+    pub timing: Option<Timing>,
 }
 
 impl R {
@@ -50,6 +111,14 @@ impl R {
             message: None,
             health: None,
             cache: None,
+            upstreams: None,
+            total: None,
+            staleness: None,
+            status: None,
+            expires_at: None,
+            immutable: false,
+            block_stable: false,
+            timing: None,
         }
     }
     pub fn error(code: i32, message: String) -> Self {
@@ -60,6 +129,14 @@ impl R {
             message: Some(Value::String(message)),
             health: None,
             cache: None,
+            upstreams: None,
+            total: None,
+            staleness: None,
+            status: None,
+            expires_at: None,
+            immutable: false,
+            block_stable: false,
+            timing: None,
         }
     }
     pub fn health(health: bool) -> Self {
@@ -70,11 +147,25 @@ impl R {
             message: None,
             health: Some(health),
             cache: None,
+            upstreams: None,
+            total: None,
+            staleness: None,
+            status: None,
+            expires_at: None,
+            immutable: false,
+            block_stable: false,
+            timing: None,
+        }
+    }
+    pub fn error_with_status(status: StatusCode, code: i32, message: String) -> Self {
+        Self {
+            status: Some(status),
+            ..Self::error(code, message)
         }
     }
 }
 
-pub type Callbacks = Arc<RwLock<HashMap<u32, oneshot::Sender<JsonRpcResponse>>>>;
+pub type Callbacks = Arc<RwLock<HashMap<u64, oneshot::Sender<JsonRpcResponse>>>>;
 
 pub struct AppError(anyhow::Error);
 
@@ -90,6 +181,8 @@ impl IntoResponse for AppError {
 
 impl IntoResponse for R {
     fn into_response(self) -> Response {
-        Json(self).into_response()
+        let mut response = (self.status.unwrap_or(StatusCode::OK), Json(&self)).into_response();
+        insert_diagnostics(&self, response.headers_mut());
+        response
     }
 }
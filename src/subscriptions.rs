@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{debug, info};
+
+use crate::envs::RESPONSE_TIMEOUT;
+use crate::structs::R;
+use crate::unavailable;
+use crate::upstream::{select_instance, Instance};
+
+pub const SCRIPTHASH_SUBSCRIBE: &str = "blockchain.scripthash.subscribe";
+pub const SCRIPTHASH_UNSUBSCRIBE: &str = "blockchain.scripthash.unsubscribe";
+
+/// The upstream subscribe sent for the first client, awaited by every client joining before
+/// it is answered.
+type FirstSubscribe = Shared<BoxFuture<'static, Result<Value, R>>>;
+
+/// An upstream `blockchain.scripthash.subscribe`, shared by every client watching the
+/// scripthash.
+struct Subscription {
+    /// Tells this subscription apart from a later one of the same scripthash.
+    id: u64,
+    /// Number of client subscriptions, the upstream one is dropped once none is left.
+    clients: usize,
+    /// Index of the instance holding the upstream subscription.
+    instance: u32,
+    /// `null` until the first subscribe is answered.
+    status: Value,
+    tx: broadcast::Sender<Value>,
+    first: FirstSubscribe,
+}
+
+static SCRIPTHASHES: LazyLock<Mutex<HashMap<String, Subscription>>> =
+    LazyLock::new(Default::default);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Subscribe a client to status changes of `scripthash`. Only the first client subscribes
+/// upstream, the others share its subscription, even those arriving before the upstream
+/// answered. Returns the current status and a receiver for the following ones.
+pub async fn subscribe_scripthash(
+    instances: &[Instance],
+    scripthash: &str,
+) -> Result<(Value, broadcast::Receiver<Value>), R> {
+    let (first, rx) = {
+        let mut scripthashes = SCRIPTHASHES.lock().unwrap();
+        match scripthashes.get_mut(scripthash) {
+            Some(subscription) => {
+                subscription.clients += 1;
+                (subscription.first.clone(), subscription.tx.subscribe())
+            }
+            None => {
+                let Some(instance) = select_instance(instances) else {
+                    return Err(unavailable(false));
+                };
+                let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+                let first = subscribe_first(id, instance.clone(), scripthash.to_string());
+                let tx = broadcast::channel(16).0;
+                let rx = tx.subscribe();
+                let subscription = Subscription {
+                    id,
+                    clients: 1,
+                    instance: instance.index,
+                    status: Value::Null,
+                    tx,
+                    first: first.clone(),
+                };
+                scripthashes.insert(scripthash.to_string(), subscription);
+                (first, rx)
+            }
+        }
+    };
+    let status = first.await?;
+    Ok((scripthash_status(scripthash).unwrap_or(status), rx))
+}
+
+/// Send the upstream subscribe of subscription `id`. On failure the subscription is dropped
+/// along with the clients waiting for it, the next client starts over.
+fn subscribe_first(id: u64, instance: Instance, scripthash: String) -> FirstSubscribe {
+    async move {
+        let subscribed = request(&instance, SCRIPTHASH_SUBSCRIBE, &scripthash).await;
+        let mut scripthashes = SCRIPTHASHES.lock().unwrap();
+        let subscription = scripthashes.get_mut(&scripthash).filter(|x| x.id == id);
+        match (&subscribed, subscription) {
+            (Ok(status), Some(subscription)) => {
+                info!(
+                    "Subscribed to scripthash {} via WS-{}",
+                    scripthash, instance.index
+                );
+                if subscription.status.is_null() {
+                    subscription.status = status.clone();
+                }
+            }
+            (Err(_), Some(_)) => {
+                scripthashes.remove(&scripthash);
+            }
+            _ => {}
+        }
+        subscribed
+    }
+    .boxed()
+    .shared()
+}
+
+/// Drop a client subscription, the last client to leave unsubscribes upstream.
+pub fn unsubscribe_scripthash(instances: &[Instance], scripthash: &str) {
+    let index = {
+        let mut scripthashes = SCRIPTHASHES.lock().unwrap();
+        let Some(subscription) = scripthashes.get_mut(scripthash) else {
+            return;
+        };
+        subscription.clients = subscription.clients.saturating_sub(1);
+        if subscription.clients > 0 {
+            return;
+        }
+        scripthashes.remove(scripthash).map(|x| x.instance)
+    };
+    let Some(instance) = instances
+        .iter()
+        .find(|x| Some(x.index) == index && x.state.is_connected())
+    else {
+        return;
+    };
+    info!(
+        "Unsubscribed from scripthash {} via WS-{}",
+        scripthash, instance.index
+    );
+    let (instance, scripthash) = (instance.clone(), scripthash.to_string());
+    tokio::spawn(async move {
+        if let Err(e) = request(&instance, SCRIPTHASH_UNSUBSCRIBE, &scripthash).await {
+            debug!(
+                "WS-{} Failed to unsubscribe from {}: {:?}",
+                instance.index, &scripthash, e.message
+            );
+        }
+    });
+}
+
+/// The last known status of a subscribed scripthash.
+pub fn scripthash_status(scripthash: &str) -> Option<Value> {
+    let scripthashes = SCRIPTHASHES.lock().unwrap();
+    scripthashes.get(scripthash).map(|x| x.status.clone())
+}
+
+/// Publish a status announced by an upstream, unchanged statuses are dropped.
+pub fn publish_status(scripthash: &str, status: &Value) {
+    let mut scripthashes = SCRIPTHASHES.lock().unwrap();
+    let Some(subscription) = scripthashes.get_mut(scripthash) else {
+        return;
+    };
+    if subscription.status == *status {
+        return;
+    }
+    subscription.status = status.clone();
+    let _ = subscription.tx.send(status.clone());
+}
+
+/// Move the upstream subscriptions held by a drained instance to the other instances, spread
+/// over them. An instance not connected yet sends them once it is, and renews them like after
+/// a reconnect.
+pub fn reassign(from: u32, instances: &[Instance]) {
+    let targets = instances
+        .iter()
+        .filter(|x| x.index != from && !x.state.is_draining())
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        return;
+    }
+    let moved = {
+        let mut scripthashes = SCRIPTHASHES.lock().unwrap();
+        scripthashes
+            .iter_mut()
+            .filter(|(_, x)| x.instance == from)
+            .enumerate()
+            .map(|(i, (scripthash, subscription))| {
+                let target = targets[i % targets.len()];
+                subscription.instance = target.index;
+                (scripthash.clone(), target.clone())
+            })
+            .collect::<Vec<_>>()
+    };
+    if !moved.is_empty() {
+        info!(
+            "WS-{} Moving {} scripthash subscriptions",
+            from,
+            moved.len()
+        );
+    }
+    for (scripthash, instance) in moved {
+        tokio::spawn(async move {
+            match request(&instance, SCRIPTHASH_SUBSCRIBE, &scripthash).await {
+                Ok(status) => publish_status(&scripthash, &status),
+                Err(e) => debug!(
+                    "WS-{} Failed to subscribe to {}: {:?}",
+                    instance.index, &scripthash, e.message
+                ),
+            }
+        });
+    }
+}
+
+/// Scripthashes whose upstream subscription is held by the instance `index`, to be renewed
+/// when it reconnects.
+pub fn held_by(index: u32) -> Vec<String> {
+    let scripthashes = SCRIPTHASHES.lock().unwrap();
+    scripthashes
+        .iter()
+        .filter(|(_, x)| x.instance == index)
+        .map(|(scripthash, _)| scripthash.clone())
+        .collect()
+}
+
+async fn request(instance: &Instance, method: &str, scripthash: &str) -> Result<Value, R> {
+    let _in_flight = instance.track();
+    let call = instance.call(method.into(), vec![json!(scripthash)].into());
+    let (id, response_rx) = match call.await {
+        Ok(call) => call,
+        Err(e) => return Err(unavailable(matches!(e, TrySendError::Full(_)))),
+    };
+    let _probe = instance.dispatched();
+    let timeout = Duration::from_secs(*RESPONSE_TIMEOUT);
+    match tokio::time::timeout(timeout, response_rx).await {
+        Ok(Ok(rep)) => match rep.error.as_ref().and_then(|x| x.as_object()) {
+            Some(err) => Err(R {
+                code: err.get("code").cloned(),
+                message: err.get("message").cloned(),
+                ..R::error(-1, String::new())
+            }),
+            None => Ok(rep.result.unwrap_or_default()),
+        },
+        Ok(Err(_)) => Err(R::error(-1, "Upstream disconnected".into())),
+        Err(_) => {
+            instance.callbacks.write().await.remove(&id);
+            Err(R::error(-1, "Response timeout".into()))
+        }
+    }
+}
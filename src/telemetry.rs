@@ -0,0 +1,257 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{filter_fn, EnvFilter};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
+
+use crate::access::ACCESS_TARGET;
+use crate::envs::{
+    ACCESS_LOG_DIR, ACCESS_LOG_MAX_FILES, ACCESS_LOG_MAX_SIZE, ACCESS_LOG_ROTATION, LOG_FORMAT,
+    OTEL_EXPORTER_OTLP_ENDPOINT, OTEL_SERVICE_NAME, RUST_LOG,
+};
+use crate::upstream::now_millis;
+
+/// Handle to swap the filter of the log lines at runtime, see [`set_log_filter`].
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// What has to outlive the server for logs and spans to be written out.
+pub struct Telemetry {
+    tracer_provider: Option<TracerProvider>,
+    _access_log: Option<WorkerGuard>,
+}
+
+impl Telemetry {
+    /// Flush the spans not exported yet, the access log is flushed on drop.
+    pub fn shutdown(self) {
+        if let Some(provider) = &self.tracer_provider {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Set up logging in `LOG_FORMAT` filtered by `RUST_LOG`, the access log in `ACCESS_LOG_DIR` and span export to
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` when set. Spans are only exported, the log lines stay as they
+/// are.
+pub fn init() -> Telemetry {
+    let tracer_provider = OTEL_EXPORTER_OTLP_ENDPOINT.as_ref().map(|endpoint| {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .unwrap_or_else(|e| panic!("Failed to set up OTEL_EXPORTER_OTLP_ENDPOINT: {}", e));
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                OTEL_SERVICE_NAME.clone(),
+            )]))
+            .build()
+    });
+    let otel = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("elex-proxy"))
+            .with_filter(LevelFilter::INFO)
+    });
+    let filter = EnvFilter::try_new(RUST_LOG.as_str())
+        .unwrap_or_else(|e| panic!("Invalid RUST_LOG {}: {}", *RUST_LOG, e));
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = LOG_FILTER.set(handle);
+    let log = log_layer(std::io::stdout, true)
+        .with_filter(filter)
+        .with_filter(filter_fn(|x| x.is_event() && x.target() != ACCESS_TARGET));
+    let (access, access_guard) = match ACCESS_LOG_DIR.as_ref() {
+        Some(dir) => {
+            let (writer, guard) = match *ACCESS_LOG_MAX_SIZE {
+                0 => tracing_appender::non_blocking(access_appender(dir)),
+                _ => tracing_appender::non_blocking(
+                    SizeAppender::open(dir)
+                        .unwrap_or_else(|e| panic!("Failed to open ACCESS_LOG_DIR {}: {}", dir, e)),
+                ),
+            };
+            let access =
+                log_layer(writer, false).with_filter(filter_fn(|x| x.target() == ACCESS_TARGET));
+            (Some(access), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(log)
+        .with(access)
+        .with(otel)
+        .init();
+    Telemetry {
+        tracer_provider,
+        _access_log: access_guard,
+    }
+}
+
+/// The directives log lines are currently filtered with.
+pub fn log_filter() -> Option<String> {
+    let handle = LOG_FILTER.get()?;
+    handle.with_current(|x| x.to_string()).ok()
+}
+
+/// Replace the filter of the log lines with `directives`, in the syntax of `RUST_LOG`. The
+/// access log and exported spans are not affected.
+pub fn set_log_filter(directives: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(directives)?;
+    let Some(handle) = LOG_FILTER.get() else {
+        anyhow::bail!("Logging is not set up");
+    };
+    handle.reload(filter)?;
+    Ok(())
+}
+
+/// A formatting layer writing to `writer` in `LOG_FORMAT`, text is colored with `ansi`.
+fn log_layer<S, W>(writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    match LOG_FORMAT.as_str() {
+        "text" => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+        "json" => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_writer(writer)
+            .boxed(),
+        x => panic!("Unknown LOG_FORMAT {}, expected text or json", x),
+    }
+}
+
+fn rotation() -> Rotation {
+    match ACCESS_LOG_ROTATION.as_str() {
+        "minutely" => Rotation::MINUTELY,
+        "hourly" => Rotation::HOURLY,
+        "daily" => Rotation::DAILY,
+        "weekly" => Rotation::WEEKLY,
+        "never" => Rotation::NEVER,
+        x => panic!(
+            "Unknown ACCESS_LOG_ROTATION {}, expected minutely, hourly, daily, weekly or never",
+            x
+        ),
+    }
+}
+
+fn access_appender(dir: &str) -> RollingFileAppender {
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation())
+        .filename_prefix("access")
+        .filename_suffix("log");
+    if *ACCESS_LOG_MAX_FILES > 0 {
+        builder = builder.max_log_files(*ACCESS_LOG_MAX_FILES);
+    }
+    builder
+        .build(dir)
+        .unwrap_or_else(|e| panic!("Failed to open ACCESS_LOG_DIR {}: {}", dir, e))
+}
+
+/// Number of the `ACCESS_LOG_ROTATION` period `secs` since the epoch fall in, weeks start on
+/// Sunday like those of tracing-appender.
+fn period(secs: u64) -> u64 {
+    match rotation() {
+        Rotation::MINUTELY => secs / 60,
+        Rotation::HOURLY => secs / 3600,
+        Rotation::DAILY => secs / 86400,
+        Rotation::WEEKLY => (secs / 86400 + 4) / 7,
+        _ => 0,
+    }
+}
+
+/// The access log while `ACCESS_LOG_MAX_SIZE` is set, which tracing-appender does not support.
+/// Lines go to `access.log`, renamed to `access.<millis>.log` before it would exceed the size
+/// or once the `ACCESS_LOG_ROTATION` period is over.
+struct SizeAppender {
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+impl SizeAppender {
+    fn open(dir: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        let file = Self::create(&dir)?;
+        let size = file.metadata()?.len();
+        let period = period(now_millis() / 1000);
+        Ok(Self {
+            dir,
+            file,
+            size,
+            period,
+        })
+    }
+
+    fn create(dir: &Path) -> io::Result<File> {
+        let path = dir.join("access.log");
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        let rolled = format!("access.{}.log", now_millis());
+        fs::rename(self.dir.join("access.log"), self.dir.join(rolled))?;
+        self.file = Self::create(&self.dir)?;
+        self.size = 0;
+        self.prune();
+        Ok(())
+    }
+
+    /// Delete the oldest rolled files so that at most `ACCESS_LOG_MAX_FILES` are left,
+    /// `access.log` included.
+    fn prune(&self) {
+        if *ACCESS_LOG_MAX_FILES == 0 {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut rolled = entries
+            .filter_map(|x| x.ok()?.file_name().into_string().ok())
+            .filter(|x| {
+                let millis = x
+                    .strip_prefix("access.")
+                    .and_then(|x| x.strip_suffix(".log"));
+                millis.is_some_and(|x| !x.is_empty() && x.bytes().all(|x| x.is_ascii_digit()))
+            })
+            .collect::<Vec<_>>();
+        rolled.sort_by_key(|x| (x.len(), x.clone()));
+        let excess = (rolled.len() + 1).saturating_sub(*ACCESS_LOG_MAX_FILES);
+        for name in &rolled[..excess.min(rolled.len())] {
+            let _ = fs::remove_file(self.dir.join(name));
+        }
+    }
+}
+
+impl Write for SizeAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = period(now_millis() / 1000);
+        let full = self.size + buf.len() as u64 > *ACCESS_LOG_MAX_SIZE;
+        if self.size > 0 && (full || period != self.period) {
+            self.roll()?;
+        }
+        self.period = period;
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use serde_json::Value;
+
+use crate::envs::{
+    CIRCUIT_BREAKER_ERROR_RATE, CIRCUIT_BREAKER_OPEN_SECS, CIRCUIT_BREAKER_TIMEOUTS,
+    CIRCUIT_BREAKER_WINDOW,
+};
+
+/// Quarantines an instance after too many failures, then lets a single probe request
+/// through once `CIRCUIT_BREAKER_OPEN_SECS` elapsed (half-open) to decide whether to close again.
+#[derive(Default)]
+pub struct CircuitBreaker {
+    inner: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    outcomes: VecDeque<bool>,
+    consecutive_timeouts: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    /// Whether a request may be routed to the instance right now.
+    pub fn is_available(&self) -> bool {
+        let state = self.inner.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(until) => Instant::now() >= until && !state.probing,
+        }
+    }
+
+    /// Called once a request is queued to the instance, turns a half-open breaker into probing.
+    /// Returns true if this request is the probe.
+    pub fn on_dispatch(&self) -> bool {
+        let mut state = self.inner.lock().unwrap();
+        match state.open_until {
+            Some(until) if Instant::now() >= until && !state.probing => {
+                state.probing = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Let another request probe a half-open breaker, the probe ended without an outcome.
+    pub fn end_probe(&self) {
+        self.inner.lock().unwrap().probing = false;
+    }
+
+    /// Returns true if the breaker was open and is now closed.
+    pub fn record_success(&self) -> bool {
+        let mut state = self.inner.lock().unwrap();
+        state.consecutive_timeouts = 0;
+        if state.open_until.is_some() {
+            *state = BreakerState::default();
+            return true;
+        }
+        state.push(true);
+        false
+    }
+
+    /// Returns true if this failure opened the breaker.
+    pub fn record_failure(&self, timeout: bool) -> bool {
+        let mut state = self.inner.lock().unwrap();
+        if state.open_until.is_some() {
+            // The half-open probe failed, stay open for another period.
+            state.open();
+            return false;
+        }
+        if timeout {
+            state.consecutive_timeouts += 1;
+        }
+        state.push(false);
+        let window = *CIRCUIT_BREAKER_WINDOW;
+        let failures = state.outcomes.iter().filter(|x| !**x).count();
+        let too_many_timeouts = *CIRCUIT_BREAKER_TIMEOUTS > 0
+            && state.consecutive_timeouts >= *CIRCUIT_BREAKER_TIMEOUTS;
+        let too_many_errors = window > 0
+            && state.outcomes.len() >= window
+            && failures * 100 >= window * *CIRCUIT_BREAKER_ERROR_RATE;
+        if too_many_timeouts || too_many_errors {
+            state.open();
+            return true;
+        }
+        false
+    }
+
+    pub fn reset(&self) {
+        *self.inner.lock().unwrap() = BreakerState::default();
+    }
+}
+
+impl BreakerState {
+    fn push(&mut self, ok: bool) {
+        self.outcomes.push_back(ok);
+        while self.outcomes.len() > *CIRCUIT_BREAKER_WINDOW {
+            self.outcomes.pop_front();
+        }
+    }
+
+    fn open(&mut self) {
+        self.outcomes.clear();
+        self.consecutive_timeouts = 0;
+        self.probing = false;
+        self.open_until = Some(Instant::now() + Duration::from_secs(*CIRCUIT_BREAKER_OPEN_SECS));
+    }
+}
+
+/// Whether a JSON-RPC error reflects a problem of the upstream rather than of the request.
+pub fn is_upstream_failure(error: &Value) -> bool {
+    // ElectrumX uses 2 for DAEMON_ERROR, -32603 is the JSON-RPC internal error.
+    matches!(
+        error.get("code").and_then(|x| x.as_i64()),
+        Some(2) | Some(-32603)
+    )
+}
+
+/// Prefixes of the methods looking up a transaction or an atomical by id.
+const LOOKUP_METHODS: [&str; 2] = ["blockchain.transaction.get", "blockchain.atomicals.get"];
+
+/// Whether a JSON-RPC error of a lookup method tells that the txid or atomical is unknown.
+/// ElectrumX reports those as BAD_REQUEST (1), or DAEMON_ERROR (2) passing on the error of the
+/// node, telling them apart from other errors of the same codes by their message only.
+pub fn is_unknown_item(method: &str, error: &Value) -> bool {
+    if !LOOKUP_METHODS.iter().any(|x| method.starts_with(x)) {
+        return false;
+    }
+    if !matches!(
+        error.get("code").and_then(|x| x.as_i64()),
+        Some(1) | Some(2)
+    ) {
+        return false;
+    }
+    let message = error
+        .get("message")
+        .and_then(|x| x.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    ["not found", "no such"].iter().any(|x| message.contains(x))
+}
+
+/// HTTP status for a JSON-RPC error of an upstream to a call of `method`: 400 for bad
+/// requests, 404 for unknown methods and when the txid or atomical is unknown, 429 when the
+/// upstream limits the proxy and 502 for daemon and other failures of the upstream.
+pub fn upstream_error_status(method: &str, error: &Value) -> StatusCode {
+    if is_unknown_item(method, error) {
+        return StatusCode::NOT_FOUND;
+    }
+    // ElectrumX uses 1 for BAD_REQUEST, -101 for EXCESSIVE_RESOURCE_USAGE and -102 for
+    // SERVER_BUSY.
+    match error.get("code").and_then(|x| x.as_i64()) {
+        Some(1) | Some(-32700) | Some(-32600) | Some(-32602) => StatusCode::BAD_REQUEST,
+        Some(-32601) => StatusCode::NOT_FOUND,
+        Some(-101) | Some(-102) => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn half_open() -> CircuitBreaker {
+        let breaker = CircuitBreaker::default();
+        breaker.inner.lock().unwrap().open_until = Some(Instant::now());
+        breaker
+    }
+
+    #[test]
+    fn lets_one_probe_through_half_open_breaker() {
+        let breaker = half_open();
+        assert!(breaker.is_available());
+        assert!(breaker.on_dispatch());
+        assert!(!breaker.is_available());
+        assert!(!breaker.on_dispatch());
+        assert!(breaker.record_success());
+        assert!(breaker.is_available());
+        assert!(!breaker.on_dispatch());
+    }
+
+    #[test]
+    fn ends_probe_without_outcome() {
+        let breaker = half_open();
+        assert!(breaker.on_dispatch());
+        breaker.end_probe();
+        assert!(breaker.is_available());
+        assert!(breaker.on_dispatch());
+    }
+
+    #[test]
+    fn maps_error_codes() {
+        let status = |code: i64| upstream_error_status("server.ping", &json!({"code": code}));
+        assert_eq!(status(1), StatusCode::BAD_REQUEST);
+        assert_eq!(status(-32602), StatusCode::BAD_REQUEST);
+        assert_eq!(status(-32601), StatusCode::NOT_FOUND);
+        assert_eq!(status(-102), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(2), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn maps_unknown_items_of_lookups_only() {
+        let unknown_tx = json!({
+            "code": 2,
+            "message": "daemon error: {'code': -5, 'message': 'No such mempool or blockchain transaction.'}",
+        });
+        let method = "blockchain.transaction.get";
+        assert_eq!(
+            upstream_error_status(method, &unknown_tx),
+            StatusCode::NOT_FOUND
+        );
+        let unknown_atomical = json!({"code": 1, "message": "atomical not found"});
+        let method = "blockchain.atomicals.get_location";
+        assert_eq!(
+            upstream_error_status(method, &unknown_atomical),
+            StatusCode::NOT_FOUND
+        );
+        let other = "blockchain.scripthash.get_balance";
+        assert_eq!(
+            upstream_error_status(other, &unknown_atomical),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn keeps_bad_requests_apart_from_unknown_items() {
+        let method = "blockchain.atomicals.get";
+        let missing = json!({"code": 1, "message": "missing parameter atomical_id"});
+        assert_eq!(
+            upstream_error_status(method, &missing),
+            StatusCode::BAD_REQUEST
+        );
+        let params = json!({"code": -32602, "message": "atomical not found"});
+        assert_eq!(
+            upstream_error_status(method, &params),
+            StatusCode::BAD_REQUEST
+        );
+        assert!(!is_unknown_item(method, &missing));
+    }
+}
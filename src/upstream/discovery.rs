@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::envs::{
+    ELECTRUMX_SOCKS5, PEER_DISCOVERY_INTERVAL, PEER_DISCOVERY_MIN_PROTOCOL,
+    PEER_DISCOVERY_SSL_ONLY, RESPONSE_TIMEOUT,
+};
+use crate::structs::JsonRpcRequest;
+use crate::upstream::{select_instance, Endpoint, Upstreams};
+
+/// Periodically ask a connected upstream for its peers with `server.peers.subscribe` and
+/// add the suitable ones to the rotation.
+pub fn spawn_discovery(upstreams: Upstreams) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs((*PEER_DISCOVERY_INTERVAL).max(1));
+        // Give the seed servers a moment to connect before the first round.
+        let mut interval =
+            time::interval_at(time::Instant::now() + Duration::from_secs(10), period);
+        loop {
+            interval.tick().await;
+            let instances = upstreams.snapshot();
+            let Some(instance) = select_instance(&instances) else {
+                warn!("Peer discovery skipped, no upstream connected");
+                continue;
+            };
+            let (id, response_rx) = instance.register().await;
+            let request = JsonRpcRequest {
+                id: Some(id),
+                method: "server.peers.subscribe".into(),
+                params: vec![].into(),
+            };
+            if let Err(e) = instance.sender.try_send(request) {
+                warn!("WS-{} Peer discovery not queued: {}", instance.index, e);
+                instance.callbacks.write().await.remove(&id);
+                continue;
+            }
+            let _probe = instance.dispatched();
+            let response = time::timeout(Duration::from_secs(*RESPONSE_TIMEOUT), response_rx).await;
+            let peers = match response {
+                Ok(Ok(rep)) => {
+                    instance.record_outcome(true, false);
+                    rep.result.map(|x| parse_peers(&x)).unwrap_or_default()
+                }
+                Ok(Err(_)) | Err(_) => {
+                    warn!(
+                        "WS-{} No response to server.peers.subscribe",
+                        instance.index
+                    );
+                    instance.record_outcome(false, true);
+                    instance.callbacks.write().await.remove(&id);
+                    continue;
+                }
+            };
+            let added = upstreams.add_peers(peers);
+            if added > 0 {
+                info!("Peer discovery added {} upstreams", added);
+            }
+        }
+    });
+}
+
+/// Turn a `server.peers.subscribe` result, `[[ip, host, ["v1.4", "s50002", "t50001"]], ..]`,
+/// into endpoints, keeping only peers that meet the `PEER_DISCOVERY_*` requirements.
+fn parse_peers(value: &Value) -> Vec<Endpoint> {
+    let min_protocol = parse_version(&PEER_DISCOVERY_MIN_PROTOCOL);
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|peer| {
+            let host = peer.get(1)?.as_str()?;
+            if host.ends_with(".onion") && ELECTRUMX_SOCKS5.is_none() {
+                return None;
+            }
+            let (mut protocol, mut ssl, mut tcp, mut pruned) = (None, None, None, false);
+            for feature in peer.get(2)?.as_array()?.iter().filter_map(|x| x.as_str()) {
+                let (kind, value) = feature.split_at(feature.len().min(1));
+                match kind {
+                    "v" => protocol = Some(parse_version(value)),
+                    "s" => ssl = Some(value),
+                    "t" => tcp = Some(value),
+                    "p" => pruned = true,
+                    _ => {}
+                }
+            }
+            // Pruned peers can not answer history requests for old transactions.
+            if pruned || protocol? < min_protocol {
+                return None;
+            }
+            let url = match (ssl, tcp) {
+                (Some(port), _) => with_port("ssl", host, port),
+                (None, Some(port)) if !*PEER_DISCOVERY_SSL_ONLY => with_port("tcp", host, port),
+                _ => return None,
+            };
+            url.parse().ok()
+        })
+        .collect()
+}
+
+fn with_port(scheme: &str, host: &str, port: &str) -> String {
+    if port.is_empty() {
+        format!("{}://{}", scheme, host)
+    } else {
+        format!("{}://{}:{}", scheme, host, port)
+    }
+}
+
+fn parse_version(value: &str) -> Vec<u32> {
+    value
+        .split('.')
+        .map(|x| x.parse().unwrap_or_default())
+        .collect()
+}
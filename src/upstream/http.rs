@@ -0,0 +1,106 @@
+use std::sync::LazyLock;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::error;
+use url::Url;
+
+use crate::upstream::{Endpoint, Transport};
+
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+/// JSON-RPC over HTTP(S) POST, responses are queued and handed out by `receive`.
+///
+/// All instances without a SOCKS5 proxy share one pooled client, so keep-alive
+/// connections are reused across requests and WS instances.
+pub struct HttpTransport {
+    client: reqwest::Client,
+    url: Url,
+    headers: HeaderMap,
+    tx: mpsc::UnboundedSender<String>,
+    rx: mpsc::UnboundedReceiver<String>,
+}
+
+impl Transport for HttpTransport {
+    async fn connect(endpoint: &Endpoint) -> anyhow::Result<Self> {
+        let client = match &endpoint.socks5 {
+            // socks5h resolves the host name on the proxy, which is required for .onion.
+            Some(proxy) => reqwest::Client::builder()
+                .proxy(reqwest::Proxy::all(proxy.as_str().replacen(
+                    "socks5://",
+                    "socks5h://",
+                    1,
+                ))?)
+                .build()?,
+            None => HTTP_CLIENT.clone(),
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            client,
+            url: endpoint.url.clone(),
+            headers: endpoint.headers.clone(),
+            tx,
+            rx,
+        })
+    }
+
+    async fn send(&mut self, text: String) -> anyhow::Result<()> {
+        let client = self.client.clone();
+        let url = self.url.clone();
+        let headers = self.headers.clone();
+        let tx = self.tx.clone();
+        let id = serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|x| x.get("id")?.as_u64());
+        tokio::spawn(async move {
+            let result = async {
+                client
+                    .post(url.clone())
+                    .headers(headers)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(text)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await
+            }
+            .await;
+            match result {
+                Ok(body) => {
+                    let _ = tx.send(body);
+                }
+                Err(e) => {
+                    error!("Failed to post request to ElectrumX {}: {:?}", url, e);
+                    // Fail the pending call now rather than after RESPONSE_TIMEOUT, as an
+                    // internal error so that it counts against the circuit breaker.
+                    if let Some(id) = id {
+                        let _ = tx.send(failure(id, e.status()).to_string());
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Option<anyhow::Result<String>> {
+        self.rx.recv().await.map(Ok)
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.rx.close();
+        Ok(())
+    }
+}
+
+/// A JSON-RPC internal error answering the request `id`, without the URL, which may carry
+/// credentials.
+fn failure(id: u64, status: Option<reqwest::StatusCode>) -> Value {
+    let message = match status {
+        Some(status) => format!("Upstream answered with HTTP {}", status),
+        None => "Upstream request failed".to_string(),
+    };
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32603, "message": message } })
+}
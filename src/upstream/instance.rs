@@ -0,0 +1,418 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::envs::{UPSTREAM_FAILOVER, UPSTREAM_MAX_IN_FLIGHT, UPSTREAM_MAX_LAG};
+use crate::structs::{Callbacks, JsonRpcRequest, JsonRpcResponse, Params};
+use crate::upstream::breaker::CircuitBreaker;
+use crate::upstream::lifecycle::{log_event, ConnectionEvent, ConnectionStats};
+use crate::upstream::Endpoint;
+
+/// One upstream client loop, together with the queue and pending callbacks used to talk to it.
+#[derive(Clone)]
+pub struct Instance {
+    pub index: u32,
+    /// Queue of requests waiting to be written to the upstream, bounded by `UPSTREAM_QUEUE_SIZE`.
+    pub sender: Sender<JsonRpcRequest>,
+    pub callbacks: Callbacks,
+    pub state: Arc<InstanceState>,
+    /// The endpoints this instance rotates through.
+    pub endpoints: Arc<Vec<Endpoint>>,
+    /// Cancelled to stop the client loop of a drained instance.
+    pub shutdown: CancellationToken,
+}
+
+#[derive(Default)]
+pub struct InstanceState {
+    connected: AtomicBool,
+    draining: AtomicBool,
+    last_response_at: AtomicU64,
+    in_flight: AtomicUsize,
+    weight: AtomicU32,
+    pub breaker: CircuitBreaker,
+    pub connections: ConnectionStats,
+    versions: Mutex<(Option<String>, Option<String>)>,
+    next_id: AtomicU64,
+    tip_height: AtomicU64,
+    priority: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    latency_ms: AtomicU64,
+}
+
+/// Counts a request as outstanding on an instance until dropped.
+pub struct InFlight(Arc<InstanceState>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Ends the probe of a half-open circuit breaker when dropped, should the request fail to
+/// record an outcome: it was dropped, cancelled or its connection went away.
+pub struct Probe(Option<Arc<InstanceState>>);
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if let Some(state) = &self.0 {
+            state.breaker.end_probe();
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(
+        index: u32,
+        sender: Sender<JsonRpcRequest>,
+        callbacks: Callbacks,
+        endpoints: Arc<Vec<Endpoint>>,
+    ) -> Self {
+        Self {
+            index,
+            sender,
+            callbacks,
+            state: Arc::new(InstanceState::default()),
+            endpoints,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Feed the result of a request into the circuit breaker of the instance.
+    pub fn record_outcome(&self, ok: bool, timeout: bool) {
+        self.state.record_outcome(ok);
+        if ok {
+            if self.state.breaker.record_success() {
+                info!("WS-{} Circuit breaker closed", self.index);
+            }
+        } else if self.state.breaker.record_failure(timeout) {
+            warn!("WS-{} Circuit breaker opened", self.index);
+        }
+    }
+
+    /// Count a connection event of the instance and keep it for `/admin/upstreams/events`.
+    pub fn record_event(&self, event: ConnectionEvent, endpoint: &str, detail: Option<String>) {
+        self.state.connections.count(event);
+        log_event(self.index, event, endpoint, detail);
+    }
+
+    /// Allocate a request id on this instance.
+    ///
+    /// Ids are per instance and skip 0, which is reserved for the headers subscription.
+    pub fn next_id(&self) -> u64 {
+        let prev = self
+            .state
+            .next_id
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                Some(if x == u64::MAX { 1 } else { x + 1 })
+            })
+            .unwrap();
+        if prev == u64::MAX {
+            1
+        } else {
+            prev + 1
+        }
+    }
+
+    /// Allocate a request id and register a callback for its response. Should the id still
+    /// be pending after wrapping around, the stale callback is cancelled.
+    pub async fn register(&self) -> (u64, oneshot::Receiver<JsonRpcResponse>) {
+        let id = self.next_id();
+        let (tx, rx) = oneshot::channel();
+        if self.callbacks.write().await.insert(id, tx).is_some() {
+            warn!(
+                "WS-{} Cancelled stale request with reused id {}",
+                self.index, id
+            );
+        }
+        (id, rx)
+    }
+
+    /// Register a callback and queue the request. The callback is removed again should the
+    /// queue reject the request.
+    pub async fn call(
+        &self,
+        method: String,
+        params: Params,
+    ) -> Result<(u64, oneshot::Receiver<JsonRpcResponse>), TrySendError<JsonRpcRequest>> {
+        let (id, rx) = self.register().await;
+        let request = JsonRpcRequest {
+            id: Some(id),
+            method,
+            params,
+        };
+        if let Err(e) = self.sender.try_send(request) {
+            warn!("WS-{} Queue rejected request {}: {}", self.index, id, e);
+            self.callbacks.write().await.remove(&id);
+            return Err(e);
+        }
+        Ok((id, rx))
+    }
+
+    /// Requests in the queue of the instance, not written to the upstream yet.
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Requests written to the upstream and waiting for their response.
+    pub async fn pending_callbacks(&self) -> usize {
+        self.callbacks.read().await.len()
+    }
+
+    /// A JSON summary of the instance for the health and status endpoints, `consensus` is
+    /// the result of [`consensus_height`].
+    pub fn summary(&self, consensus: u64) -> Value {
+        let (server, protocol) = self.state.versions();
+        json!({
+            "instance": self.index,
+            "connected": self.state.is_connected(),
+            "server_version": server,
+            "protocol_version": protocol,
+            "tip_height": self.state.tip_height(),
+            "lagging": self.is_lagging(consensus),
+            "pending": self.state.in_flight(),
+            "latency_ms": self.state.latency_ms(),
+            "last_response_at": self.state.last_response_at(),
+            "consecutive_failures": self.state.consecutive_failures(),
+        })
+    }
+
+    /// Whether the tip of the upstream is more than `UPSTREAM_MAX_LAG` blocks behind
+    /// `consensus`. An upstream whose tip is not known yet counts as lagging.
+    pub fn is_lagging(&self, consensus: u64) -> bool {
+        consensus.saturating_sub(self.state.tip_height()) > *UPSTREAM_MAX_LAG
+    }
+
+    /// Whether the instance can take requests: connected, not draining and not cut off by
+    /// its circuit breaker.
+    pub fn is_available(&self) -> bool {
+        self.state.is_connected() && !self.state.is_draining() && self.state.breaker.is_available()
+    }
+
+    /// Whether the instance is below `UPSTREAM_MAX_IN_FLIGHT` outstanding requests.
+    pub fn has_capacity(&self) -> bool {
+        *UPSTREAM_MAX_IN_FLIGHT == 0 || self.state.in_flight() < *UPSTREAM_MAX_IN_FLIGHT
+    }
+
+    /// Like [`Instance::track`], unless the instance already has `UPSTREAM_MAX_IN_FLIGHT`
+    /// outstanding requests.
+    pub fn try_track(&self) -> Option<InFlight> {
+        self.state
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                (*UPSTREAM_MAX_IN_FLIGHT == 0 || x < *UPSTREAM_MAX_IN_FLIGHT).then_some(x + 1)
+            })
+            .ok()?;
+        Some(InFlight(self.state.clone()))
+    }
+
+    /// Mark a request as outstanding until the returned guard is dropped.
+    pub fn track(&self) -> InFlight {
+        self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.state.clone())
+    }
+
+    /// Tell the circuit breaker a request was queued, to be called once [`Instance::call`]
+    /// succeeded. Keep the guard until the outcome of the request is recorded.
+    pub fn dispatched(&self) -> Probe {
+        let probing = self.state.breaker.on_dispatch();
+        Probe(probing.then(|| self.state.clone()))
+    }
+}
+
+impl InstanceState {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// A draining instance takes no new requests, it is about to be shut down.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    /// Unix timestamp in milliseconds of the last response matched to a request, 0 if none yet.
+    pub fn last_response_at(&self) -> u64 {
+        self.last_response_at.load(Ordering::SeqCst)
+    }
+
+    pub fn record_response(&self) {
+        self.last_response_at.store(now_millis(), Ordering::SeqCst);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Weight of the endpoint the instance is currently connected to.
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::SeqCst).max(1)
+    }
+
+    pub fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::SeqCst);
+    }
+
+    /// Priority of the endpoint the instance is currently connected to, see [`Endpoint`].
+    pub fn priority(&self) -> usize {
+        self.priority.load(Ordering::SeqCst)
+    }
+
+    pub fn set_priority(&self, priority: usize) {
+        self.priority.store(priority, Ordering::SeqCst);
+    }
+
+    /// Latest block height announced by the upstream, 0 if none yet.
+    pub fn tip_height(&self) -> u64 {
+        self.tip_height.load(Ordering::SeqCst)
+    }
+
+    pub fn set_tip_height(&self, height: u64) {
+        self.tip_height.store(height, Ordering::SeqCst);
+    }
+
+    /// Failed requests since the last successful one.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::SeqCst)
+    }
+
+    fn record_outcome(&self, ok: bool) {
+        if ok {
+            self.consecutive_failures.store(0, Ordering::SeqCst);
+        } else {
+            self.consecutive_failures.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Round trip in milliseconds of the last health check, `None` if none answered yet.
+    pub fn latency_ms(&self) -> Option<u64> {
+        Some(self.latency_ms.load(Ordering::SeqCst)).filter(|x| *x > 0)
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        let millis = (latency.as_millis() as u64).max(1);
+        self.latency_ms.store(millis, Ordering::SeqCst);
+    }
+
+    /// Server software and protocol version negotiated by `server.version`.
+    pub fn versions(&self) -> (Option<String>, Option<String>) {
+        self.versions.lock().unwrap().clone()
+    }
+
+    pub fn set_versions(&self, server: Option<String>, protocol: Option<String>) {
+        *self.versions.lock().unwrap() = (server, protocol);
+    }
+}
+
+/// Pick the connected instance with the fewest outstanding requests relative to its
+/// endpoint weight, ties are broken by a weighted random choice. Instances with an open
+/// circuit breaker are skipped.
+pub fn select_instance(instances: &[Instance]) -> Option<&Instance> {
+    select_instance_where(instances, |_| true)
+}
+
+/// Like [`select_instance`], but skips instances lagging behind the tip of the majority.
+/// Used for cacheable queries, which must not be answered from a stale upstream.
+pub fn select_synced_instance(instances: &[Instance]) -> Option<&Instance> {
+    let consensus = consensus_height(instances);
+    select_instance_where(instances, |x| !x.is_lagging(consensus))
+}
+
+/// The tip height most connected upstreams agree on, the median of their announced tips.
+pub fn consensus_height(instances: &[Instance]) -> u64 {
+    let heights = instances
+        .iter()
+        .filter(|x| x.state.is_connected())
+        .map(|x| x.state.tip_height());
+    median_height(heights)
+}
+
+/// The median of the announced tips, upper for an even number, 0 until any is announced.
+fn median_height(heights: impl Iterator<Item = u64>) -> u64 {
+    let mut heights = heights.filter(|x| *x > 0).collect::<Vec<_>>();
+    heights.sort_unstable();
+    heights.get(heights.len() / 2).copied().unwrap_or_default()
+}
+
+/// Like [`select_synced_instance`], but never picks the instance with index `exclude`.
+pub fn select_other_instance(instances: &[Instance], exclude: u32) -> Option<&Instance> {
+    let consensus = consensus_height(instances);
+    select_instance_where(instances, |x| {
+        x.index != exclude && !x.is_lagging(consensus)
+    })
+}
+
+fn select_instance_where(
+    instances: &[Instance],
+    predicate: impl Fn(&Instance) -> bool,
+) -> Option<&Instance> {
+    let available = |x: &&Instance| x.is_available() && x.has_capacity() && predicate(x);
+    // With failover, only the most preferred endpoint that is up takes traffic.
+    let best = instances
+        .iter()
+        .filter(available)
+        .map(|x| x.state.priority())
+        .min()?;
+    let alive = instances
+        .iter()
+        .filter(available)
+        .filter(|x| !*UPSTREAM_FAILOVER || x.state.priority() == best)
+        .map(|x| (x, x.state.in_flight() as u64, x.state.weight() as u64))
+        .collect::<Vec<_>>();
+    // Compare in_flight / weight without floating point: a.1 / a.2 < b.1 / b.2.
+    let (_, min_load, min_weight) = *alive.iter().min_by(|a, b| (a.1 * b.2).cmp(&(b.1 * a.2)))?;
+    let candidates = alive
+        .into_iter()
+        .filter(|(_, n, w)| n * min_weight == min_load * w)
+        .collect::<Vec<_>>();
+    let (instance, _, _) = candidates
+        .choose_weighted(&mut rand::thread_rng(), |(_, _, w)| *w)
+        .ok()?;
+    Some(*instance)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_median_height() {
+        assert_eq!(
+            median_height([850_001, 850_000, 850_002].into_iter()),
+            850_001
+        );
+        assert_eq!(
+            median_height([850_000, 849_000, 850_000].into_iter()),
+            850_000
+        );
+        assert_eq!(median_height([850_000, 850_001].into_iter()), 850_001);
+    }
+
+    #[test]
+    fn ignores_unannounced_tips() {
+        assert_eq!(median_height([0, 0, 850_000].into_iter()), 850_000);
+        assert_eq!(median_height([0].into_iter()), 0);
+        assert_eq!(median_height(std::iter::empty()), 0);
+    }
+}
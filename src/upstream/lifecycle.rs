@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::envs::UPSTREAM_EVENT_LOG_SIZE;
+use crate::upstream::now_millis;
+
+/// Something that happened to the connection of an instance.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionEvent {
+    ConnectAttempt,
+    ConnectFailure,
+    Connect,
+    Disconnect,
+    SendFailure,
+    ParseFailure,
+}
+
+impl ConnectionEvent {
+    const ALL: [ConnectionEvent; 6] = [
+        ConnectionEvent::ConnectAttempt,
+        ConnectionEvent::ConnectFailure,
+        ConnectionEvent::Connect,
+        ConnectionEvent::Disconnect,
+        ConnectionEvent::SendFailure,
+        ConnectionEvent::ParseFailure,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionEvent::ConnectAttempt => "connect_attempt",
+            ConnectionEvent::ConnectFailure => "connect_failure",
+            ConnectionEvent::Connect => "connect",
+            ConnectionEvent::Disconnect => "disconnect",
+            ConnectionEvent::SendFailure => "send_failure",
+            ConnectionEvent::ParseFailure => "parse_failure",
+        }
+    }
+}
+
+/// Connection events of one instance, counted since it started.
+#[derive(Default)]
+pub struct ConnectionStats([AtomicU64; ConnectionEvent::ALL.len()]);
+
+impl ConnectionStats {
+    pub fn count(&self, event: ConnectionEvent) {
+        self.0[event as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Every event with its counter, for `/metrics`.
+    pub fn counters(&self) -> impl Iterator<Item = (ConnectionEvent, &AtomicU64)> {
+        ConnectionEvent::ALL.into_iter().zip(&self.0)
+    }
+
+    /// The counters by event name, for `/status`.
+    pub fn summary(&self) -> Value {
+        let counts = self
+            .counters()
+            .map(|(event, x)| (event.name().to_string(), json!(x.load(Ordering::Relaxed))))
+            .collect::<Map<_, _>>();
+        Value::Object(counts)
+    }
+}
+
+/// The last `UPSTREAM_EVENT_LOG_SIZE` connection events of all instances, oldest first.
+static EVENTS: LazyLock<Mutex<VecDeque<Value>>> = LazyLock::new(Default::default);
+
+pub fn log_event(index: u32, event: ConnectionEvent, endpoint: &str, detail: Option<String>) {
+    if *UPSTREAM_EVENT_LOG_SIZE == 0 {
+        return;
+    }
+    let mut events = EVENTS.lock().unwrap();
+    if events.len() >= *UPSTREAM_EVENT_LOG_SIZE {
+        events.pop_front();
+    }
+    events.push_back(json!({
+        "at": now_millis(),
+        "instance": index,
+        "event": event,
+        "endpoint": endpoint,
+        "detail": detail,
+    }));
+}
+
+pub fn recent_events() -> Vec<Value> {
+    EVENTS.lock().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tokio::time;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info, warn};
+
+use crate::cache::bus;
+use crate::envs::{
+    ELECTRUMX_CLIENT_NAME, ELECTRUMX_PROTOCOL_VERSION, RESPONSE_TIMEOUT,
+    UPSTREAM_CONNECT_STAGGER_MS, UPSTREAM_FAILBACK_INTERVAL, UPSTREAM_FAILOVER,
+    UPSTREAM_PING_INTERVAL, UPSTREAM_QUEUE_SIZE,
+};
+use crate::events::publish_header;
+use crate::structs::{Callbacks, JsonRpcRequest, JsonRpcResponse, ResultCache};
+use crate::subscriptions::{held_by, publish_status, SCRIPTHASH_SUBSCRIBE};
+use crate::CACHED_BLOCK_HEIGHT;
+
+pub use breaker::{is_unknown_item, is_upstream_failure, upstream_error_status};
+pub use discovery::spawn_discovery;
+pub use endpoint::{parse_endpoints, parse_socks5, Endpoint};
+pub use http::HttpTransport;
+pub use instance::{
+    consensus_height, now_millis, select_instance, select_other_instance, select_synced_instance,
+    InFlight, Instance,
+};
+pub use lifecycle::{recent_events, ConnectionEvent};
+pub use registry::Upstreams;
+pub use tcp::TcpTransport;
+pub use ws::WsTransport;
+
+mod breaker;
+mod discovery;
+mod endpoint;
+mod http;
+mod instance;
+mod lifecycle;
+mod registry;
+mod tcp;
+mod ws;
+
+/// Server software and protocol version reported by `server.version`.
+type Versions = (Option<String>, Option<String>);
+
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// A bidirectional JSON-RPC text channel to an ElectrumX server.
+pub trait Transport: Sized + Send {
+    fn connect(endpoint: &Endpoint) -> impl Future<Output = anyhow::Result<Self>> + Send;
+
+    fn send(&mut self, text: String) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Resolves to `None` once the connection is closed by the remote.
+    fn receive(&mut self) -> impl Future<Output = Option<anyhow::Result<String>>> + Send;
+
+    fn close(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// The transport selected by the scheme of an `ELECTRUMX_WSS` entry.
+pub enum Connection {
+    Ws(WsTransport),
+    Tcp(TcpTransport),
+    Http(HttpTransport),
+}
+
+impl Transport for Connection {
+    async fn connect(endpoint: &Endpoint) -> anyhow::Result<Self> {
+        match endpoint.url.scheme() {
+            "ws" | "wss" => Ok(Connection::Ws(WsTransport::connect(endpoint).await?)),
+            "tcp" | "ssl" | "tls" => Ok(Connection::Tcp(TcpTransport::connect(endpoint).await?)),
+            "http" | "https" => Ok(Connection::Http(HttpTransport::connect(endpoint).await?)),
+            scheme => Err(anyhow!("Unsupported ElectrumX scheme: {}", scheme)),
+        }
+    }
+
+    async fn send(&mut self, text: String) -> anyhow::Result<()> {
+        match self {
+            Connection::Ws(t) => t.send(text).await,
+            Connection::Tcp(t) => t.send(text).await,
+            Connection::Http(t) => t.send(text).await,
+        }
+    }
+
+    async fn receive(&mut self) -> Option<anyhow::Result<String>> {
+        match self {
+            Connection::Ws(t) => t.receive().await,
+            Connection::Tcp(t) => t.receive().await,
+            Connection::Http(t) => t.receive().await,
+        }
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        match self {
+            Connection::Ws(t) => t.close().await,
+            Connection::Tcp(t) => t.close().await,
+            Connection::Http(t) => t.close().await,
+        }
+    }
+}
+
+fn new_callbacks() -> (
+    Sender<JsonRpcRequest>,
+    Callbacks,
+    Arc<Mutex<ReceiverStream<JsonRpcRequest>>>,
+) {
+    let (ws_tx, ws_rx) = mpsc::channel::<JsonRpcRequest>((*UPSTREAM_QUEUE_SIZE).max(1));
+    let callbacks: Callbacks = Arc::new(RwLock::new(HashMap::new()));
+    let ws_rx_stream = Arc::new(Mutex::new(ReceiverStream::new(ws_rx)));
+    (ws_tx, callbacks, ws_rx_stream)
+}
+
+fn try_new_client(
+    instance: Instance,
+    ws_rx_stream: Arc<Mutex<ReceiverStream<JsonRpcRequest>>>,
+    cache: ResultCache,
+) {
+    tokio::spawn(async move {
+        let ins = instance.index;
+        let list = instance.endpoints.clone();
+        let urls = list.iter().map(|x| x.url.as_str()).collect::<Vec<_>>();
+        info!("WS-{} ElectrumX servers: {:?}", ins, &urls);
+        // Spread instances over the endpoints so that every endpoint takes its share, unless
+        // failover wants all of them on the primary.
+        let preferred = if *UPSTREAM_FAILOVER {
+            0
+        } else {
+            ins as usize % list.len()
+        };
+        let mut index = preferred;
+        let mut failback = None;
+        while !instance.shutdown.is_cancelled() {
+            let connected = match failback.take() {
+                Some(connected) => Some(connected),
+                None => tokio::select! {
+                    connected = connect_any(&instance, &list, index) => connected,
+                    _ = instance.shutdown.cancelled() => break,
+                },
+            };
+            let Some((connected_index, conn, (server, protocol))) = connected else {
+                error!("WS-{} Failed to connect to any ElectrumX server", ins);
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(3)) => {}
+                    _ = instance.shutdown.cancelled() => {}
+                }
+                continue;
+            };
+            let endpoint = &list[connected_index];
+            let wss = &endpoint.url;
+            info!("WS-{} Connected to ElectrumX: {}", ins, &wss);
+            instance.record_event(ConnectionEvent::Connect, wss.as_str(), None);
+            instance.state.set_versions(server, protocol);
+            instance.state.set_priority(endpoint.priority);
+            instance.state.set_weight(endpoint.weight);
+            instance.state.breaker.reset();
+            instance.state.set_connected(true);
+            let on_fallback = *UPSTREAM_FAILOVER && connected_index != 0;
+            let reason = tokio::select! {
+                served = serve(&instance, wss.as_str(), conn, &ws_rx_stream, &cache) => {
+                    served.err().map(|e| {
+                        error!("WS-{} Connection error: {:?}", ins, e);
+                        e.to_string()
+                    })
+                }
+                primary = probe_primary(&instance, &list), if on_fallback => {
+                    info!("WS-{} Primary ElectrumX recovered, failing back: {}", ins, &list[0].url);
+                    failback = Some(primary);
+                    Some("Failing back to the primary".to_string())
+                }
+            };
+            instance.state.set_connected(false);
+            warn!("WS-{} Connection closed: {}", ins, &wss);
+            instance.record_event(ConnectionEvent::Disconnect, wss.as_str(), reason);
+            // Dropping the callbacks tells the waiting handlers right away, instead
+            // of leaving them to run into the response timeout.
+            let pending = {
+                let mut callbacks = instance.callbacks.write().await;
+                let pending = callbacks.len();
+                callbacks.clear();
+                pending
+            };
+            if pending > 0 {
+                warn!("WS-{} Dropped {} pending requests", ins, pending);
+            }
+            // Prefer the next endpoint after a disconnect, failover always starts over at the
+            // primary.
+            index = if *UPSTREAM_FAILOVER {
+                preferred
+            } else {
+                (connected_index + 1) % list.len()
+            };
+        }
+        info!("WS-{} Stopped", ins);
+    });
+}
+
+/// Race connections to all endpoints, starting with `start` and opening the next one every
+/// `UPSTREAM_CONNECT_STAGGER_MS`, and keep the first one that completes the handshake.
+/// Returns its index in `list`, the connection and the negotiated versions.
+async fn connect_any(
+    instance: &Instance,
+    list: &[Endpoint],
+    start: usize,
+) -> Option<(usize, Connection, Versions)> {
+    let stagger = Duration::from_millis(*UPSTREAM_CONNECT_STAGGER_MS);
+    let mut attempts = (0..list.len())
+        .map(|n| {
+            let index = (start + n) % list.len();
+            let endpoint = &list[index];
+            async move {
+                time::sleep(stagger * n as u32).await;
+                info!(
+                    "WS-{} Try to connect to ElectrumX: {}",
+                    instance.index, &endpoint.url
+                );
+                let url = endpoint.url.as_str();
+                instance.record_event(ConnectionEvent::ConnectAttempt, url, None);
+                let attempt = async {
+                    let mut conn = Connection::connect(endpoint).await?;
+                    let versions = handshake(instance, &mut conn).await?;
+                    anyhow::Ok((index, conn, versions))
+                };
+                attempt.await.inspect_err(|e| {
+                    let detail = Some(e.to_string());
+                    instance.record_event(ConnectionEvent::ConnectFailure, url, detail);
+                })
+            }
+        })
+        .collect::<FuturesUnordered<_>>();
+    while let Some(attempt) = attempts.next().await {
+        match attempt {
+            Ok(connected) => return Some(connected),
+            Err(e) => error!(
+                "WS-{} Failed to connect to ElectrumX: {:?}",
+                instance.index, e
+            ),
+        }
+    }
+    None
+}
+
+/// Check the primary endpoint every `UPSTREAM_FAILBACK_INTERVAL` seconds, resolves to the
+/// connection once it completes the handshake again.
+async fn probe_primary(instance: &Instance, list: &[Endpoint]) -> (usize, Connection, Versions) {
+    let period = Duration::from_secs((*UPSTREAM_FAILBACK_INTERVAL).max(1));
+    loop {
+        time::sleep(period).await;
+        let probe = async {
+            let mut conn = Connection::connect(&list[0]).await?;
+            let versions = handshake(instance, &mut conn).await?;
+            anyhow::Ok((conn, versions))
+        };
+        match probe.await {
+            Ok((conn, versions)) => return (0, conn, versions),
+            Err(e) => debug!(
+                "WS-{} Primary ElectrumX still down: {:?}",
+                instance.index, e
+            ),
+        }
+    }
+}
+
+/// Open a TCP stream to `port` on the endpoint host, through its SOCKS5 proxy if configured.
+pub(crate) async fn dial(endpoint: &Endpoint, port: u16) -> anyhow::Result<Box<dyn Io>> {
+    let host = endpoint.host()?;
+    let Some(proxy) = &endpoint.socks5 else {
+        return Ok(Box::new(TcpStream::connect((host, port)).await?));
+    };
+    let proxy_addr = format!(
+        "{}:{}",
+        proxy
+            .host_str()
+            .ok_or_else(|| anyhow!("Missing SOCKS5 host: {}", proxy))?,
+        proxy.port().unwrap_or(1080)
+    );
+    let stream = if proxy.username().is_empty() {
+        Socks5Stream::connect(proxy_addr.as_str(), (host, port)).await?
+    } else {
+        Socks5Stream::connect_with_password(
+            proxy_addr.as_str(),
+            (host, port),
+            proxy.username(),
+            proxy.password().unwrap_or_default(),
+        )
+        .await?
+    };
+    Ok(Box::new(stream))
+}
+
+/// Pump requests from the instance queue into `conn` and dispatch everything read back,
+/// until either side of the connection goes away.
+async fn serve<T: Transport>(
+    instance: &Instance,
+    url: &str,
+    mut conn: T,
+    ws_rx_stream: &Mutex<ReceiverStream<JsonRpcRequest>>,
+    cache: &ResultCache,
+) -> anyhow::Result<()> {
+    let ins = instance.index;
+    let subscribe_request = JsonRpcRequest {
+        id: Some(0),
+        method: "blockchain.headers.subscribe".into(),
+        params: vec![].into(),
+    };
+    conn.send(serde_json::to_string(&subscribe_request)?)
+        .await
+        .map_err(|e| anyhow!("Failed to subscribe: {:?}", e))?;
+    // Renew the scripthash subscriptions held by this instance, they are lost with the
+    // previous connection. Statuses that changed meanwhile go out to the clients.
+    for scripthash in held_by(ins) {
+        let (id, response_rx) = instance.register().await;
+        let request = JsonRpcRequest {
+            id: Some(id),
+            method: SCRIPTHASH_SUBSCRIBE.into(),
+            params: vec![json!(scripthash)].into(),
+        };
+        conn.send(serde_json::to_string(&request)?).await?;
+        tokio::spawn(async move {
+            if let Ok(JsonRpcResponse {
+                result: Some(status),
+                ..
+            }) = response_rx.await
+            {
+                publish_status(&scripthash, &status);
+            }
+        });
+    }
+    let mut guard = ws_rx_stream.lock().await;
+    let ping_period = Duration::from_secs((*UPSTREAM_PING_INTERVAL).max(1));
+    let mut ping_interval = time::interval_at(time::Instant::now() + ping_period, ping_period);
+    let mut pending_ping: Option<(u64, oneshot::Receiver<JsonRpcResponse>)> = None;
+    loop {
+        tokio::select! {
+            _ = instance.shutdown.cancelled() => break,
+            _ = ping_interval.tick(), if *UPSTREAM_PING_INTERVAL > 0 => {
+                if let Some((id, mut rx)) = pending_ping.take() {
+                    if let Err(TryRecvError::Empty) = rx.try_recv() {
+                        instance.callbacks.write().await.remove(&id);
+                        return Err(anyhow!("No response to server.ping within {:?}", ping_period));
+                    }
+                }
+                let (id, rx) = instance.register().await;
+                let ping = JsonRpcRequest {
+                    id: Some(id),
+                    method: "server.ping".into(),
+                    params: vec![].into(),
+                };
+                debug!("WS-{} Keepalive ping sent: {}", ins, id);
+                let sent = async {
+                    conn.send(serde_json::to_string(&ping)?).await?;
+                    // Poll the tip as well, transports without notifications depend on it.
+                    conn.send(serde_json::to_string(&subscribe_request)?).await
+                };
+                if let Err(e) = sent.await {
+                    instance.record_event(ConnectionEvent::SendFailure, url, Some(e.to_string()));
+                    return Err(e);
+                }
+                pending_ping = Some((id, rx));
+            }
+            message = guard.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let request_text = serde_json::to_string(&message)?;
+                debug!("WS-{} Request sent: {}", ins, &request_text);
+                if let Err(e) = conn.send(request_text).await {
+                    error!("WS-{} Failed to send message to ElectrumX: {:?}", ins, e);
+                    instance.record_event(ConnectionEvent::SendFailure, url, Some(e.to_string()));
+                    break;
+                }
+            }
+            text = conn.receive() => match text {
+                Some(Ok(text)) => on_message(instance, url, &text, cache).await,
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+    }
+    let _ = conn.close().await;
+    Ok(())
+}
+
+/// Negotiate the protocol version with `server.version`, which ElectrumX expects to be
+/// the first request of a session.
+async fn handshake<T: Transport>(instance: &Instance, conn: &mut T) -> anyhow::Result<Versions> {
+    let id = instance.next_id();
+    let request = JsonRpcRequest {
+        id: Some(id),
+        method: "server.version".into(),
+        params: vec![
+            Value::String(ELECTRUMX_CLIENT_NAME.clone()),
+            Value::String(ELECTRUMX_PROTOCOL_VERSION.clone()),
+        ]
+        .into(),
+    };
+    conn.send(serde_json::to_string(&request)?).await?;
+    let response = time::timeout(Duration::from_secs(*RESPONSE_TIMEOUT), async {
+        while let Some(text) = conn.receive().await {
+            if let Ok(resp) = serde_json::from_str::<JsonRpcResponse>(&text?) {
+                if resp.id == id {
+                    return Ok(resp);
+                }
+            }
+        }
+        Err(anyhow!("Connection closed during server.version"))
+    })
+    .await
+    .map_err(|_| anyhow!("No response to server.version"))??;
+    if let Some(err) = response.error {
+        return Err(anyhow!("server.version rejected: {}", err));
+    }
+    let versions = response.result.unwrap_or_default();
+    let server = versions.get(0).and_then(|x| x.as_str()).map(String::from);
+    let protocol = versions.get(1).and_then(|x| x.as_str()).map(String::from);
+    info!(
+        "WS-{} Negotiated protocol {:?} with {:?}",
+        instance.index, &protocol, &server
+    );
+    Ok((server, protocol))
+}
+
+async fn on_message(instance: &Instance, url: &str, text: &str, cache: &ResultCache) {
+    let ins = instance.index;
+    debug!("WS-{} Response received: {}", ins, text);
+    if let Ok(resp) = serde_json::from_str::<JsonRpcResponse>(text) {
+        if let Some(callback) = instance.callbacks.write().await.remove(&resp.id) {
+            info!("WS-{} <= {}, Request matched", ins, &resp.id);
+            instance.state.record_response();
+            let _ = callback.send(resp);
+        } else if resp.id == 0 {
+            // Response to our own `blockchain.headers.subscribe`, the current tip.
+            if let Some(header) = resp.result.as_ref() {
+                let height = header.get("height").and_then(|x| x.as_u64());
+                instance.state.set_tip_height(height.unwrap_or_default());
+                publish_header(header);
+            }
+            debug!("WS-{} Tip received: {}", ins, text);
+        } else {
+            warn!("WS-{} No matching request found: {}", ins, text);
+        }
+        return;
+    }
+    match serde_json::from_str::<JsonRpcRequest>(text) {
+        Ok(req) => {
+            debug!("WS-{} Remote request received: {}", ins, text);
+            if req.method == "blockchain.headers.subscribe" {
+                let new_height = req.params.positional().first().map(|v| {
+                    if let Some(v) = v.as_object() {
+                        if let Some(height) = v.get("height") {
+                            return height.as_u64();
+                        }
+                    }
+                    None
+                });
+                if let Some(Some(height)) = new_height {
+                    instance.state.set_tip_height(height);
+                    publish_header(&req.params.positional()[0]);
+                    if CACHED_BLOCK_HEIGHT.fetch_max(height, Ordering::SeqCst) < height {
+                        cache.invalidate_on_block().await;
+                        bus::publish_block(height);
+                        info!(
+                            "New block height by subscribe: {}, invalidate cache",
+                            height
+                        );
+                    }
+                }
+            } else if req.method == SCRIPTHASH_SUBSCRIBE {
+                if let [Value::String(scripthash), status] = req.params.positional() {
+                    publish_status(scripthash, status);
+                }
+            }
+        }
+        Err(e) => {
+            error!("WS-{} Failed to parse ws response: {}, {:?}", ins, text, e);
+            instance.record_event(ConnectionEvent::ParseFailure, url, Some(e.to_string()));
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::envs::{
+    read_env, DEFAULT_ELECTRUMX_WSS, ELECTRUMX_ENDPOINTS, ELECTRUMX_WS_INSTANCE,
+    PEER_DISCOVERY_MAX, RESPONSE_TIMEOUT, UPSTREAM_POOL_MAX, UPSTREAM_POOL_MIN,
+    UPSTREAM_POOL_TARGET_IN_FLIGHT,
+};
+use crate::structs::ResultCache;
+use crate::subscriptions::reassign;
+use crate::upstream::{new_callbacks, parse_endpoints, try_new_client, Endpoint, Instance};
+
+/// The running set of upstream instances, shared by all handlers.
+///
+/// Reloading spawns a fresh set of instances for the new endpoint list and drains the
+/// previous ones once their pending requests completed.
+///
+/// With `UPSTREAM_POOL_MAX` set, every endpoint gets a pool of instances pinned to it
+/// instead, which grows and shrinks with the number of outstanding requests.
+#[derive(Clone)]
+pub struct Upstreams {
+    instances: Arc<RwLock<Vec<Instance>>>,
+    next_index: Arc<AtomicU32>,
+    /// Number of instances started for peers found by discovery.
+    discovered: Arc<AtomicUsize>,
+    cache: ResultCache,
+}
+
+impl Upstreams {
+    pub fn start(cache: ResultCache) -> Self {
+        let upstreams = Self {
+            instances: Arc::new(RwLock::new(vec![])),
+            next_index: Arc::new(AtomicU32::new(0)),
+            discovered: Arc::new(AtomicUsize::new(0)),
+            cache,
+        };
+        let instances = upstreams.spawn_endpoints(ELECTRUMX_ENDPOINTS.clone());
+        *upstreams.instances.write().unwrap() = instances;
+        if *UPSTREAM_POOL_MAX > 0 {
+            let upstreams = upstreams.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(5));
+                loop {
+                    interval.tick().await;
+                    upstreams.autoscale();
+                }
+            });
+        }
+        upstreams
+    }
+
+    pub fn snapshot(&self) -> Vec<Instance> {
+        self.instances.read().unwrap().clone()
+    }
+
+    /// Re-read `ELECTRUMX_WSS` from the `.env` file (or the environment) and replace the
+    /// running instances, discovered peers included. Returns the number of endpoints now in use.
+    pub fn reload(&self) -> anyhow::Result<usize> {
+        let wss = read_env("ELECTRUMX_WSS").unwrap_or(DEFAULT_ELECTRUMX_WSS.to_string());
+        let endpoints = parse_endpoints(&wss)?;
+        let count = endpoints.len();
+        let instances = self.spawn_endpoints(endpoints);
+        let old = {
+            let mut guard = self.instances.write().unwrap();
+            self.discovered.store(0, Ordering::SeqCst);
+            std::mem::replace(&mut *guard, instances)
+        };
+        info!("Reloaded upstreams, draining {} instances", old.len());
+        let instances = self.snapshot();
+        for instance in old {
+            drain(instance, &instances);
+        }
+        Ok(count)
+    }
+
+    /// Start one instance for each peer whose host is not served yet, until
+    /// `PEER_DISCOVERY_MAX` discovered peers are in the rotation. Returns the number added.
+    pub fn add_peers(&self, peers: Vec<Endpoint>) -> usize {
+        let mut instances = self.instances.write().unwrap();
+        let mut known = instances
+            .iter()
+            .flat_map(|x| x.endpoints.iter())
+            .filter_map(|x| x.url.host_str().map(String::from))
+            .collect::<HashSet<_>>();
+        let mut added = 0;
+        for peer in peers {
+            if self.discovered.load(Ordering::SeqCst) >= *PEER_DISCOVERY_MAX {
+                break;
+            }
+            let Some(host) = peer.url.host_str() else {
+                continue;
+            };
+            if !known.insert(host.to_string()) {
+                continue;
+            }
+            info!("Discovered ElectrumX peer: {}", &peer.url);
+            instances.extend(self.spawn_instances(Arc::new(vec![peer]), 1));
+            self.discovered.fetch_add(1, Ordering::SeqCst);
+            added += 1;
+        }
+        added
+    }
+
+    /// Start the instances for a configured endpoint list: `ELECTRUMX_WS_INSTANCE` instances
+    /// rotating through the list, or a pool of `UPSTREAM_POOL_MIN` per endpoint.
+    fn spawn_endpoints(&self, endpoints: Vec<Endpoint>) -> Vec<Instance> {
+        if *UPSTREAM_POOL_MAX == 0 {
+            return self.spawn_instances(Arc::new(endpoints), *ELECTRUMX_WS_INSTANCE);
+        }
+        endpoints
+            .into_iter()
+            .flat_map(|x| self.spawn_instances(Arc::new(vec![x]), (*UPSTREAM_POOL_MIN).max(1)))
+            .collect()
+    }
+
+    /// Resize the pool of every endpoint: add a connection while the pool is below
+    /// `UPSTREAM_POOL_MIN` or the outstanding requests
+    /// per connection exceed `UPSTREAM_POOL_TARGET_IN_FLIGHT`, drain one once the pool is
+    /// less than half busy.
+    fn autoscale(&self) {
+        let target = (*UPSTREAM_POOL_TARGET_IN_FLIGHT).max(1);
+        let (min, max) = ((*UPSTREAM_POOL_MIN).max(1), *UPSTREAM_POOL_MAX);
+        let mut instances = self.instances.write().unwrap();
+        let mut pools: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, instance) in instances.iter().enumerate() {
+            if instance.endpoints.len() == 1 && !instance.state.is_draining() {
+                let url = instance.endpoints[0].url.to_string();
+                pools.entry(url).or_default().push(i);
+            }
+        }
+        let mut removed = vec![];
+        for (url, pool) in pools {
+            let size = pool.len() as u32;
+            let load = pool
+                .iter()
+                .map(|i| instances[*i].state.in_flight())
+                .sum::<usize>();
+            if size < min || (load > size as usize * target && size < max) {
+                let endpoints = instances[pool[0]].endpoints.clone();
+                instances.extend(self.spawn_instances(endpoints, 1));
+                info!("Pool {} grown to {} connections", url, size + 1);
+            } else if size > min && load * 2 < (size as usize - 1) * target {
+                removed.push(*pool.last().unwrap());
+                info!("Pool {} shrunk to {} connections", url, size - 1);
+            }
+        }
+        removed.sort_unstable();
+        for i in removed.into_iter().rev() {
+            let instance = instances.remove(i);
+            drain(instance, &instances);
+        }
+    }
+
+    fn spawn_instances(&self, endpoints: Arc<Vec<Endpoint>>, count: u32) -> Vec<Instance> {
+        (0..count)
+            .map(|_| {
+                let index = self.next_index.fetch_add(1, Ordering::SeqCst);
+                let (ws_tx, callbacks, ws_rx_stream) = new_callbacks();
+                let instance = Instance::new(index, ws_tx, callbacks, endpoints.clone());
+                try_new_client(instance.clone(), ws_rx_stream, self.cache.clone());
+                instance
+            })
+            .collect()
+    }
+}
+
+/// Stop routing to the instance, move its scripthash subscriptions to the `instances` left and
+/// shut it down once its pending requests are answered, or at the latest after
+/// `RESPONSE_TIMEOUT`.
+fn drain(instance: Instance, instances: &[Instance]) {
+    instance.state.set_draining(true);
+    reassign(instance.index, instances);
+    tokio::spawn(async move {
+        let deadline = Instant::now() + Duration::from_secs(*RESPONSE_TIMEOUT);
+        while !instance.callbacks.read().await.is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        info!("WS-{} Drained", instance.index);
+        instance.shutdown.cancel();
+    });
+}
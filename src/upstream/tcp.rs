@@ -0,0 +1,51 @@
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::envs::ELECTRUMX_ACCEPT_INVALID_CERTS;
+use crate::upstream::{dial, Endpoint, Io, Transport};
+
+const DEFAULT_TCP_PORT: u16 = 50001;
+const DEFAULT_SSL_PORT: u16 = 50002;
+
+/// Newline-delimited JSON-RPC over plain TCP (`tcp://`) or TLS (`ssl://`).
+pub struct TcpTransport {
+    framed: Framed<Box<dyn Io>, LinesCodec>,
+}
+
+impl Transport for TcpTransport {
+    async fn connect(endpoint: &Endpoint) -> anyhow::Result<Self> {
+        let tls = matches!(endpoint.url.scheme(), "ssl" | "tls");
+        let default_port = if tls {
+            DEFAULT_SSL_PORT
+        } else {
+            DEFAULT_TCP_PORT
+        };
+        let stream = dial(endpoint, endpoint.url.port().unwrap_or(default_port)).await?;
+        let io: Box<dyn Io> = if tls {
+            let connector = native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(*ELECTRUMX_ACCEPT_INVALID_CERTS)
+                .build()?;
+            let connector = tokio_native_tls::TlsConnector::from(connector);
+            Box::new(connector.connect(endpoint.host()?, stream).await?)
+        } else {
+            stream
+        };
+        Ok(Self {
+            framed: Framed::new(io, LinesCodec::new()),
+        })
+    }
+
+    async fn send(&mut self, text: String) -> anyhow::Result<()> {
+        self.framed.send(text).await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Option<anyhow::Result<String>> {
+        self.framed.next().await.map(|r| r.map_err(Into::into))
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        SinkExt::<String>::close(&mut self.framed).await?;
+        Ok(())
+    }
+}
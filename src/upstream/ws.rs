@@ -0,0 +1,44 @@
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async_tls, MaybeTlsStream, WebSocketStream};
+
+use crate::upstream::{dial, Endpoint, Io, Transport};
+
+/// JSON-RPC over WebSocket, one request or response per text frame.
+pub struct WsTransport {
+    ws: WebSocketStream<MaybeTlsStream<Box<dyn Io>>>,
+}
+
+impl Transport for WsTransport {
+    async fn connect(endpoint: &Endpoint) -> anyhow::Result<Self> {
+        let port = endpoint.url.port_or_known_default().unwrap_or(443);
+        let stream = dial(endpoint, port).await?;
+        let mut request = endpoint.url.as_str().into_client_request()?;
+        request.headers_mut().extend(endpoint.headers.clone());
+        let (ws, _) = client_async_tls(request, stream).await?;
+        Ok(Self { ws })
+    }
+
+    async fn send(&mut self, text: String) -> anyhow::Result<()> {
+        self.ws.send(Message::Text(text)).await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Option<anyhow::Result<String>> {
+        while let Some(msg) = self.ws.next().await {
+            match msg {
+                Ok(Message::Text(text)) => return Some(Ok(text)),
+                Ok(Message::Close(_)) => return None,
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        None
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.ws.close(None).await?;
+        Ok(())
+    }
+}
@@ -1,51 +1,100 @@
-use crate::{handle_request, random_callback, AppError, Callbacks, JsonRpcRequest, R};
-use axum::body::Body;
+use crate::cache::{etag_matches, to_strong_etag};
+use crate::envs::{
+    THUMBNAIL_CACHE_ENTRIES, THUMBNAIL_CACHE_TIME_TO_LIVE, THUMBNAIL_MAX_SIZE, URN_CACHE_BYTES,
+    URN_CACHE_TIME_TO_LIVE,
+};
+use crate::upstream::Upstreams;
+use crate::{handle_request, AppError, R};
+use axum::body::{Body, Bytes};
 use axum::extract::{Path, Query};
-use axum::http::header::CONTENT_TYPE;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Redirect, Response};
 use axum::{Extension, Json};
 use bitcoin::opcodes::all::{OP_ENDIF, OP_IF};
 use bitcoin::{Script, Transaction};
 use headers::HeaderMap;
+use image::ImageFormat;
 use mime_guess::Mime;
+use moka::future::Cache;
 use regex::Regex;
 use serde_json::{Number, Value};
 use std::io::Cursor;
 use std::str::FromStr;
-use tokio::sync::mpsc;
-use tracing::{debug, info};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{debug, info, warn};
 
-use crate::structs::MokaCache;
+use crate::structs::ResultCache;
 
 const ATOMICALS_PROTOCOL_ENVELOPE_ID: [u8; 4] = [97, 116, 111, 109];
 // // dat
 // const ATOMICALS_PROTOCOL_DAT: [u8; 3] = [100, 97, 116];
 
+/// Resized images served for `?w=` and `?h=`, by URN and size, and whether they are immutable.
+/// Payloads of atomicals never change, so thumbnails are kept much longer than query results.
+static THUMBNAILS: LazyLock<Cache<String, (Mime, Bytes, bool)>> = LazyLock::new(|| {
+    Cache::builder()
+        .max_capacity(*THUMBNAIL_CACHE_ENTRIES)
+        .time_to_live(Duration::from_secs(*THUMBNAIL_CACHE_TIME_TO_LIVE))
+        .build()
+});
+
+/// Decoded payloads of `atom:btc:dat` URNs by URN. They are read from the reveal transaction
+/// and never change once it is settled, only then are they served as immutable.
+static PAYLOADS: LazyLock<Cache<String, (Mime, Bytes, bool)>> = LazyLock::new(|| {
+    Cache::builder()
+        .max_capacity(*URN_CACHE_BYTES)
+        .weigher(|urn: &String, (_, bytes, _): &(Mime, Bytes, bool)| {
+            (urn.len() + bytes.len()).try_into().unwrap_or(u32::MAX)
+        })
+        .time_to_live(Duration::from_secs(*URN_CACHE_TIME_TO_LIVE))
+        .build()
+});
+
 pub async fn handle_urn(
-    Extension(callbacks): Extension<Vec<(mpsc::UnboundedSender<JsonRpcRequest>, Callbacks)>>,
-    Extension(cache): Extension<MokaCache>,
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
     headers: HeaderMap,
     Path(urn): Path<String>,
     Query(query): Query<Value>,
 ) -> Result<impl IntoResponse, AppError> {
     info!("URN: {}", &urn);
-    let result = decode_urn(&urn).unwrap();
+    let instances = upstreams.snapshot();
+    let Ok(result) = decode_urn(&urn) else {
+        return to_urn_r(R::error_with_status(
+            StatusCode::BAD_REQUEST,
+            -1,
+            format!("Invalid URN: {}", urn),
+        ));
+    };
     debug!("URN info: {:?}", result);
+    let size = thumbnail_size(&query);
+    let if_none_match = headers.get(IF_NONE_MATCH).cloned();
+    let if_none_match = if_none_match.as_ref();
+    if let Some(size) = size {
+        if let Some((mime_type, bytes, immutable)) =
+            THUMBNAILS.get(&thumbnail_key(&urn, size)).await
+        {
+            return to_urn_content(mime_type, bytes, immutable, if_none_match);
+        }
+    }
+    if let Some((mime_type, bytes, immutable)) = PAYLOADS.get(&urn).await {
+        return to_urn_payload(&urn, size, mime_type, bytes, immutable, if_none_match).await;
+    }
     if UrnType::Dat == result.urn_type {
-        let item = random_callback(&callbacks);
-        let sender = item.0.clone();
-        let calls = item.1.clone();
         let txid = result.identifier.split('i').collect::<Vec<&str>>()[0];
         let r = handle_request(
             cache,
-            sender,
-            calls,
+            &instances,
             headers,
             "blockchain.transaction.get".into(),
             vec![Value::String(txid.to_string())],
         )
         .await;
         return if r.success {
+            // As immutable as the reveal transaction, see `crate::cache::unsettled_txid`.
+            let immutable = r.immutable;
             let value = r.response.unwrap();
             let rawhex = value.as_str().unwrap();
             let transaction = transaction_from_hex(rawhex).unwrap();
@@ -105,16 +154,28 @@ pub async fn handle_urn(
                     for (k, v) in v.as_map().unwrap().iter() {
                         let x = k.as_text().unwrap();
                         if x == f {
-                            let mime_type = mime_guess::from_path(f).first_or_octet_stream();
                             if let Some(v) = find_cbor_first_bytes(v) {
-                                return to_urn_response(
+                                let bytes = Bytes::from(v.as_bytes().unwrap().to_vec());
+                                let mime_type = detect_mime(f, &bytes);
+                                PAYLOADS
+                                    .insert(
+                                        urn.clone(),
+                                        (mime_type.clone(), bytes.clone(), immutable),
+                                    )
+                                    .await;
+                                return to_urn_payload(
+                                    &urn,
+                                    size,
                                     mime_type,
-                                    Body::from(v.as_bytes().unwrap().to_vec()),
-                                );
+                                    bytes,
+                                    immutable,
+                                    if_none_match,
+                                )
+                                .await;
                             }
                         }
                     }
-                    return to_urn_r(R::error(-1, format!("No field found: {}", f)));
+                    return to_urn_r(no_field(f));
                 }
             }
             to_urn_json(cbor_to_json(v))
@@ -132,13 +193,9 @@ pub async fn handle_urn(
             UrnType::Arc => "blockchain.atomicals.get_by_ticker",
             _ => unreachable!(),
         };
-        let item = random_callback(&callbacks);
-        let sender = item.0.clone();
-        let calls = item.1.clone();
         let r = handle_request(
             cache.clone(),
-            sender,
-            calls,
+            &instances,
             headers.clone(),
             method.into(),
             vec![Value::String(result.identifier)],
@@ -155,19 +212,19 @@ pub async fn handle_urn(
             if let Some(aid) = aid {
                 atomical_id = aid.to_string();
             } else {
-                return to_urn_r(R::error(-1, "Not atomical found".to_string()));
+                return to_urn_r(R::error_with_status(
+                    StatusCode::NOT_FOUND,
+                    -1,
+                    "Not atomical found".into(),
+                ));
             }
         } else {
             return to_urn_r(r);
         }
     }
-    let item = random_callback(&callbacks);
-    let sender = item.0.clone();
-    let calls = item.1.clone();
     let r = handle_request(
         cache,
-        sender,
-        calls,
+        &instances,
         headers,
         "blockchain.atomicals.get_state".into(),
         vec![Value::String(atomical_id), Value::Bool(false)],
@@ -180,7 +237,11 @@ pub async fn handle_urn(
             .and_then(|x| x.get("result"))
             .and_then(|x| x.as_object());
         if res.is_none() {
-            return to_urn_r(R::error(-1, "No result found".to_string()));
+            return to_urn_r(R::error_with_status(
+                StatusCode::NOT_FOUND,
+                -1,
+                "No result found".into(),
+            ));
         }
         if let Some(t) = result.path_type {
             if t == "$" {
@@ -198,7 +259,7 @@ pub async fn handle_urn(
                     .unwrap()
                     .as_str()
                     .unwrap();
-                return to_urn_redirect(&format!("/urn/atom:btc:dat:{}{}{}", location, &t, path));
+                return to_urn_redirect(&format!("/urn/atom:btc:dat:{}{}{}", location, t, path));
             }
         }
         let state = res
@@ -213,21 +274,28 @@ pub async fn handle_urn(
                         if let Some(b) = v.get("$b").and_then(|x| x.as_object()) {
                             let hex = b.get("$b").and_then(|x| x.as_str());
                             if let Some(hex) = hex {
-                                let bytes = hex::decode(hex).unwrap();
-                                let mime_type = match b.get("$ct").and_then(|x| x.as_str()) {
-                                    Some(x) => Mime::from_str(x).unwrap_or(
-                                        mime_guess::from_path(f).first_or_octet_stream(),
-                                    ),
-                                    None => mime_guess::from_path(f).first_or_octet_stream(),
-                                };
-                                return to_urn_response(mime_type, Body::from(bytes));
+                                let bytes = Bytes::from(hex::decode(hex).unwrap());
+                                let mime_type = b
+                                    .get("$ct")
+                                    .and_then(|x| x.as_str())
+                                    .and_then(|x| Mime::from_str(x).ok())
+                                    .unwrap_or_else(|| detect_mime(f, &bytes));
+                                return to_urn_payload(
+                                    &urn,
+                                    size,
+                                    mime_type,
+                                    bytes,
+                                    false,
+                                    if_none_match,
+                                )
+                                .await;
                             }
                         }
                     }
-                    return to_urn_r(R::error(-1, format!("No field found: {}", f)));
+                    return to_urn_r(no_field(f));
                 }
             }
-            let use_image = query.as_object().unwrap().contains_key("image");
+            let use_image = query.get("image").is_some();
             if use_image {
                 if let Some(urn) = state.get("image").and_then(|x| x.as_str()) {
                     if urn.starts_with("atom:btc:") {
@@ -238,13 +306,56 @@ pub async fn handle_urn(
             }
             to_urn_json(state.to_owned())
         } else {
-            to_urn_r(R::error(-1, "No state found".to_string()))
+            to_urn_r(R::error_with_status(
+                StatusCode::NOT_FOUND,
+                -1,
+                "No state found".into(),
+            ))
         }
     } else {
         to_urn_r(r)
     }
 }
 
+fn no_field(field: &str) -> R {
+    R::error_with_status(
+        StatusCode::NOT_FOUND,
+        -1,
+        format!("No field found: {}", field),
+    )
+}
+
+/// The content type of a payload stored under the field `name`, guessed from its extension
+/// and otherwise from the leading bytes of the payload, so that images and pages render
+/// directly in a browser.
+fn detect_mime(name: &str, bytes: &[u8]) -> Mime {
+    if let Some(mime) = mime_guess::from_path(name).first() {
+        return mime;
+    }
+    let text = std::str::from_utf8(&bytes[..bytes.len().min(512)]).unwrap_or_default();
+    let text = text.trim_start().to_ascii_lowercase();
+    let mime = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        "image/jpeg"
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        "image/gif"
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        "image/webp"
+    } else if text.starts_with("<svg") || (text.starts_with("<?xml") && text.contains("<svg")) {
+        "image/svg+xml"
+    } else if text.starts_with("<!doctype html") || text.starts_with("<html") {
+        "text/html"
+    } else if serde_json::from_slice::<Value>(bytes).is_ok() {
+        "application/json"
+    } else if std::str::from_utf8(bytes).is_ok() {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    };
+    Mime::from_str(mime).unwrap()
+}
+
 fn find_cbor_first_bytes(cbor: &ciborium::Value) -> Option<&ciborium::Value> {
     if cbor.is_bytes() {
         return Some(cbor);
@@ -311,6 +422,115 @@ fn to_urn_response(context_type: Mime, body: Body) -> anyhow::Result<Response, A
         .unwrap())
 }
 
+/// Serve content that never changes with a strong ETag and an immutable `Cache-Control`, so
+/// that a CDN in front of the proxy can take over serving it. Other content is served as is.
+fn to_urn_content(
+    mime_type: Mime,
+    bytes: Bytes,
+    immutable: bool,
+    if_none_match: Option<&HeaderValue>,
+) -> anyhow::Result<Response, AppError> {
+    if !immutable {
+        return to_urn_response(mime_type, Body::from(bytes));
+    }
+    let etag = HeaderValue::from_str(&to_strong_etag(&bytes)).unwrap();
+    let cache_control = HeaderValue::from_static("public, max-age=31536000, immutable");
+    let builder = Response::builder()
+        .header(ETAG, etag.clone())
+        .header(CACHE_CONTROL, cache_control);
+    if if_none_match.is_some_and(|x| etag_matches(x, &etag)) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+    Ok(builder
+        .header(CONTENT_TYPE, mime_type.to_string())
+        .body(Body::from(bytes))
+        .unwrap())
+}
+
+/// Serve a payload, or a thumbnail of it when a size was requested and it is an image that
+/// can be resized.
+async fn to_urn_payload(
+    urn: &str,
+    size: Option<(u32, u32)>,
+    mime_type: Mime,
+    bytes: Bytes,
+    immutable: bool,
+    if_none_match: Option<&HeaderValue>,
+) -> anyhow::Result<Response, AppError> {
+    let format = ImageFormat::from_mime_type(mime_type.essence_str())
+        .filter(|x| matches!(x, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP));
+    let (Some(size), Some(format)) = (size, format) else {
+        return to_urn_content(mime_type, bytes, immutable, if_none_match);
+    };
+    let resized = tokio::task::spawn_blocking(move || (resize(&bytes, format, size), bytes))
+        .await
+        .unwrap();
+    match resized {
+        (Ok(Some((mime_type, thumbnail))), _) => {
+            let thumbnail = Bytes::from(thumbnail);
+            THUMBNAILS
+                .insert(
+                    thumbnail_key(urn, size),
+                    (mime_type.clone(), thumbnail.clone(), immutable),
+                )
+                .await;
+            to_urn_content(mime_type, thumbnail, immutable, if_none_match)
+        }
+        (Ok(None), bytes) => to_urn_content(mime_type, bytes, immutable, if_none_match),
+        (Err(e), bytes) => {
+            warn!("Failed to resize {}: {:?}", urn, e);
+            to_urn_content(mime_type, bytes, immutable, if_none_match)
+        }
+    }
+}
+
+/// The bounding box requested with `?w=` and `?h=`, capped at `THUMBNAIL_MAX_SIZE`. A missing
+/// side is unbounded, the aspect ratio is kept either way.
+fn thumbnail_size(query: &Value) -> Option<(u32, u32)> {
+    let side = |key| {
+        query
+            .get(key)
+            .and_then(|x| x.as_str())
+            .and_then(|x| x.parse::<u32>().ok())
+            .filter(|x| *x > 0)
+            .map(|x| x.min(*THUMBNAIL_MAX_SIZE))
+    };
+    match (side("w"), side("h")) {
+        (None, None) => None,
+        (w, h) => Some((w.unwrap_or(u32::MAX), h.unwrap_or(u32::MAX))),
+    }
+}
+
+fn thumbnail_key(urn: &str, (w, h): (u32, u32)) -> String {
+    format!("{}?w={}&h={}", urn, w, h)
+}
+
+/// Scale an image down to fit into `w` x `h`. JPEG stays JPEG, everything else is encoded as
+/// PNG. `None` when the image already fits.
+fn resize(
+    bytes: &[u8],
+    format: ImageFormat,
+    (w, h): (u32, u32),
+) -> anyhow::Result<Option<(Mime, Vec<u8>)>> {
+    let image = image::load_from_memory_with_format(bytes, format)?;
+    if image.width() <= w && image.height() <= h {
+        return Ok(None);
+    }
+    let thumbnail = image.thumbnail(w, h);
+    let mut out = Cursor::new(vec![]);
+    let mime_type = if format == ImageFormat::Jpeg {
+        thumbnail.to_rgb8().write_to(&mut out, ImageFormat::Jpeg)?;
+        mime_guess::mime::IMAGE_JPEG
+    } else {
+        thumbnail.write_to(&mut out, ImageFormat::Png)?;
+        mime_guess::mime::IMAGE_PNG
+    };
+    Ok(Some((mime_type, out.into_inner())))
+}
+
 fn to_urn_json(json: Value) -> anyhow::Result<Response, AppError> {
     Ok(Json(json).into_response())
 }
@@ -323,7 +543,7 @@ fn to_urn_redirect(urn: &str) -> anyhow::Result<Response, AppError> {
     Ok(Redirect::permanent(urn).into_response())
 }
 
-fn transaction_from_hex(hex: &str) -> anyhow::Result<Transaction> {
+pub fn transaction_from_hex(hex: &str) -> anyhow::Result<Transaction> {
     let bytes = hex::decode(hex)?;
     let transaction = bitcoin::consensus::deserialize(&bytes)?;
     Ok(transaction)
@@ -0,0 +1,131 @@
+use serde_json::Value;
+
+use crate::structs::Params;
+
+/// Kinds of params checked before a call is sent upstream.
+#[derive(Clone, Copy)]
+enum Kind {
+    /// A txid or scripthash, 64 hex digits.
+    Hash,
+    /// Hex of even length, such as a raw transaction.
+    Hex,
+    Bool,
+    Uint,
+    String,
+    /// An atomical id such as `<txid>i0`, or an atomical number.
+    AtomicalId,
+}
+
+/// Required and optional positional params of a method.
+struct Signature(&'static [Kind], &'static [Kind]);
+
+fn signature(method: &str) -> Option<Signature> {
+    use Kind::*;
+    let signature = match method {
+        "blockchain.block.header" => Signature(&[Uint], &[Uint]),
+        "blockchain.block.headers" => Signature(&[Uint, Uint], &[Uint]),
+        "blockchain.estimatefee" => Signature(&[Uint], &[String]),
+        "blockchain.relayfee" => Signature(&[], &[]),
+        "blockchain.scripthash.get_balance"
+        | "blockchain.scripthash.get_history"
+        | "blockchain.scripthash.get_mempool"
+        | "blockchain.scripthash.listunspent"
+        | "blockchain.scripthash.subscribe"
+        | "blockchain.scripthash.unsubscribe" => Signature(&[Hash], &[]),
+        "blockchain.transaction.broadcast" => Signature(&[Hex], &[]),
+        "blockchain.transaction.get" => Signature(&[Hash], &[Bool]),
+        "blockchain.transaction.get_merkle" => Signature(&[Hash], &[Uint]),
+        "blockchain.transaction.id_from_pos" => Signature(&[Uint, Uint], &[Bool]),
+        "blockchain.atomicals.get"
+        | "blockchain.atomicals.get_location"
+        | "blockchain.atomicals.get_ft_info" => Signature(&[AtomicalId], &[]),
+        "blockchain.atomicals.get_state" => Signature(&[AtomicalId], &[Bool]),
+        "blockchain.atomicals.listscripthash" => Signature(&[Hash], &[Bool]),
+        "blockchain.atomicals.get_by_realm"
+        | "blockchain.atomicals.get_by_ticker"
+        | "blockchain.atomicals.get_by_container" => Signature(&[String], &[]),
+        "blockchain.atomicals.get_by_subrealm" => Signature(&[AtomicalId, String], &[]),
+        "blockchain.atomicals.get_by_container_item" => Signature(&[String, String], &[]),
+        "blockchain.atomicals.get_container_items" => Signature(&[String], &[Uint, Uint]),
+        _ => return None,
+    };
+    Some(signature)
+}
+
+/// Check the positional params of well-known methods, so that malformed calls are refused
+/// before they take an upstream round trip. Other methods and named params pass unchecked.
+pub fn validate(method: &str, params: &Params) -> Result<(), String> {
+    let (Some(Signature(required, optional)), Params::Positional(params)) =
+        (signature(method), params)
+    else {
+        return Ok(());
+    };
+    let max = required.len() + optional.len();
+    if params.len() < required.len() || params.len() > max {
+        let expected = if optional.is_empty() {
+            required.len().to_string()
+        } else {
+            format!("{} to {}", required.len(), max)
+        };
+        return Err(format!(
+            "{} expects {} params, got {}",
+            method,
+            expected,
+            params.len()
+        ));
+    }
+    for (i, (param, kind)) in params
+        .iter()
+        .zip(required.iter().chain(optional))
+        .enumerate()
+    {
+        if let Some(expected) = check(param, *kind) {
+            return Err(format!("{} param {} must be {}", method, i, expected));
+        }
+    }
+    Ok(())
+}
+
+/// What a param of `kind` should have been, `None` when it is fine.
+fn check(param: &Value, kind: Kind) -> Option<&'static str> {
+    let valid = match kind {
+        Kind::Hash => param.as_str().is_some_and(|x| x.len() == 64 && is_hex(x)),
+        Kind::Hex => param
+            .as_str()
+            .is_some_and(|x| !x.is_empty() && x.len() % 2 == 0 && is_hex(x)),
+        Kind::Bool => param.is_boolean(),
+        Kind::Uint => param.is_u64(),
+        Kind::String => param.is_string(),
+        Kind::AtomicalId => param.is_u64() || param.as_str().is_some_and(is_atomical_id),
+    };
+    if valid {
+        return None;
+    }
+    Some(match kind {
+        Kind::Hash => "64 hex digits",
+        Kind::Hex => "hex of even length",
+        Kind::Bool => "a boolean",
+        Kind::Uint => "a non-negative integer",
+        Kind::String => "a string",
+        Kind::AtomicalId => "an atomical id or number",
+    })
+}
+
+fn is_hex(x: &str) -> bool {
+    x.bytes().all(|x| x.is_ascii_hexdigit())
+}
+
+fn is_atomical_id(x: &str) -> bool {
+    if !x.is_empty() && x.bytes().all(|x| x.is_ascii_digit()) {
+        return true;
+    }
+    match x.split_once('i') {
+        Some((txid, index)) => {
+            txid.len() == 64
+                && is_hex(txid)
+                && !index.is_empty()
+                && index.bytes().all(|x| x.is_ascii_digit())
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Extension;
+use axum::http::HeaderMap;
+use axum::response::Response;
+use futures::future::join_all;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::envs::{MAX_BATCH_SIZE, WS_CLIENT_MAX_IN_FLIGHT, WS_CLIENT_QUEUE_SIZE};
+use crate::events::subscribe_headers;
+use crate::ip::maybe_ip_from_headers;
+use crate::is_method_allowed;
+use crate::limit::Charge;
+use crate::rpc::{
+    count_calls, dispatch, error, parse_error, to_response, INVALID_PARAMS, LIMIT_EXCEEDED,
+};
+use crate::structs::{ResultCache, R};
+use crate::subscriptions::{
+    scripthash_status, subscribe_scripthash, unsubscribe_scripthash, SCRIPTHASH_SUBSCRIBE,
+    SCRIPTHASH_UNSUBSCRIBE,
+};
+use crate::upstream::{Instance, Upstreams};
+
+/// WebSocket endpoint for clients, JSON-RPC 2.0 requests in text frames are proxied the same
+/// way as on `/rpc` and answered with the id of the request.
+///
+/// Scripthash subscriptions are kept by the session, clients watching the same scripthash
+/// share a single upstream subscription. Every call counts against the rate limit of the
+/// client, calls over it are answered with an error.
+pub async fn handle_ws(
+    ws: WebSocketUpgrade,
+    Extension(upstreams): Extension<Upstreams>,
+    Extension(cache): Extension<ResultCache>,
+    Extension(charge): Extension<Charge>,
+    headers: HeaderMap,
+) -> Response {
+    ws.on_upgrade(move |socket| serve_client(socket, upstreams, cache, charge, headers))
+}
+
+async fn serve_client(
+    socket: WebSocket,
+    upstreams: Upstreams,
+    cache: ResultCache,
+    charge: Charge,
+    headers: HeaderMap,
+) {
+    let addr = maybe_ip_from_headers(&headers);
+    info!("{} WebSocket client connected", &addr);
+    let (mut sink, mut stream) = socket.split();
+    let (tx, mut rx) = mpsc::channel::<String>((*WS_CLIENT_QUEUE_SIZE).max(1));
+    let outbox = Outbox {
+        tx,
+        full: Arc::new(Notify::new()),
+    };
+    let writer = tokio::spawn(async move {
+        while let Some(text) = rx.recv().await {
+            if sink.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+    // Requests of a session run concurrently, responses go out in the order they complete.
+    let permits = Arc::new(Semaphore::new((*WS_CLIENT_MAX_IN_FLIGHT).max(1)));
+    let session = Arc::new(Session {
+        outbox: outbox.clone(),
+        scripthashes: Mutex::new(HashMap::new()),
+        closed: AtomicBool::new(false),
+    });
+    let mut headers_task: Option<JoinHandle<()>> = None;
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = outbox.full.notified() => {
+                warn!(
+                    "{} WebSocket client too slow, {} messages waiting",
+                    &addr, *WS_CLIENT_QUEUE_SIZE
+                );
+                break;
+            }
+        };
+        let Some(Ok(message)) = message else {
+            break;
+        };
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let request = serde_json::from_str::<Value>(&text);
+        let calls = request.as_ref().map_or(1, count_calls);
+        if let Err(refused) = charge.take(calls) {
+            if let Some(response) = request.ok().and_then(|x| refuse(&x, refused.message())) {
+                outbox.send(response.to_string());
+            }
+            continue;
+        }
+        if let Ok(request) = &request {
+            if headers_task.is_none() && subscribes_headers(request) {
+                headers_task = Some(tokio::spawn(forward_headers(outbox.clone())));
+            }
+        }
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            break;
+        };
+        let (upstreams, cache, headers, session) = (
+            upstreams.clone(),
+            cache.clone(),
+            headers.clone(),
+            session.clone(),
+        );
+        tokio::spawn(async move {
+            let response = match request {
+                Ok(request) => {
+                    let instances = upstreams.snapshot();
+                    session.respond(&cache, &instances, &headers, request).await
+                }
+                Err(e) => Some(parse_error(e)),
+            };
+            if let Some(response) = response {
+                session.outbox.send(response.to_string());
+            }
+            drop(permit);
+        });
+    }
+    if let Some(task) = headers_task {
+        task.abort();
+    }
+    session.close(&upstreams.snapshot());
+    writer.abort();
+    info!("{} WebSocket client disconnected", &addr);
+}
+
+/// Messages on their way to a client. A client reading slower than it is sent to is
+/// disconnected once `WS_CLIENT_QUEUE_SIZE` messages wait for it, rather than buffered for
+/// without bound.
+#[derive(Clone)]
+struct Outbox {
+    tx: mpsc::Sender<String>,
+    /// Notified when the queue overflows.
+    full: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Queue a message, false once the client is gone or too slow.
+    fn send(&self, text: String) -> bool {
+        match self.tx.try_send(text) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.full.notify_one();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Answer every call of a request over the rate limit with an error, notifications are
+/// dropped.
+fn refuse(request: &Value, message: String) -> Option<Value> {
+    let refuse = |call: &Value| {
+        let id = call.get("id").cloned()?;
+        Some(error(id, LIMIT_EXCEEDED, message.clone()))
+    };
+    match request {
+        Value::Array(calls) => {
+            let responses = calls.iter().filter_map(refuse).collect::<Vec<_>>();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        call => refuse(call),
+    }
+}
+
+const HEADERS_SUBSCRIBE: &str = "blockchain.headers.subscribe";
+
+fn subscribes_headers(request: &Value) -> bool {
+    if !is_method_allowed(HEADERS_SUBSCRIBE) {
+        return false;
+    }
+    let is_subscribe =
+        |x: &Value| x.get("method").and_then(|x| x.as_str()) == Some(HEADERS_SUBSCRIBE);
+    match request {
+        Value::Array(calls) => calls.iter().any(is_subscribe),
+        call => is_subscribe(call),
+    }
+}
+
+/// Send a notification for every new block to a client that subscribed to headers.
+async fn forward_headers(outbox: Outbox) {
+    let mut headers = subscribe_headers();
+    loop {
+        let header = match headers.recv().await {
+            Ok(header) => header,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": HEADERS_SUBSCRIBE,
+            "params": [header],
+        });
+        if !outbox.send(notification.to_string()) {
+            break;
+        }
+    }
+}
+
+/// State of a client connection shared by its concurrently running requests.
+struct Session {
+    outbox: Outbox,
+    /// Subscribed scripthashes and the tasks forwarding their status changes.
+    scripthashes: Mutex<HashMap<String, JoinHandle<()>>>,
+    /// Set once the client is gone, late subscriptions are released right away.
+    closed: AtomicBool,
+}
+
+impl Session {
+    /// Answer scripthash subscriptions of the session itself, everything else is dispatched.
+    async fn respond(
+        &self,
+        cache: &ResultCache,
+        instances: &[Instance],
+        headers: &HeaderMap,
+        request: Value,
+    ) -> Option<Value> {
+        match request {
+            Value::Array(calls)
+                if calls.iter().any(is_scripthash_call) && calls.len() <= *MAX_BATCH_SIZE =>
+            {
+                let (own, others): (Vec<_>, Vec<_>) =
+                    calls.into_iter().partition(is_scripthash_call);
+                let mut responses = join_all(own.iter().map(|x| self.call(instances, x)))
+                    .await
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>();
+                if !others.is_empty() {
+                    let others = dispatch(cache, instances, headers, Value::Array(others)).await;
+                    if let Some(Value::Array(others)) = others {
+                        responses.extend(others);
+                    }
+                }
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            call if is_scripthash_call(&call) => self.call(instances, &call).await,
+            request => dispatch(cache, instances, headers, request).await,
+        }
+    }
+
+    async fn call(&self, instances: &[Instance], call: &Value) -> Option<Value> {
+        let id = call.get("id").cloned();
+        let scripthash = call
+            .get("params")
+            .and_then(|x| x.get(0).or_else(|| x.get("scripthash")))
+            .and_then(|x| x.as_str());
+        let Some(scripthash) = scripthash else {
+            let message = "Missing scripthash".to_string();
+            return id.map(|id| error(id, INVALID_PARAMS, message));
+        };
+        let r = match call.get("method").and_then(|x| x.as_str()) {
+            Some(SCRIPTHASH_SUBSCRIBE) => self.subscribe(instances, scripthash).await,
+            _ => self.unsubscribe(instances, scripthash),
+        };
+        Some(to_response(id?, r))
+    }
+
+    async fn subscribe(&self, instances: &[Instance], scripthash: &str) -> R {
+        if self.scripthashes.lock().unwrap().contains_key(scripthash) {
+            return R::ok(scripthash_status(scripthash).unwrap_or_default());
+        }
+        let (status, statuses) = match subscribe_scripthash(instances, scripthash).await {
+            Ok(subscribed) => subscribed,
+            Err(r) => return r,
+        };
+        let mut scripthashes = self.scripthashes.lock().unwrap();
+        if scripthashes.contains_key(scripthash) || self.closed.load(Ordering::SeqCst) {
+            // Subscribed twice concurrently or the client left meanwhile, the session holds
+            // one reference at most.
+            unsubscribe_scripthash(instances, scripthash);
+        } else {
+            let task = forward_status(self.outbox.clone(), scripthash.to_string(), statuses);
+            scripthashes.insert(scripthash.to_string(), tokio::spawn(task));
+        }
+        R::ok(status)
+    }
+
+    fn unsubscribe(&self, instances: &[Instance], scripthash: &str) -> R {
+        let task = self.scripthashes.lock().unwrap().remove(scripthash);
+        let Some(task) = task else {
+            return R::ok(json!(false));
+        };
+        task.abort();
+        unsubscribe_scripthash(instances, scripthash);
+        R::ok(json!(true))
+    }
+
+    /// Release the subscriptions of a disconnected client.
+    fn close(&self, instances: &[Instance]) {
+        let scripthashes = {
+            let mut scripthashes = self.scripthashes.lock().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+            std::mem::take(&mut *scripthashes)
+        };
+        for (scripthash, task) in scripthashes {
+            task.abort();
+            unsubscribe_scripthash(instances, &scripthash);
+        }
+    }
+}
+
+/// Scripthash calls the session answers itself. Blocked ones are dispatched like any other
+/// call, to be refused there.
+fn is_scripthash_call(call: &Value) -> bool {
+    let method = call.get("method").and_then(|x| x.as_str());
+    call.get("jsonrpc").and_then(|x| x.as_str()) == Some("2.0")
+        && matches!(method, Some(SCRIPTHASH_SUBSCRIBE | SCRIPTHASH_UNSUBSCRIBE))
+        && method.is_some_and(is_method_allowed)
+}
+
+/// Send a notification for every status change of a scripthash the client subscribed to.
+async fn forward_status(
+    outbox: Outbox,
+    scripthash: String,
+    mut statuses: broadcast::Receiver<Value>,
+) {
+    loop {
+        let status = match statuses.recv().await {
+            Ok(status) => status,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": SCRIPTHASH_SUBSCRIBE,
+            "params": [&scripthash, status],
+        });
+        if !outbox.send(notification.to_string()) {
+            break;
+        }
+    }
+}